@@ -0,0 +1,18 @@
+/// What an atomic change to a key does, given the key's current value.
+pub(crate) enum Action<V> {
+    /// Leave the key as it is.
+    Keep,
+    /// Store a new value for the key, inserting the key if it is not in the map.
+    Set(V),
+    /// Remove the key from the map.
+    Remove,
+}
+
+impl<V> From<Option<V>> for Action<V> {
+    fn from(val: Option<V>) -> Self {
+        match val {
+            Some(v) => Action::Set(v),
+            None => Action::Remove,
+        }
+    }
+}
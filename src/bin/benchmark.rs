@@ -41,6 +41,14 @@ fn main() {
                 .help("Set the number of writers")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("eventual")
+                .short("e")
+                .long("eventual")
+                .default_value("1")
+                .help("Refresh the left-right map after this many writes")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("distribution")
                 .short("d")
@@ -52,7 +60,7 @@ fn main() {
         )
         .get_matches();
 
-    //let refresh = value_t!(matches, "eventual", usize).unwrap_or_else(|e| e.exit());
+    let refresh = value_t!(matches, "eventual", usize).unwrap_or_else(|e| e.exit());
     let readers = value_t!(matches, "readers", usize).unwrap_or_else(|e| e.exit());
     let writers = value_t!(matches, "writers", usize).unwrap_or_else(|e| e.exit());
     let dist = matches.value_of("distribution").unwrap_or("uniform");
@@ -170,6 +178,34 @@ fn main() {
         stat("concache::manual", "write", wres);
         stat("concache::manual", "read", rres);
     }
+
+    // benchmark concache::leftright, with the writers sharing the one write handle
+    {
+        let (r, w) = concache::leftright::with_capacity(5_000_000);
+        let w = sync::Arc::new(sync::Mutex::new(SharedWriter {
+            handle: w,
+            writes: 0,
+            refresh_every: refresh.max(1),
+        }));
+        let start = time::Instant::now();
+        let end = start + dur;
+        join.extend((0..readers).map(|_| {
+            let map = LeftRightHandle::Read(r.clone());
+            let dist = dist.to_owned();
+            thread::spawn(move || drive(map, end, &dist, false, span))
+        }));
+        join.extend((0..writers).map(|_| {
+            let map = LeftRightHandle::Write(w.clone());
+            let dist = dist.to_owned();
+            thread::spawn(move || drive(map, end, &dist, true, span))
+        }));
+        let (wres, rres): (Vec<_>, _) = join
+            .drain(..)
+            .map(|jh| jh.join().unwrap())
+            .partition(|&(write, _)| write);
+        stat("concache::leftright", "write", wres);
+        stat("concache::leftright", "read", rres);
+    }
 }
 
 trait Backend {
@@ -246,31 +282,40 @@ impl Backend for concache::manual::MapHandle<usize, usize> {
     }
 }
 
-/*enum EvHandle {
-    Read(evmap::ReadHandle<usize, usize>),
-    Write(sync::Arc<sync::Mutex<(evmap::WriteHandle<usize, usize>, usize, usize)>>),
+/// The write handle of a left-right map, which the writers share, along with the number of writes
+/// since the last refresh and the number of writes to refresh after.
+struct SharedWriter {
+    handle: concache::leftright::WriteHandle<usize, usize>,
+    writes: usize,
+    refresh_every: usize,
+}
+
+/// A reader or a writer of a left-right map.
+enum LeftRightHandle {
+    Read(concache::leftright::ReadHandle<usize, usize>),
+    Write(sync::Arc<sync::Mutex<SharedWriter>>),
 }
 
-impl Backend for EvHandle {
-    fn b_get(&self, key: usize) -> usize {
-        if let EvHandle::Read(ref r) = *self {
-            r.get_and(&key, |v| v[0]).unwrap_or(0)
+impl Backend for LeftRightHandle {
+    fn b_get(&mut self, key: usize) -> usize {
+        if let LeftRightHandle::Read(ref r) = *self {
+            r.get_and(&key, |&v| v).unwrap_or(0)
         } else {
             unreachable!();
         }
     }
 
     fn b_put(&mut self, key: usize, value: usize) {
-        if let EvHandle::Write(ref w) = *self {
+        if let LeftRightHandle::Write(ref w) = *self {
             let mut w = w.lock().unwrap();
-            w.0.update(key, value);
-            w.1 += 1;
-            if w.1 == w.2 {
-                w.1 = 0;
-                w.0.refresh();
+            w.handle.insert(key, value);
+            w.writes += 1;
+            if w.writes == w.refresh_every {
+                w.writes = 0;
+                w.handle.refresh();
             }
         } else {
             unreachable!();
         }
     }
-}*/
+}
@@ -0,0 +1,244 @@
+#[macro_use]
+extern crate clap;
+extern crate concache;
+extern crate rand;
+extern crate zipf;
+
+use clap::{App, Arg, ArgMatches};
+use concache::cache::{Cache, Clock, Fifo, Random, SampledLru};
+use rand::distributions::Distribution;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process;
+
+const POLICIES: &[&str] = &["clock", "sampled-lru", "fifo", "random"];
+
+fn main() {
+    let matches = App::new("Cache Policy Simulator")
+        .about(
+            "Replay a key-access trace against concache::cache::Cache with each eviction policy \
+             and capacity, and print the hit ratios as CSV. Text traces have one key per line; \
+             binary traces are a sequence of little-endian u64 keys.",
+        )
+        .arg(
+            Arg::with_name("trace")
+                .short("t")
+                .long("trace")
+                .help("Read the trace from this file, or from stdin if it is -")
+                .required_unless("generate")
+                .conflicts_with("generate")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .possible_values(&["text", "binary"])
+                .default_value("text")
+                .help("Set the format of the trace that is read or written")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("generate")
+                .short("g")
+                .long("generate")
+                .help("Generate a trace of this many accesses instead of reading one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keys")
+                .short("k")
+                .long("keys")
+                .default_value("10000")
+                .help("Set the number of distinct keys in a generated trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exponent")
+                .short("s")
+                .long("exponent")
+                .default_value("1.03")
+                .help("Set the exponent of the Zipf distribution of a generated trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .requires("generate")
+                .help("Write the generated trace to this file instead of replaying it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("capacities")
+                .short("c")
+                .long("capacities")
+                .use_delimiter(true)
+                .default_value("100,1000")
+                .help("Set the capacities to simulate, separated by commas")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("policies")
+                .short("p")
+                .long("policies")
+                .use_delimiter(true)
+                .possible_values(POLICIES)
+                .default_value("clock,sampled-lru,fifo,random")
+                .help("Set the eviction policies to simulate, separated by commas")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tiny-lfu")
+                .long("tiny-lfu")
+                .help("Also simulate each policy with TinyLFU admission"),
+        )
+        .get_matches();
+
+    let binary = matches.value_of("format") == Some("binary");
+    let trace = if matches.is_present("generate") {
+        let trace = generate(&matches);
+        if let Some(path) = matches.value_of("output") {
+            let out = File::create(path).unwrap_or_else(|e| fail(path, &e));
+            write_trace(BufWriter::new(out), &trace, binary).unwrap_or_else(|e| fail(path, &e));
+            return;
+        }
+        trace
+    } else {
+        let path = matches.value_of("trace").unwrap();
+        let res = if path == "-" {
+            let stdin = io::stdin();
+            let input = stdin.lock();
+            read_trace(input, binary)
+        } else {
+            File::open(path).and_then(|f| read_trace(BufReader::new(f), binary))
+        };
+        res.unwrap_or_else(|e| fail(path, &e))
+    };
+
+    let capacities = values_t!(matches, "capacities", usize).unwrap_or_else(|e| e.exit());
+    let policies: Vec<_> = matches.values_of("policies").unwrap().collect();
+    let tiny_lfu: &[bool] = if matches.is_present("tiny-lfu") {
+        &[false, true]
+    } else {
+        &[false]
+    };
+
+    println!("policy,tiny_lfu,capacity,requests,hits,hit_ratio");
+    for &capacity in &capacities {
+        for &policy in &policies {
+            for &tiny_lfu in tiny_lfu {
+                if capacity == 0 || (tiny_lfu && capacity < 2) {
+                    // no such cache
+                    continue;
+                }
+                let stats = replay(&trace, policy, capacity, tiny_lfu);
+                println!(
+                    "{},{},{},{},{},{:.6}",
+                    policy,
+                    tiny_lfu,
+                    capacity,
+                    stats.requests(),
+                    stats.hits,
+                    stats.hit_ratio()
+                );
+            }
+        }
+    }
+}
+
+fn fail(path: &str, e: &io::Error) -> ! {
+    eprintln!("{}: {}", path, e);
+    process::exit(1);
+}
+
+/// Generates a trace from the same Zipf distribution as the benchmark's skewed workload.
+fn generate(matches: &ArgMatches) -> Vec<u64> {
+    let n = value_t!(matches, "generate", usize).unwrap_or_else(|e| e.exit());
+    let keys = value_t!(matches, "keys", usize).unwrap_or_else(|e| e.exit());
+    let exponent = value_t!(matches, "exponent", f64).unwrap_or_else(|e| e.exit());
+    let zipf = zipf::ZipfDistribution::new(keys, exponent).unwrap_or_else(|_| {
+        eprintln!("the Zipf distribution needs at least one key and a positive exponent");
+        process::exit(1);
+    });
+
+    let mut t_rng = rand::thread_rng();
+    (0..n).map(|_| zipf.sample(&mut t_rng) as u64).collect()
+}
+
+/// Reads a trace, numbering the keys of a text trace in the order they first appear.
+fn read_trace<R: BufRead>(mut input: R, binary: bool) -> io::Result<Vec<u64>> {
+    if binary {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        if bytes.len() % 8 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "a binary trace must be a sequence of 8-byte keys",
+            ));
+        }
+        let mut key = [0; 8];
+        return Ok(bytes
+            .chunks(8)
+            .map(|chunk| {
+                key.copy_from_slice(chunk);
+                u64::from_le_bytes(key)
+            })
+            .collect());
+    }
+
+    let mut ids = HashMap::new();
+    let mut trace = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let next = ids.len() as u64;
+        trace.push(*ids.entry(line.to_owned()).or_insert(next));
+    }
+    Ok(trace)
+}
+
+fn write_trace<W: Write>(mut out: W, trace: &[u64], binary: bool) -> io::Result<()> {
+    for &key in trace {
+        if binary {
+            out.write_all(&key.to_le_bytes())?;
+        } else {
+            writeln!(out, "{}", key)?;
+        }
+    }
+    out.flush()
+}
+
+/// Replays `trace` against a new cache, inserting every key that misses, and returns the cache's
+/// statistics.
+fn replay(
+    trace: &[u64],
+    policy: &str,
+    capacity: usize,
+    tiny_lfu: bool,
+) -> concache::cache::CacheStats {
+    let builder = Cache::builder(capacity);
+    let builder = match policy {
+        "clock" => builder.eviction_policy(Clock::new),
+        "sampled-lru" => builder.eviction_policy(SampledLru::new),
+        "fifo" => builder.eviction_policy(Fifo::new),
+        "random" => builder.eviction_policy(Random::new),
+        _ => unreachable!(),
+    };
+    let cache = if tiny_lfu {
+        builder.tiny_lfu().build()
+    } else {
+        builder.build()
+    };
+
+    for &key in trace {
+        if cache.get(&key).is_none() {
+            cache.insert(key, key);
+        }
+    }
+    cache.stats()
+}
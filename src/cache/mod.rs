@@ -0,0 +1,2479 @@
+//! A concurrent cache with a bounded number of entries.
+//!
+//! A [`Cache`] is a [`crossbeam::Map`](::crossbeam::Map) that holds at most a fixed number of
+//! entries. When it is full, inserting a new key evicts an existing one, chosen by an
+//! [`EvictionPolicy`]. By default, that is the
+//! [CLOCK](https://en.wikipedia.org/wiki/Page_replacement_algorithm#Clock) (or second-chance)
+//! policy, which approximates evicting the least recently used entry; [`SampledLru`], [`Fifo`]
+//! and [`Random`] are also built in, and [`CacheBuilder::eviction_policy`] takes any other.
+//!
+//! Reads stay lock-free: with CLOCK, looking up a key only sets a flag on its entry, which the
+//! eviction sweep later clears. Inserting a new key locks the single slot of the cache that the
+//! key is being moved into, so inserts of different keys rarely contend.
+//!
+//! Entries can also be given a time to live, either one at a time with [`Cache::insert_with_ttl`]
+//! or for the whole cache with [`CacheBuilder::time_to_live`]. An expired entry is treated as
+//! absent, and removed the next time it is looked up. A background thread, started the first time
+//! an entry that expires is inserted, removes the remaining expired entries soon after they
+//! expire, with the help of a hierarchical timer wheel. [`Cache::run_pending_tasks`] does the
+//! same work on the calling thread, without waiting for it.
+//!
+//! Entries can also be refreshed in the background, with [`CacheBuilder::refresh_after_write`].
+//! An entry that has not been written to for a while goes stale, but is still served: the first
+//! read that finds it stale has another background thread reload it, and reads keep getting the
+//! stale value until the new one is inserted in its place.
+//!
+//! Entries can be tagged when they are inserted, with [`Cache::insert_with_tags`], so that all of
+//! the entries with a tag can be invalidated at once with [`Cache::invalidate_tag`], for example
+//! when the data of a tenant changes. [`Cache::invalidate_where`] invalidates the entries that
+//! match a predicate instead.
+//!
+//! A cache can also front a slower store. A [`CacheLoader`] given to [`CacheBuilder::loader`]
+//! loads the values that `get` misses on, and a [`CacheWriter`] writes what is inserted into and
+//! removed from the cache to the store, either [through](CacheBuilder::write_through) straight
+//! away or [behind](CacheBuilder::write_behind), in batches from the background thread.
+//!
+//! Instead of only counting entries, a cache can also weigh them, with a function given to
+//! [`CacheBuilder::weigher`], and keep their total weight under a budget set with
+//! [`CacheBuilder::max_weight`]. Inserting an entry that takes the cache over its budget evicts
+//! entries, in the same order, until it is back under it.
+//!
+//! CLOCK only looks at how recently entries were used, so a scan over many keys that are each
+//! used once can flush out entries that are used all the time. [`CacheBuilder::tiny_lfu`] guards
+//! against that with [W-TinyLFU](https://arxiv.org/abs/1512.00727) admission: new entries go into
+//! a small window, and only make it into the rest of the cache if a sketch of how often keys are
+//! used says they are used more often than the entry they would displace.
+//!
+//! A [removal listener](CacheBuilder::removal_listener) can be told about every entry that leaves
+//! the cache, along with the [`RemovalCause`], for example to release resources the values stand
+//! for.
+//!
+//! Every cache counts its hits, misses, evictions and so on, striped across threads so that
+//! counting does not make threads contend; [`Cache::stats`] adds them up.
+//!
+//! Like the maps it is built on, the cache requires its values to be `Copy`.
+
+mod policy;
+mod sketch;
+mod slots;
+mod stats;
+mod store;
+mod tags;
+mod timer;
+
+pub use self::policy::{Clock, EvictionPolicy, Fifo, Random, SampledLru};
+pub use self::stats::CacheStats;
+pub use self::store::{CacheLoader, CacheWriter, StoreError};
+
+use self::sketch::FrequencySketch;
+use self::slots::Slots;
+use self::stats::{Counter, Stats};
+use self::store::{WriteErrorHandler, Writer};
+use self::tags::TagIndex;
+use self::timer::{TimerWheel, TICK};
+use crossbeam::Map;
+use flight::{self, Flights};
+use std::convert::Infallible;
+use std::error::Error;
+use std::hash::Hash;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A cached value, along with the slot that tracks it.
+#[derive(Clone, Copy)]
+struct Entry<V> {
+    value: V,
+    slot: usize,
+    expires: Option<Instant>,
+    /// When the entry goes stale, unless it is already being refreshed.
+    refresh_at: Option<Instant>,
+    /// The id of the write in the tag index, if it was tagged.
+    tag_id: Option<u64>,
+    weight: u32,
+    /// Which write of the cache this entry is, if the cache refreshes its entries, so that a
+    /// refresh can tell whether the entry it reloaded has been written over since.
+    version: u64,
+}
+
+type Weigher<K, V> = Box<dyn Fn(&K, &V) -> u32 + Send + Sync>;
+type Listener<K, V> = Box<dyn Fn(&K, V, RemovalCause) + Send + Sync>;
+type Reloader<K, V> = Box<dyn Fn(&K, V) -> Result<V, RefreshError> + Send + Sync>;
+type ErrorHandler<K> = Box<dyn Fn(&K, &RefreshError) + Send + Sync>;
+type StoreWriter<K, V> = Box<dyn CacheWriter<K, V>>;
+type PolicyFactory = Box<dyn Fn(usize) -> Box<dyn EvictionPolicy>>;
+type Now = Box<dyn Fn() -> Instant + Send + Sync>;
+
+/// Entries that have left the cache, along with why, for the removal listener to be told about
+/// once the operation that removed them no longer holds any of the cache's locks.
+type Removals<K, V> = Vec<(K, V, RemovalCause)>;
+
+/// The error a reload for [`CacheBuilder::refresh_after_write`] fails with.
+pub type RefreshError = Box<dyn Error + Send + Sync>;
+
+/// How the entries of a cache are refreshed.
+struct Refresh<K, V> {
+    interval: Duration,
+    reload: Reloader<K, V>,
+    on_error: Option<ErrorHandler<K>>,
+}
+
+/// The keys whose entries have gone stale, for the refresher thread to reload.
+struct StaleKeys<K> {
+    keys: Mutex<Vec<K>>,
+    found: Condvar,
+}
+
+/// Which entry already cached for a key an entry may take the place of.
+#[derive(Clone, Copy)]
+enum Put {
+    /// Any entry.
+    Over,
+    /// Only an expired entry, so that the key is only inserted if it is not cached.
+    Absent,
+    /// Only the entry that was written as this version and is being refreshed.
+    Refreshed(u64),
+}
+
+/// Why a load from a cache's store did not produce a value.
+#[derive(Clone)]
+enum Miss {
+    Absent,
+    Failed(StoreError),
+}
+
+/// Why an entry left a [`Cache`], as told to its [removal
+/// listener](CacheBuilder::removal_listener).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RemovalCause {
+    /// The entry was removed with [`Cache::remove`].
+    Explicit,
+    /// The entry's value was replaced by inserting the same key again.
+    Replaced,
+    /// The entry outlived its time to live.
+    Expired,
+    /// The entry was evicted to keep the cache within its bounds.
+    Evicted,
+    /// The entry was removed with [`Cache::clear`].
+    Cleared,
+}
+
+impl<V> Entry<V> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.map(|t| t <= now).unwrap_or(false)
+    }
+}
+
+/// Configures and creates a [`Cache`].
+///
+/// # Examples
+///
+/// ```
+/// use concache::cache::Cache;
+/// use std::time::Duration;
+///
+/// let cache = Cache::builder(1024)
+///     .time_to_live(Duration::from_secs(60))
+///     .build();
+/// cache.insert("session", 42);
+/// assert_eq!(cache.get(&"session"), Some(42));
+/// ```
+pub struct CacheBuilder<K, V> {
+    max_entries: usize,
+    ttl: Option<Duration>,
+    weigher: Option<Weigher<K, V>>,
+    max_weight: Option<u64>,
+    listener: Option<Listener<K, V>>,
+    tiny_lfu: bool,
+    refresh: Option<Refresh<K, V>>,
+    policy: Option<PolicyFactory>,
+    loader: Option<Box<dyn CacheLoader<K, V>>>,
+    /// The writer, and how long it writes behind by, if it does.
+    writer: Option<(StoreWriter<K, V>, Option<Duration>)>,
+    on_write_error: Option<WriteErrorHandler<K>>,
+    clock: Option<Now>,
+}
+
+impl<K, V> CacheBuilder<K, V> {
+    /// Makes every entry expire `ttl` after it was last inserted, unless it is inserted with
+    /// [`Cache::insert_with_ttl`].
+    pub fn time_to_live(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the function that gives the weight of each entry, for the cache's
+    /// [`max_weight`](CacheBuilder::max_weight). Without one, every entry weighs 1.
+    ///
+    /// An entry is weighed once, when it is inserted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::Cache;
+    ///
+    /// // at most 10 entries, taking up at most 1 KiB between them
+    /// let cache = Cache::builder(10)
+    ///     .weigher(|_: &u32, v: &(u16, u16)| u32::from(v.1))
+    ///     .max_weight(1024)
+    ///     .build();
+    /// cache.insert(1, (0, 600));
+    /// cache.insert(2, (0, 300));
+    /// cache.insert(3, (0, 300));
+    /// assert!(cache.weight() <= 1024);
+    /// assert_eq!(cache.get(&3), Some((0, 300)));
+    /// ```
+    pub fn weigher<F>(mut self, weigher: F) -> Self
+    where
+        F: Fn(&K, &V) -> u32 + Send + Sync + 'static,
+    {
+        self.weigher = Some(Box::new(weigher));
+        self
+    }
+
+    /// Limits the total weight of the entries in the cache to `max_weight`, on top of the limit on
+    /// their number.
+    pub fn max_weight(mut self, max_weight: u64) -> Self {
+        self.max_weight = Some(max_weight);
+        self
+    }
+
+    /// Sets the policy that chooses which entries to evict, given a function that creates it from
+    /// the number of slots it has to choose from. Without one, the cache uses [`Clock`].
+    ///
+    /// With [`tiny_lfu`](CacheBuilder::tiny_lfu), the admission window and the rest of the cache
+    /// each get a policy of their own.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::{Cache, Fifo, SampledLru};
+    ///
+    /// let fifo = Cache::builder(2).eviction_policy(Fifo::new).build();
+    /// fifo.insert("a", 1);
+    /// fifo.insert("b", 2);
+    /// // reading "a" makes no difference
+    /// assert_eq!(fifo.get(&"a"), Some(1));
+    /// fifo.insert("c", 3);
+    /// assert_eq!(fifo.get(&"a"), None);
+    ///
+    /// let lru = Cache::<u32, u32>::builder(1000)
+    ///     .eviction_policy(|n| SampledLru::with_samples(n, 10))
+    ///     .build();
+    /// assert_eq!(lru.capacity(), 1000);
+    /// ```
+    pub fn eviction_policy<F, P>(mut self, policy: F) -> Self
+    where
+        F: Fn(usize) -> P + 'static,
+        P: EvictionPolicy + 'static,
+    {
+        self.policy = Some(Box::new(move |n| Box::new(policy(n))));
+        self
+    }
+
+    /// Puts a W-TinyLFU admission filter in front of eviction.
+    ///
+    /// One percent of the cache, and at least one entry, is set aside as a window that every new
+    /// entry goes into. When an entry is pushed out of the window, it only takes the place of the
+    /// entry that would be evicted from the rest of the cache if its key has been used more often
+    /// recently, and is evicted itself otherwise. How often keys are used is tracked, for keys in
+    /// the cache and out of it, with a small sketch that every `get` and `insert` updates without
+    /// taking a lock.
+    ///
+    /// The cache must be able to hold at least two entries.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::Cache;
+    ///
+    /// let cache = Cache::builder(100).tiny_lfu().build();
+    /// for _ in 0..5 {
+    ///     for k in 0..50 {
+    ///         if cache.get(&k).is_none() {
+    ///             cache.insert(k, k);
+    ///         }
+    ///     }
+    /// }
+    /// // a scan of keys that are used only once
+    /// for k in 1000..2000 {
+    ///     cache.insert(k, k);
+    /// }
+    /// assert!((0..50).filter(|k| cache.get(k).is_some()).count() >= 45);
+    /// ```
+    pub fn tiny_lfu(mut self) -> Self {
+        self.tiny_lfu = true;
+        self
+    }
+
+    /// Makes entries go stale `interval` after they were last written, and refreshes stale
+    /// entries with `reload` in the background.
+    ///
+    /// A stale entry is still returned by `get`. The first read that finds it stale has a
+    /// background thread call `reload` with the key and the stale value, and the new value is then
+    /// inserted in place of the stale one, unless the entry was removed or written to in the
+    /// meantime. Until then, reads keep getting the stale value. Entries are reloaded one at a
+    /// time, on a thread of their own, so a slow `reload` delays other refreshes, but not the
+    /// removal of expired entries or write-behind. If `reload` fails, the stale
+    /// value stays, the error is passed to the [error handler](CacheBuilder::on_refresh_error),
+    /// if there is one, and the entry is refreshed again after another `interval`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::Cache;
+    /// use std::time::Duration;
+    ///
+    /// // entries go stale as soon as they are written
+    /// let cache = Cache::builder(16)
+    ///     .refresh_after_write(Duration::from_secs(0), |_, v| Ok(v + 1))
+    ///     .build();
+    /// cache.insert("generation", 0);
+    ///
+    /// // stale, but still served while the refresh happens
+    /// assert_eq!(cache.get(&"generation"), Some(0));
+    /// // wait for the refresh
+    /// cache.run_pending_tasks();
+    /// assert_eq!(cache.get(&"generation"), Some(1));
+    /// ```
+    pub fn refresh_after_write<F>(mut self, interval: Duration, reload: F) -> Self
+    where
+        F: Fn(&K, V) -> Result<V, RefreshError> + Send + Sync + 'static,
+    {
+        let on_error = self.refresh.take().and_then(|r| r.on_error);
+        self.refresh = Some(Refresh {
+            interval,
+            reload: Box::new(reload),
+            on_error,
+        });
+        self
+    }
+
+    /// Sets a function to call with the key and the error when refreshing an entry fails.
+    ///
+    /// This does nothing unless entries are refreshed with
+    /// [`refresh_after_write`](CacheBuilder::refresh_after_write), which must be set first.
+    pub fn on_refresh_error<F>(mut self, on_error: F) -> Self
+    where
+        F: Fn(&K, &RefreshError) + Send + Sync + 'static,
+    {
+        if let Some(ref mut refresh) = self.refresh {
+            refresh.on_error = Some(Box::new(on_error));
+        }
+        self
+    }
+
+    /// Makes the cache front a store, from which `loader` loads the value of every key that
+    /// [`Cache::get`] misses on.
+    ///
+    /// A loaded value is inserted into the cache, but not written back to the store. Like with
+    /// [`Cache::get_or_insert_with`], only one of the threads that miss on a key at the same time
+    /// loads it. `get` treats a failed load as a miss; [`Cache::try_get`] returns the error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::{Cache, StoreError};
+    ///
+    /// let cache = Cache::builder(16)
+    ///     .loader(|&k: &u32| -> Result<Option<u32>, StoreError> { Ok(Some(k * 2)) })
+    ///     .build();
+    /// assert_eq!(cache.get(&21), Some(42));
+    /// assert_eq!(cache.len(), 1);
+    /// ```
+    pub fn loader<L>(mut self, loader: L) -> Self
+    where
+        L: CacheLoader<K, V> + 'static,
+    {
+        self.loader = Some(Box::new(loader));
+        self
+    }
+
+    /// Makes the cache write every value inserted into it, and every key removed from it, to a
+    /// store with `writer`, before the cache itself is changed.
+    ///
+    /// Only [`Cache::insert`] and its variants, and [`Cache::remove`], write to the store. Values
+    /// the cache loads or computes, and entries that leave the cache any other way, such as by
+    /// being evicted, do not. Writes to the same key are made one at a time, so that the store
+    /// ends up with the same value as the cache. If a write fails, the cache is left as it was,
+    /// and the error is passed to the [error handler](CacheBuilder::on_write_error), if there is
+    /// one.
+    pub fn write_through<W>(mut self, writer: W) -> Self
+    where
+        W: CacheWriter<K, V> + 'static,
+    {
+        self.writer = Some((Box::new(writer), None));
+        self
+    }
+
+    /// Makes the cache write every value inserted into it, and every key removed from it, to a
+    /// store with `writer`, in batches that the cache's background thread writes about `delay`
+    /// after the first write of the batch.
+    ///
+    /// See [`write_through`](CacheBuilder::write_through) for what is written. Until it is
+    /// written out, a write is only queued, and writing a key again replaces its queued write,
+    /// so that the store only sees the last one. Writes that are still queued are written out
+    /// when the cache is dropped, or by [`Cache::flush`]. If a batch fails, the error is passed to
+    /// the [error handler](CacheBuilder::on_write_error) with each key in it, and the batch is
+    /// not retried.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::{Cache, CacheWriter, StoreError};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    ///
+    /// struct Counter(Arc<AtomicUsize>);
+    ///
+    /// impl CacheWriter<&'static str, u32> for Counter {
+    ///     fn write(&self, _: &&'static str, _: u32) -> Result<(), StoreError> {
+    ///         self.0.fetch_add(1, Ordering::SeqCst);
+    ///         Ok(())
+    ///     }
+    ///     fn delete(&self, _: &&'static str) -> Result<(), StoreError> {
+    ///         self.0.fetch_add(1, Ordering::SeqCst);
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let writes = Arc::new(AtomicUsize::new(0));
+    /// let cache = Cache::builder(16)
+    ///     .write_behind(Counter(Arc::clone(&writes)), Duration::from_secs(1))
+    ///     .build();
+    /// for i in 0..100 {
+    ///     cache.insert("hits", i);
+    /// }
+    /// cache.flush();
+    /// assert_eq!(writes.load(Ordering::SeqCst), 1);
+    /// ```
+    pub fn write_behind<W>(mut self, writer: W, delay: Duration) -> Self
+    where
+        W: CacheWriter<K, V> + 'static,
+    {
+        self.writer = Some((Box::new(writer), Some(delay)));
+        self
+    }
+
+    /// Sets a function to call with the key and the error when writing to the cache's store
+    /// fails.
+    pub fn on_write_error<F>(mut self, on_error: F) -> Self
+    where
+        F: Fn(&K, &StoreError) + Send + Sync + 'static,
+    {
+        self.on_write_error = Some(Box::new(on_error));
+        self
+    }
+
+    /// Sets a function to call with the key, the value and the [`RemovalCause`] of every entry
+    /// that leaves the cache.
+    ///
+    /// The listener is called by the thread that removed the entry, once the entry is out of the
+    /// cache and no locks of the cache are held, so it may use the cache itself. An entry that had
+    /// already expired is reported as [`Expired`](RemovalCause::Expired) however it was removed,
+    /// unless the cache is being cleared. A value too heavy to be cached at all is reported as
+    /// [`Evicted`](RemovalCause::Evicted) straight away.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::{Cache, RemovalCause};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let removed = Arc::new(Mutex::new(Vec::new()));
+    /// let log = Arc::clone(&removed);
+    /// let cache = Cache::builder(1)
+    ///     .removal_listener(move |&k, v, cause| log.lock().unwrap().push((k, v, cause)))
+    ///     .build();
+    /// cache.insert(1, "a");
+    /// cache.insert(1, "b");
+    /// cache.insert(2, "c");
+    /// assert_eq!(
+    ///     *removed.lock().unwrap(),
+    ///     vec![(1, "a", RemovalCause::Replaced), (1, "b", RemovalCause::Evicted)]
+    /// );
+    /// ```
+    pub fn removal_listener<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&K, V, RemovalCause) + Send + Sync + 'static,
+    {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    /// Makes the cache read the time from `clock`, and leaves the work of its background thread
+    /// to [`Cache::run_pending_tasks`], so that tests decide when time passes.
+    #[cfg(test)]
+    fn clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> Instant + Send + Sync + 'static,
+    {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+}
+
+impl<K, V> CacheBuilder<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Copy + Send + Sync + 'static,
+{
+    /// Creates the cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache was configured to hold no entries, or only one entry with
+    /// [`tiny_lfu`](CacheBuilder::tiny_lfu).
+    pub fn build(self) -> Cache<K, V> {
+        let cache = Cache::from_builder(self);
+        if cache.inner.ttl.is_some() || cache.inner.writer.as_ref().is_some_and(Writer::is_behind) {
+            cache.start_sweeper();
+        }
+        if cache.inner.refresh.is_some() {
+            cache.start_refresher();
+        }
+        cache
+    }
+}
+
+struct Inner<K, V: 'static> {
+    map: Map<K, Entry<V>>,
+    /// The slots of the main part of the cache, or of all of it without TinyLFU.
+    main: Slots<K>,
+    /// The slots of the admission window, with TinyLFU.
+    window: Option<Slots<K>>,
+    sketch: Option<FrequencySketch>,
+    ttl: Option<Duration>,
+    timers: Mutex<TimerWheel<K>>,
+    sweeping: AtomicBool,
+    /// Held while expired entries are being removed and writes written behind, by whichever
+    /// thread is doing it.
+    sweeping_lock: Mutex<()>,
+    /// Where the time comes from, if not the system clock.
+    clock: Option<Now>,
+    weigher: Option<Weigher<K, V>>,
+    max_weight: Option<u64>,
+    weight: AtomicU64,
+    listener: Option<Listener<K, V>>,
+    stats: Stats,
+    flights: Flights<K, V>,
+    refresh: Option<Refresh<K, V>>,
+    stale: Arc<StaleKeys<K>>,
+    /// Held while stale entries are being reloaded, by whichever thread is reloading them.
+    refreshing: Mutex<()>,
+    /// The version of the next entry to be written, if the cache refreshes its entries.
+    versions: AtomicU64,
+    tags: TagIndex<K>,
+    loader: Option<Box<dyn CacheLoader<K, V>>>,
+    writer: Option<Writer<K, V>>,
+}
+
+/// A handle to a shared, concurrent cache holding at most a fixed number of entries.
+///
+/// Cloning a `Cache` gives another handle to the same cache.
+///
+/// # Examples
+///
+/// ```
+/// use concache::cache::Cache;
+///
+/// let cache = Cache::new(2);
+/// cache.insert("a", 1);
+/// cache.insert("b", 2);
+/// // reading "a" gives it a second chance
+/// assert_eq!(cache.get(&"a"), Some(1));
+/// cache.insert("c", 3);
+///
+/// assert_eq!(cache.len(), 2);
+/// assert_eq!(cache.get(&"a"), Some(1));
+/// assert_eq!(cache.get(&"b"), None);
+/// ```
+pub struct Cache<K, V: 'static> {
+    inner: Arc<Inner<K, V>>,
+}
+
+impl<K, V> Cache<K, V> {
+    /// Creates a new, empty cache that holds at most `max_entries` entries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_entries` is 0.
+    pub fn new(max_entries: usize) -> Self {
+        Self::from_builder(Self::builder(max_entries))
+    }
+
+    /// Returns a builder for a cache that holds at most `max_entries` entries, which allows
+    /// configuring the cache further.
+    pub fn builder(max_entries: usize) -> CacheBuilder<K, V> {
+        CacheBuilder {
+            max_entries,
+            ttl: None,
+            weigher: None,
+            max_weight: None,
+            listener: None,
+            tiny_lfu: false,
+            refresh: None,
+            policy: None,
+            loader: None,
+            writer: None,
+            on_write_error: None,
+            clock: None,
+        }
+    }
+
+    fn from_builder(builder: CacheBuilder<K, V>) -> Self {
+        let n = builder.max_entries;
+        let on_write_error = builder.on_write_error;
+        let writer = builder
+            .writer
+            .map(|(store, delay)| Writer::new(store, delay, on_write_error));
+        let new_policy = builder
+            .policy
+            .unwrap_or_else(|| Box::new(|n| Box::new(Clock::new(n))));
+        let slots = |first, n| Slots::new(first, n, new_policy(n));
+        let (main, window, sketch) = if builder.tiny_lfu {
+            assert!(
+                n >= 2,
+                "a cache with TinyLFU admission must be able to hold at least two entries"
+            );
+            let window = (n / 100).max(1);
+            (
+                slots(0, n - window),
+                Some(slots(n - window, window)),
+                Some(FrequencySketch::new(n)),
+            )
+        } else {
+            (slots(0, n), None, None)
+        };
+
+        let clock = builder.clock;
+        let start = clock.as_ref().map_or_else(Instant::now, |now| now());
+        Cache {
+            inner: Arc::new(Inner {
+                map: Map::with_capacity(n.max(1)),
+                main,
+                window,
+                sketch,
+                ttl: builder.ttl,
+                timers: Mutex::new(TimerWheel::new(start)),
+                sweeping: AtomicBool::new(false),
+                sweeping_lock: Mutex::new(()),
+                clock,
+                weigher: builder.weigher,
+                max_weight: builder.max_weight,
+                weight: AtomicU64::new(0),
+                listener: builder.listener,
+                stats: Stats::default(),
+                flights: Flights::default(),
+                refresh: builder.refresh,
+                stale: Arc::new(StaleKeys {
+                    keys: Mutex::new(Vec::new()),
+                    found: Condvar::new(),
+                }),
+                refreshing: Mutex::new(()),
+                versions: AtomicU64::new(0),
+                tags: TagIndex::default(),
+                loader: builder.loader,
+                writer,
+            }),
+        }
+    }
+
+    /// Returns the most entries the cache can hold.
+    pub fn capacity(&self) -> usize {
+        self.inner.parts().map(Slots::capacity).sum()
+    }
+
+    /// Returns a snapshot of the cache's statistics.
+    pub fn stats(&self) -> CacheStats {
+        self.inner.stats.snapshot()
+    }
+
+    /// Returns the total weight of the entries in the cache.
+    pub fn weight(&self) -> u64 {
+        self.inner.weight.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of entries in the cache.
+    ///
+    /// This includes entries that have expired, but have not been removed yet.
+    pub fn len(&self) -> usize {
+        self.inner.map.len()
+    }
+
+    /// Returns true if the cache contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.inner.map.is_empty()
+    }
+}
+
+impl<K, V> Inner<K, V> {
+    /// Returns the current time.
+    fn now(&self) -> Instant {
+        match self.clock {
+            Some(ref now) => now(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Returns the parts the cache's slots are divided between.
+    fn parts(&self) -> impl Iterator<Item = &Slots<K>> {
+        Some(&self.main).into_iter().chain(self.window.as_ref())
+    }
+
+    /// Returns the part that `slot` belongs to.
+    fn part_of(&self, slot: usize) -> &Slots<K> {
+        match self.window {
+            Some(ref window) if window.contains(slot) => window,
+            _ => &self.main,
+        }
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    /// Inserts a key-value pair into the cache, returning the previous value for the key, if any.
+    ///
+    /// If the key is not already cached and the cache is full, another entry is evicted to make
+    /// room for it, as are as many as it takes to keep the cache under its
+    /// [`max_weight`](CacheBuilder::max_weight). An entry that weighs more than that on its own is
+    /// not cached at all, and only removes the key's previous value. If the cache has a
+    /// [time to live](CacheBuilder::time_to_live), the entry expires after that long.
+    ///
+    /// If the cache [writes through](CacheBuilder::write_through) to a store, and the write fails,
+    /// the cache is left as it was, and `None` is returned.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.write_entry(key, value, self.default_expiry(), None)
+    }
+
+    /// Inserts a key-value pair into the cache with the given tags, returning the previous value
+    /// for the key, if any.
+    ///
+    /// The tags belong to this value of the key: inserting the key again, with or without tags,
+    /// replaces them. See [`Cache::insert`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::Cache;
+    ///
+    /// let cache = Cache::new(16);
+    /// cache.insert_with_tags(("acme", "logo"), 1, &["tenant:acme"]);
+    /// cache.insert_with_tags(("acme", "motd"), 2, &["tenant:acme"]);
+    /// cache.insert_with_tags(("initech", "logo"), 3, &["tenant:initech"]);
+    ///
+    /// assert_eq!(cache.invalidate_tag("tenant:acme"), 2);
+    /// assert_eq!(cache.get(&("acme", "logo")), None);
+    /// assert_eq!(cache.get(&("initech", "logo")), Some(3));
+    /// ```
+    pub fn insert_with_tags<T: AsRef<str>>(&self, key: K, value: V, tags: &[T]) -> Option<V> {
+        let tag_id = self.register_tags(&key, tags);
+        self.write_entry(key, value, self.default_expiry(), tag_id)
+    }
+
+    /// Returns when an entry inserted now expires, by the cache's time to live.
+    fn default_expiry(&self) -> Option<Instant> {
+        self.inner.ttl.map(|ttl| self.inner.now() + ttl)
+    }
+
+    /// Records a write of `key` with `tags` in the tag index, unless there are no tags.
+    fn register_tags<T: AsRef<str>>(&self, key: &K, tags: &[T]) -> Option<u64> {
+        if tags.is_empty() {
+            None
+        } else {
+            Some(self.inner.tags.register(key.clone(), tags))
+        }
+    }
+
+    /// Inserts an entry for a value written to the cache, as opposed to one it loaded, writing it
+    /// to the store if there is one.
+    fn write_entry(
+        &self,
+        key: K,
+        value: V,
+        expires: Option<Instant>,
+        tag_id: Option<u64>,
+    ) -> Option<V> {
+        let mut removed = Vec::new();
+        let ret = match self.inner.writer {
+            Some(ref writer) => {
+                let _locked = writer.lock(&key);
+                if writer.write(&key, Some(value), self.inner.now()).is_err() {
+                    if let Some(id) = tag_id {
+                        self.inner.tags.forget(id);
+                    }
+                    return None;
+                }
+                self.insert_entry(key, value, expires, tag_id, &mut removed)
+            }
+            None => self.insert_entry(key, value, expires, tag_id, &mut removed),
+        };
+        self.notify(removed);
+        ret
+    }
+
+    fn insert_entry(
+        &self,
+        key: K,
+        value: V,
+        expires: Option<Instant>,
+        tag_id: Option<u64>,
+        removed: &mut Removals<K, V>,
+    ) -> Option<V> {
+        self.put_entry(key, value, expires, tag_id, Put::Over, removed)
+    }
+
+    /// Inserts an entry in place of the one cached for its key, if `put` allows it to take that
+    /// entry's place.
+    ///
+    /// Returns the value that was replaced, or, if the key is cached and was not inserted because
+    /// of [`Put::Absent`], the value that is cached.
+    fn put_entry(
+        &self,
+        key: K,
+        value: V,
+        expires: Option<Instant>,
+        tag_id: Option<u64>,
+        put: Put,
+        removed: &mut Removals<K, V>,
+    ) -> Option<V> {
+        let inner = &*self.inner;
+        let now = inner.now();
+        let refresh_at = inner.refresh.as_ref().map(|r| now + r.interval);
+        let version = match inner.refresh {
+            Some(_) => inner.versions.fetch_add(1, Ordering::SeqCst),
+            None => 0,
+        };
+        let current = |e: &Entry<V>| match put {
+            Put::Over => true,
+            Put::Absent => e.is_expired(now),
+            Put::Refreshed(v) => e.version == v && e.refresh_at.is_none(),
+        };
+        let forget_tags = || {
+            if let Some(id) = tag_id {
+                inner.tags.forget(id);
+            }
+        };
+
+        if let Some(ref sketch) = inner.sketch {
+            sketch.increment(&key);
+        }
+        let weight = inner.weigher.as_ref().map_or(1, |f| f(&key, &value));
+        if inner.max_weight.is_some_and(|max| u64::from(weight) > max) {
+            let old = match inner.map.remove_if(&key, current) {
+                Some(e) => {
+                    self.unlink(&key, e, RemovalCause::Replaced, removed);
+                    if e.is_expired(now) {
+                        None
+                    } else {
+                        Some(e.value)
+                    }
+                }
+                None => match put {
+                    Put::Over => None,
+                    Put::Absent => match inner.map.get(&key) {
+                        Some(e) if !e.is_expired(now) => {
+                            forget_tags();
+                            return Some(e.value);
+                        }
+                        _ => None,
+                    },
+                    Put::Refreshed(_) => {
+                        // written over or removed while it was being refreshed
+                        forget_tags();
+                        return None;
+                    }
+                },
+            };
+            forget_tags();
+            inner.stats.record(Counter::Eviction, 1);
+            if inner.listener.is_some() {
+                removed.push((key, value, RemovalCause::Evicted));
+            }
+            return old;
+        }
+
+        // count the new entry before anyone can see it, so that the total never drops below what
+        // is actually cached
+        inner.weight.fetch_add(u64::from(weight), Ordering::SeqCst);
+        let mut evicted = Vec::new();
+        // whether the key's timer, if it has one, goes off by the new expiry
+        let mut timed = false;
+        let (ret, slot) = loop {
+            // a key that is already cached keeps its slot
+            let old = inner.map.fetch_update(&key, |e| {
+                e.map(|e| {
+                    if !current(e) {
+                        return *e;
+                    }
+                    Entry {
+                        value,
+                        slot: e.slot,
+                        expires,
+                        refresh_at,
+                        tag_id,
+                        weight,
+                        version,
+                    }
+                })
+            });
+            let kept = match old {
+                Some(ref e) => !current(e),
+                // removed while it was being refreshed
+                None => matches!(put, Put::Refreshed(_)),
+            };
+            if kept {
+                inner.weight.fetch_sub(u64::from(weight), Ordering::SeqCst);
+                forget_tags();
+                return match put {
+                    Put::Absent => old.map(|e| e.value),
+                    _ => None,
+                };
+            }
+            if let Some(old) = old {
+                inner.part_of(old.slot).touch(old.slot);
+                self.discard(&key, old, RemovalCause::Replaced, removed);
+                timed = match (old.expires, expires) {
+                    (Some(before), Some(at)) => before <= at,
+                    _ => false,
+                };
+                if old.is_expired(now) {
+                    inner.stats.record(Counter::Insert, 1);
+                    break (None, old.slot);
+                }
+                inner.stats.record(Counter::Update, 1);
+                break (Some(old.value), old.slot);
+            }
+
+            // new entries start out in the window, if there is one
+            let (slot, mut held) = inner.window.as_ref().unwrap_or(&inner.main).claim();
+            if let Some(victim) = held.take() {
+                if inner.window.is_some() {
+                    self.promote(victim, slot, &mut evicted);
+                } else if let Some(e) = inner.map.remove_if(&victim, |e| e.slot == slot) {
+                    // the victim may have been removed, or moved on to another slot, already
+                    evicted.push((victim, e));
+                }
+            }
+
+            let entry = Entry {
+                value,
+                slot,
+                expires,
+                refresh_at,
+                tag_id,
+                weight,
+                version,
+            };
+            if inner.map.insert_if_absent(key.clone(), entry).is_none() {
+                inner
+                    .part_of(slot)
+                    .fill(slot, &mut held, key.clone(), weight);
+                inner.stats.record(Counter::Insert, 1);
+                break (None, slot);
+            }
+            // someone else cached the key in the meantime; give the slot back and update their
+            // entry instead
+            inner.part_of(slot).release(slot);
+        };
+        for (victim, e) in evicted {
+            self.discard(&victim, e, RemovalCause::Evicted, removed);
+        }
+
+        // a timer that goes off early finds the entry still live, and is set again for its expiry
+        if let Some(at) = expires {
+            if !timed {
+                inner.timers.lock().unwrap().schedule(key, at);
+            }
+        }
+        self.shed(slot, removed);
+        ret
+    }
+
+    /// Moves `candidate`, which the window's policy has just pushed out of the slot `from`, into
+    /// the main part of the cache if there is room for it or it is used more often than the entry
+    /// it would displace there, and evicts it otherwise.
+    fn promote(&self, candidate: K, from: usize, evicted: &mut Vec<(K, Entry<V>)>) {
+        let inner = &*self.inner;
+        let (to, mut held) = inner.main.claim();
+        let admit = match (held.as_ref(), inner.sketch.as_ref()) {
+            (Some(victim), Some(sketch)) => sketch.frequency(&candidate) > sketch.frequency(victim),
+            _ => true,
+        };
+
+        if !admit {
+            if let Some(e) = inner.map.remove_if(&candidate, |e| e.slot == from) {
+                evicted.push((candidate, e));
+            }
+            return;
+        }
+
+        if let Some(victim) = held.take() {
+            if let Some(e) = inner.map.remove_if(&victim, |e| e.slot == to) {
+                evicted.push((victim, e));
+            }
+        }
+        // the candidate may have been removed, or moved on to another slot, already
+        let old = inner.map.fetch_update(&candidate, |e| {
+            e.map(|&e| {
+                if e.slot == from {
+                    Entry { slot: to, ..e }
+                } else {
+                    e
+                }
+            })
+        });
+        match old {
+            Some(e) if e.slot == from => inner.main.fill(to, &mut held, candidate, e.weight),
+            _ => inner.main.release(to),
+        }
+    }
+
+    /// Evicts entries until the cache is back under its maximum weight, sparing the entry in
+    /// `keep`, which was just inserted.
+    fn shed(&self, keep: usize, removed: &mut Removals<K, V>) {
+        let inner = &*self.inner;
+        let max = match inner.max_weight {
+            Some(max) => max,
+            None => return,
+        };
+
+        // give up after twice as many victims as there are slots, which only happens if the
+        // entries that take the cache over its budget are still being inserted
+        for part in inner.parts() {
+            for _ in 0..2 * part.capacity() {
+                if inner.weight.load(Ordering::SeqCst) <= max {
+                    return;
+                }
+                let (slot, mut held) = part.next_victim();
+                if slot == keep {
+                    continue;
+                }
+                if let Some(victim) = held.take() {
+                    let evicted = inner.map.remove_if(&victim, |e| e.slot == slot);
+                    part.release(slot);
+                    drop(held);
+                    if let Some(e) = evicted {
+                        self.discard(&victim, e, RemovalCause::Evicted, removed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the value cached for the key, if any.
+    ///
+    /// If the cache has a [loader](CacheBuilder::loader), a key that is not cached is loaded from
+    /// the store, and `None` is returned if the store has no value for it or the load fails.
+    pub fn get(&self, key: &K) -> Option<V> {
+        if self.inner.loader.is_some() {
+            return self.try_get(key).unwrap_or(None);
+        }
+        if let Some(ref sketch) = self.inner.sketch {
+            sketch.increment(key);
+        }
+        let v = self.lookup(key);
+        let counter = if v.is_some() {
+            Counter::Hit
+        } else {
+            Counter::Miss
+        };
+        self.inner.stats.record(counter, 1);
+        v
+    }
+
+    /// Returns the value for the key, loading it with the cache's [loader](CacheBuilder::loader)
+    /// if it is not cached, or the error the load failed with.
+    ///
+    /// A load that finds no value counts as a failed load in the cache's
+    /// [statistics](Cache::stats). Without a loader, this is the same as [`Cache::get`].
+    pub fn try_get(&self, key: &K) -> Result<Option<V>, StoreError> {
+        let loader = match self.inner.loader {
+            Some(ref loader) => loader,
+            None => return Ok(self.get(key)),
+        };
+        let load = || {
+            // a write that is yet to be written behind is newer than what the store has
+            let pending = self.inner.writer.as_ref().and_then(|w| w.pending(key));
+            let res = match pending {
+                Some(write) => Ok(write),
+                None => loader.load(key),
+            };
+            match res {
+                Ok(Some(v)) => Ok(v),
+                Ok(None) => Err(Miss::Absent),
+                Err(e) => Err(Miss::Failed(e)),
+            }
+        };
+        match self.try_get_or_insert_with(key.clone(), load) {
+            Ok(v) => Ok(Some(v)),
+            Err(Miss::Absent) => Ok(None),
+            Err(Miss::Failed(e)) => Err(e),
+        }
+    }
+
+    /// Looks up `key` without counting it as a use of the key.
+    fn lookup(&self, key: &K) -> Option<V> {
+        let e = self.inner.map.get(key)?;
+        let now = self.inner.now();
+        if e.is_expired(now) {
+            self.expire(key);
+            return None;
+        }
+        self.inner.part_of(e.slot).touch(e.slot);
+        if let Some(at) = e.refresh_at {
+            if at <= now {
+                self.mark_stale(key, at);
+            }
+        }
+        Some(e.value)
+    }
+
+    /// Queues `key` for a refresh, unless someone else has already found its entry, which was due
+    /// for one `at`, stale.
+    fn mark_stale(&self, key: &K, at: Instant) {
+        // clearing the entry's refresh time is what makes sure only one read queues it
+        let old = self.inner.map.fetch_update(key, |e| {
+            e.map(|&e| {
+                if e.refresh_at == Some(at) {
+                    Entry {
+                        refresh_at: None,
+                        ..e
+                    }
+                } else {
+                    e
+                }
+            })
+        });
+        if old.is_some_and(|e| e.refresh_at == Some(at)) {
+            let stale = &self.inner.stale;
+            stale.keys.lock().unwrap().push(key.clone());
+            stale.found.notify_one();
+        }
+    }
+
+    /// Reloads the entries that have been found stale.
+    fn refresh_stale(&self) {
+        let refresh = match self.inner.refresh {
+            Some(ref refresh) => refresh,
+            None => return,
+        };
+        // whoever else is refreshing finishes first
+        let _refreshing = self
+            .inner
+            .refreshing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let stale = mem::take(&mut *self.inner.stale.keys.lock().unwrap());
+        for key in stale {
+            // the entry may have been removed or written to since
+            let (old, tag_id, version) = match self.inner.map.get(&key) {
+                Some(e) if e.refresh_at.is_none() => (e.value, e.tag_id, e.version),
+                _ => continue,
+            };
+
+            let start = Instant::now();
+            let res = (refresh.reload)(&key, old);
+            let stats = &self.inner.stats;
+            stats.record(Counter::LoadNanos, start.elapsed().as_nanos() as u64);
+            match res {
+                Ok(v) => {
+                    stats.record(Counter::LoadSuccess, 1);
+                    // the new value keeps the old one's tags
+                    let tags = tag_id.map_or_else(Vec::new, |id| self.inner.tags.tags_of(id));
+                    let tag_id = self.register_tags(&key, &tags);
+                    let mut removed = Vec::new();
+                    let expires = self.default_expiry();
+                    self.put_entry(
+                        key,
+                        v,
+                        expires,
+                        tag_id,
+                        Put::Refreshed(version),
+                        &mut removed,
+                    );
+                    self.notify(removed);
+                }
+                Err(e) => {
+                    stats.record(Counter::LoadFailure, 1);
+                    if let Some(ref on_error) = refresh.on_error {
+                        on_error(&key, &e);
+                    }
+                    // keep the stale value, and try again later
+                    let retry = self.inner.now() + refresh.interval;
+                    self.inner.map.fetch_update(&key, |e| {
+                        e.map(|&e| {
+                            if e.version == version && e.refresh_at.is_none() {
+                                Entry {
+                                    refresh_at: Some(retry),
+                                    ..e
+                                }
+                            } else {
+                                e
+                            }
+                        })
+                    });
+                }
+            }
+        }
+    }
+
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not
+    /// cached.
+    ///
+    /// Like [`crossbeam::MapHandle::get_or_insert_with`](::crossbeam::MapHandle::get_or_insert_with),
+    /// only one of the threads that miss on a key at the same time calls its `f`. A call counts as
+    /// a miss in the cache's [statistics](Cache::stats) if it calls `f`, and as a hit otherwise.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::Cache;
+    ///
+    /// let cache = Cache::new(16);
+    /// assert_eq!(cache.get_or_insert_with(1, || 10), 10);
+    /// assert_eq!(cache.get_or_insert_with(1, || 20), 10);
+    /// assert_eq!(cache.stats().load_successes, 1);
+    /// ```
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        match self.try_get_or_insert_with(key, || Ok::<V, Infallible>(f())) {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not
+    /// cached and `f` succeeds.
+    ///
+    /// See [`Cache::get_or_insert_with`], and
+    /// [`crossbeam::MapHandle::try_get_or_insert_with`](::crossbeam::MapHandle::try_get_or_insert_with)
+    /// for how errors are shared.
+    pub fn try_get_or_insert_with<F, E>(&self, key: K, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+        E: Clone + Send + Sync + 'static,
+    {
+        let inner = &*self.inner;
+        if let Some(ref sketch) = inner.sketch {
+            sketch.increment(&key);
+        }
+
+        let mut loaded = false;
+        let res = flight::get_or_load(
+            &inner.flights,
+            self,
+            key,
+            |cache, k| cache.lookup(k),
+            |cache, k, v| {
+                // a value that was inserted while this one was being loaded is newer
+                let mut removed = Vec::new();
+                let expires = cache.default_expiry();
+                let cached = cache.put_entry(k, v, expires, None, Put::Absent, &mut removed);
+                cache.notify(removed);
+                cached
+            },
+            || {
+                loaded = true;
+                let start = Instant::now();
+                let res = f();
+                let elapsed = start.elapsed();
+                inner
+                    .stats
+                    .record(Counter::LoadNanos, elapsed.as_nanos() as u64);
+                let counter = if res.is_ok() {
+                    Counter::LoadSuccess
+                } else {
+                    Counter::LoadFailure
+                };
+                inner.stats.record(counter, 1);
+                res
+            },
+        );
+
+        let counter = if loaded { Counter::Miss } else { Counter::Hit };
+        inner.stats.record(counter, 1);
+        res
+    }
+
+    /// Removes a key from the cache, returning its value if it was cached.
+    ///
+    /// If the cache [writes through](CacheBuilder::write_through) to a store, and deleting the key
+    /// from the store fails, the cache is left as it was, and `None` is returned.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut removed = Vec::new();
+        let ret = match self.inner.writer {
+            Some(ref writer) => {
+                let _locked = writer.lock(key);
+                if writer.write(key, None, self.inner.now()).is_err() {
+                    return None;
+                }
+                self.remove_as(key, RemovalCause::Explicit, &mut removed)
+            }
+            None => self.remove_as(key, RemovalCause::Explicit, &mut removed),
+        };
+        self.notify(removed);
+        ret
+    }
+
+    /// Writes out the writes that a [write-behind](CacheBuilder::write_behind) cache has queued
+    /// up, without waiting for the background thread to.
+    pub fn flush(&self) {
+        if let Some(ref writer) = self.inner.writer {
+            writer.flush();
+        }
+    }
+
+    fn remove_as(&self, key: &K, cause: RemovalCause, removed: &mut Removals<K, V>) -> Option<V> {
+        let e = self.inner.map.remove_if(key, |_| true)?;
+        self.unlink(key, e, cause, removed);
+        if e.is_expired(self.inner.now()) {
+            return None;
+        }
+        Some(e.value)
+    }
+
+    /// Removes every entry that was inserted with `tag`, and returns how many there were.
+    ///
+    /// The entries are removed as if by [`Cache::remove`], except that they are not deleted from
+    /// the cache's store, if it has one. See [`Cache::insert_with_tags`].
+    pub fn invalidate_tag(&self, tag: &str) -> usize {
+        let mut removed = Vec::new();
+        let mut n = 0;
+        for (id, key) in self.inner.tags.tagged(tag) {
+            if let Some(e) = self.inner.map.remove_if(&key, |e| e.tag_id == Some(id)) {
+                self.unlink(&key, e, RemovalCause::Explicit, &mut removed);
+                n += 1;
+            }
+        }
+        self.notify(removed);
+        n
+    }
+
+    /// Removes every entry for which `f` returns true, and returns how many there were.
+    ///
+    /// The entries are removed as if by [`Cache::remove`], except that they are not deleted from
+    /// the cache's store, if it has one. The cache is walked one slot at a time,
+    /// while other threads keep using it, so entries inserted during the walk may or may not be
+    /// looked at.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::Cache;
+    ///
+    /// let cache = Cache::new(16);
+    /// for i in 0..10 {
+    ///     cache.insert(i, i * i);
+    /// }
+    /// assert_eq!(cache.invalidate_where(|_, &v| v > 10), 6);
+    /// assert_eq!(cache.len(), 4);
+    /// ```
+    pub fn invalidate_where<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let inner = &*self.inner;
+        let mut removed = Vec::new();
+        let mut n = 0;
+        for slot in 0..self.capacity() {
+            let key = match *inner.part_of(slot).lock(slot) {
+                Some(ref key) => key.clone(),
+                None => continue,
+            };
+            if let Some(e) = inner
+                .map
+                .remove_if(&key, |e| e.slot == slot && f(&key, &e.value))
+            {
+                self.unlink(&key, e, RemovalCause::Explicit, &mut removed);
+                n += 1;
+            }
+        }
+        self.notify(removed);
+        n
+    }
+
+    /// Removes every entry from the cache.
+    pub fn clear(&self) {
+        let inner = &*self.inner;
+        let mut removed = Vec::new();
+        for slot in 0..self.capacity() {
+            let part = inner.part_of(slot);
+            let mut held = part.lock(slot);
+            let cleared = held.take().and_then(|key| {
+                part.release(slot);
+                inner
+                    .map
+                    .remove_if(&key, |e| e.slot == slot)
+                    .map(|e| (key, e))
+            });
+            drop(held);
+            if let Some((key, e)) = cleared {
+                self.discard(&key, e, RemovalCause::Cleared, &mut removed);
+            }
+        }
+        self.notify(removed);
+    }
+
+    /// Does the work that the cache's background threads do: removes the entries that have
+    /// expired, reloads the entries that reads have found stale, and writes out the
+    /// [write-behind](CacheBuilder::write_behind) writes that are due.
+    ///
+    /// If a background thread is in the middle of any of this work, it is finished first, so once
+    /// this returns, everything that was due when it was called has been done. There is usually no
+    /// need to call this, other than to not have to wait for the background thread, such as in
+    /// tests.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::Cache;
+    /// use std::time::Duration;
+    ///
+    /// let cache = Cache::builder(16)
+    ///     .refresh_after_write(Duration::from_secs(0), |_, v| Ok(v * 2))
+    ///     .build();
+    /// cache.insert("budget", 7);
+    /// // goes stale right away, and is reloaded by the background thread
+    /// assert_eq!(cache.get(&"budget"), Some(7));
+    /// cache.run_pending_tasks();
+    /// assert_eq!(cache.get(&"budget"), Some(14));
+    /// ```
+    pub fn run_pending_tasks(&self) {
+        self.sweep();
+        self.refresh_stale();
+    }
+
+    /// Removes the entries that have expired, and writes out the writes that are due.
+    fn sweep(&self) {
+        // whoever else is sweeping finishes first
+        let _sweeping = self
+            .inner
+            .sweeping_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.purge_expired();
+        if let Some(ref writer) = self.inner.writer {
+            writer.flush_due(self.inner.now());
+        }
+    }
+
+    /// Removes every entry that has expired.
+    ///
+    /// This is done in the background for caches that have entries that expire, so there is
+    /// usually no need to call this. Entries are removed within a tick of expiring, where a tick
+    /// is a tenth of a second.
+    pub fn purge_expired(&self) {
+        let now = self.inner.now();
+        let due = self.inner.timers.lock().unwrap().advance(now);
+        let mut later = Vec::new();
+        for key in due {
+            if self.expire(&key) {
+                continue;
+            }
+            // the entry was written to after its timer was set, and now expires later
+            if let Some(at) = self.inner.map.get(&key).and_then(|e| e.expires) {
+                later.push((key, at));
+            }
+        }
+        if !later.is_empty() {
+            let mut timers = self.inner.timers.lock().unwrap();
+            for (key, at) in later {
+                timers.schedule(key, at);
+            }
+        }
+    }
+
+    /// Removes `key` if it has expired, and returns whether it did.
+    fn expire(&self, key: &K) -> bool {
+        let now = self.inner.now();
+        match self.inner.map.remove_if(key, |e| e.is_expired(now)) {
+            Some(e) => {
+                let mut removed = Vec::new();
+                self.unlink(key, e, RemovalCause::Expired, &mut removed);
+                self.notify(removed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes `e`, which has just left the map as the entry for `key`, off the cache's weight, and
+    /// adds it to `removed` for the listener, if there is one.
+    fn discard(&self, key: &K, e: Entry<V>, cause: RemovalCause, removed: &mut Removals<K, V>) {
+        self.inner
+            .weight
+            .fetch_sub(u64::from(e.weight), Ordering::SeqCst);
+        if let Some(id) = e.tag_id {
+            self.inner.tags.forget(id);
+        }
+        let cause = if cause != RemovalCause::Cleared && e.is_expired(self.inner.now()) {
+            RemovalCause::Expired
+        } else {
+            cause
+        };
+        match cause {
+            RemovalCause::Explicit => self.inner.stats.record(Counter::Remove, 1),
+            RemovalCause::Expired => self.inner.stats.record(Counter::Expiration, 1),
+            RemovalCause::Evicted => self.inner.stats.record(Counter::Eviction, 1),
+            RemovalCause::Replaced | RemovalCause::Cleared => {}
+        }
+        if self.inner.listener.is_some() {
+            removed.push((key.clone(), e.value, cause));
+        }
+    }
+
+    /// Tells the listener about the entries that have left the cache. The caller must not hold
+    /// any of the cache's locks.
+    fn notify(&self, removed: Removals<K, V>) {
+        if let Some(ref listener) = self.inner.listener {
+            for (key, value, cause) in removed {
+                listener(&key, value, cause);
+            }
+        }
+    }
+
+    /// Discards `e`, which has just been removed from the map as the entry for `key`, and frees
+    /// its slot unless the slot has already been handed to another key.
+    fn unlink(&self, key: &K, e: Entry<V>, cause: RemovalCause, removed: &mut Removals<K, V>) {
+        self.discard(key, e, cause, removed);
+        let part = self.inner.part_of(e.slot);
+        let mut held = part.lock(e.slot);
+        if held.as_ref() == Some(key) && self.inner.map.get(key).map(|e| e.slot) != Some(e.slot) {
+            *held = None;
+            part.release(e.slot);
+        }
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Copy + Send + Sync + 'static,
+{
+    /// Inserts a key-value pair into the cache that expires after `ttl`, returning the previous
+    /// value for the key, if any.
+    ///
+    /// See [`Cache::insert`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::cache::Cache;
+    /// use std::time::Duration;
+    ///
+    /// let cache = Cache::new(16);
+    /// cache.insert_with_ttl("token", 7, Duration::from_secs(60));
+    /// // expires right away
+    /// cache.insert_with_ttl("nonce", 8, Duration::from_secs(0));
+    /// assert_eq!(cache.get(&"token"), Some(7));
+    /// assert_eq!(cache.get(&"nonce"), None);
+    /// ```
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        self.start_sweeper();
+        self.write_entry(key, value, Some(self.inner.now() + ttl), None)
+    }
+
+    /// Starts the thread that removes expired entries and writes behind, unless it is already
+    /// running.
+    ///
+    /// The thread only holds on to the cache while it is sweeping it, and exits once every handle
+    /// to the cache has been dropped.
+    fn start_sweeper(&self) {
+        if self.inner.clock.is_some() || self.inner.sweeping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let inner = Arc::downgrade(&self.inner);
+        thread::Builder::new()
+            .name("concache-sweeper".to_string())
+            .spawn(move || sweep(&inner))
+            .expect("failed to start the cache's sweeper thread");
+    }
+
+    /// Starts the thread that reloads stale entries.
+    ///
+    /// Reloads get a thread of their own so that a slow one does not hold up the sweeper. Like
+    /// the sweeper, the thread only holds on to the cache while it is reloading entries.
+    fn start_refresher(&self) {
+        if self.inner.clock.is_some() {
+            return;
+        }
+
+        let inner = Arc::downgrade(&self.inner);
+        let stale = Arc::clone(&self.inner.stale);
+        thread::Builder::new()
+            .name("concache-refresher".to_string())
+            .spawn(move || refresh(&inner, &stale))
+            .expect("failed to start the cache's refresher thread");
+    }
+}
+
+fn refresh<K, V>(inner: &Weak<Inner<K, V>>, stale: &StaleKeys<K>)
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    loop {
+        {
+            // wake up every tick regardless, to notice the cache going away
+            let keys = stale.keys.lock().unwrap();
+            drop(
+                stale
+                    .found
+                    .wait_timeout_while(keys, TICK, |keys| keys.is_empty()),
+            );
+        }
+        match inner.upgrade() {
+            Some(inner) => Cache { inner }.refresh_stale(),
+            None => return,
+        }
+    }
+}
+
+fn sweep<K, V>(inner: &Weak<Inner<K, V>>)
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    loop {
+        thread::sleep(TICK);
+        match inner.upgrade() {
+            Some(inner) => Cache { inner }.sweep(),
+            None => return,
+        }
+    }
+}
+
+impl<K, V> Clone for Cache<K, V> {
+    fn clone(&self) -> Self {
+        Cache {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+    use std::collections::HashMap;
+    use std::io;
+    use std::mem;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn cache_clock() {
+        let cache = Cache::new(4);
+        for i in 0..4 {
+            assert_eq!(cache.insert(i, i), None);
+        }
+        assert_eq!(cache.len(), 4);
+
+        // 0 gets a second chance, so 1 is evicted in its place
+        assert_eq!(cache.get(&0), Some(0));
+        assert_eq!(cache.insert(4, 4), None);
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&0), Some(0));
+
+        // updating a cached key does not evict anything
+        assert_eq!(cache.insert(4, 40), Some(4));
+        assert_eq!(cache.len(), 4);
+
+        assert_eq!(cache.remove(&4), Some(40));
+        assert_eq!(cache.remove(&4), None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.insert(5, 5), None);
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn cache_concurr() {
+        let builders: Vec<fn() -> CacheBuilder<u32, u32>> = vec![
+            || Cache::builder(64),
+            || Cache::builder(64).tiny_lfu(),
+            || Cache::builder(64).eviction_policy(SampledLru::new),
+            || Cache::builder(64).eviction_policy(Fifo::new).tiny_lfu(),
+            || Cache::builder(64).eviction_policy(Random::new),
+        ];
+        for builder in builders {
+            let cache = builder().build();
+            let mut threads = vec![];
+            for _ in 0..5 {
+                let cache = cache.clone();
+                threads.push(thread::spawn(move || {
+                    let mut rng = thread_rng();
+                    for _ in 0..20000 {
+                        let key = rng.gen_range(0, 256);
+                        match rng.gen_range(0, 4) {
+                            0 | 1 => {
+                                if let Some(v) = cache.get(&key) {
+                                    assert_eq!(v, key * 2);
+                                }
+                            }
+                            2 => {
+                                cache.insert(key, key * 2);
+                            }
+                            _ => {
+                                cache.remove(&key);
+                            }
+                        }
+                    }
+                }));
+            }
+            for t in threads {
+                t.join().unwrap();
+            }
+            assert!(cache.len() <= 64);
+        }
+    }
+
+    /// A clock that only moves when it is told to.
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<Instant>>);
+
+    impl ManualClock {
+        fn new() -> Self {
+            ManualClock(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+
+        /// Returns a builder for a cache of `n` entries that runs on this clock.
+        fn builder<K, V: 'static>(&self, n: usize) -> CacheBuilder<K, V> {
+            let now = Arc::clone(&self.0);
+            Cache::builder(n).clock(move || *now.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn cache_ttl() {
+        let clock = ManualClock::new();
+        let cache = clock.builder(4).build();
+        cache.insert(0, 0);
+        assert_eq!(cache.insert_with_ttl(1, 1, Duration::from_millis(20)), None);
+        assert_eq!(cache.insert_with_ttl(2, 2, Duration::from_millis(20)), None);
+        assert_eq!(cache.insert_with_ttl(3, 3, Duration::from_secs(60)), None);
+        assert_eq!(cache.get(&1), Some(1));
+
+        clock.advance(Duration::from_millis(50));
+        // expired entries are absent, and are removed as soon as they are looked up
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.insert_with_ttl(2, 20, Duration::from_secs(60)), None);
+        assert_eq!(cache.get(&2), Some(20));
+
+        // the rest are left to the background work
+        assert_eq!(cache.insert_with_ttl(4, 4, Duration::from_millis(20)), None);
+        clock.advance(TICK * 2);
+        cache.run_pending_tasks();
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&0), Some(0));
+        assert_eq!(cache.get(&2), Some(20));
+        assert_eq!(cache.get(&3), Some(3));
+
+        // their slots were freed, so there is room for one more without evicting anything
+        cache.insert(5, 5);
+        assert_eq!(cache.len(), 4);
+        for k in &[0, 2, 3, 5] {
+            assert!(cache.get(k).is_some());
+        }
+
+        // 2's first timer went off while it was still live, and was set again for its new expiry
+        clock.advance(Duration::from_secs(61));
+        cache.run_pending_tasks();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&0).is_some());
+        assert!(cache.get(&5).is_some());
+    }
+
+    #[test]
+    fn cache_ttl_timers() {
+        let cache = ManualClock::new().builder(16).build();
+        let timers = || cache.inner.timers.lock().unwrap().len();
+
+        // writing a key again only sets a new timer if its expiry moves earlier
+        for i in 0..1000 {
+            cache.insert_with_ttl(1, i, Duration::from_secs(60));
+        }
+        assert_eq!(timers(), 1);
+        cache.insert_with_ttl(1, 0, Duration::from_secs(30));
+        assert_eq!(timers(), 2);
+        for i in 0..10 {
+            cache.insert_with_ttl(i, i, Duration::from_secs(90));
+        }
+        assert_eq!(timers(), 11);
+        cache.insert(1, 1);
+        cache.insert_with_ttl(1, 1, Duration::from_secs(90));
+        assert_eq!(timers(), 12);
+    }
+
+    #[test]
+    fn cache_default_ttl() {
+        let clock = ManualClock::new();
+        let cache = clock
+            .builder(4)
+            .time_to_live(Duration::from_millis(20))
+            .build();
+        cache.insert(1, 1);
+        cache.insert_with_ttl(2, 2, Duration::from_secs(60));
+        assert_eq!(cache.get(&1), Some(1));
+
+        clock.advance(TICK * 2);
+        cache.run_pending_tasks();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(2));
+        assert_eq!(cache.remove(&2), Some(2));
+    }
+
+    #[test]
+    fn cache_weight() {
+        let cache = Cache::builder(16)
+            .weigher(|_: &u32, v: &u32| *v)
+            .max_weight(10)
+            .build();
+        for k in 1..5 {
+            cache.insert(k, k);
+        }
+        assert_eq!(cache.weight(), 10);
+        assert_eq!(cache.len(), 4);
+
+        // over budget: older entries make way, but the new one stays
+        cache.insert(5, 5);
+        assert!(cache.weight() <= 10);
+        assert_eq!(cache.get(&5), Some(5));
+        let cached: u32 = (1..6).filter_map(|k| cache.get(&k)).sum();
+        assert_eq!(u64::from(cached), cache.weight());
+
+        // replacing a value reweighs it
+        assert_eq!(cache.insert(5, 1), Some(5));
+        assert_eq!(u64::from(cached) - 4, cache.weight());
+
+        // too heavy to cache at all
+        assert_eq!(cache.insert(5, 11), Some(1));
+        assert_eq!(cache.get(&5), None);
+        assert_eq!(cache.insert(6, 11), None);
+        assert_eq!(cache.get(&6), None);
+
+        for k in 1..7 {
+            cache.remove(&k);
+        }
+        assert_eq!(cache.weight(), 0);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn cache_listener() {
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&removed);
+        let clock = ManualClock::new();
+        let cache = clock
+            .builder(2)
+            .weigher(|_, &v: &u32| v)
+            .max_weight(100)
+            .removal_listener(move |&k: &u32, v, cause| log.lock().unwrap().push((k, v, cause)))
+            .build();
+        let take = || mem::take(&mut *removed.lock().unwrap());
+
+        cache.insert(1, 1);
+        cache.insert(1, 2);
+        assert_eq!(cache.remove(&1), Some(2));
+        assert_eq!(cache.remove(&1), None);
+        assert_eq!(
+            take(),
+            vec![
+                (1, 1, RemovalCause::Replaced),
+                (1, 2, RemovalCause::Explicit)
+            ]
+        );
+
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        cache.insert(4, 101);
+        assert_eq!(
+            take(),
+            vec![
+                (1, 1, RemovalCause::Evicted),
+                (4, 101, RemovalCause::Evicted)
+            ]
+        );
+
+        cache.insert_with_ttl(5, 5, Duration::from_millis(10));
+        clock.advance(TICK * 2);
+        cache.run_pending_tasks();
+        assert_eq!(cache.get(&5), None);
+        let mut log = take();
+        log.sort_by_key(|&(k, _, _)| k);
+        assert_eq!(
+            log,
+            vec![(2, 2, RemovalCause::Evicted), (5, 5, RemovalCause::Expired)]
+        );
+
+        cache.insert(6, 6);
+        cache.clear();
+        let mut log = take();
+        log.sort_by_key(|&(k, _, _)| k);
+        assert_eq!(
+            log,
+            vec![(3, 3, RemovalCause::Cleared), (6, 6, RemovalCause::Cleared)]
+        );
+        assert!(cache.is_empty());
+        assert_eq!(cache.weight(), 0);
+    }
+
+    #[test]
+    fn cache_invalidate() {
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&removed);
+        let cache = Cache::builder(4)
+            .removal_listener(move |&k: &u32, v: u32, cause| {
+                log.lock().unwrap().push((k, v, cause))
+            })
+            .build();
+
+        cache.insert_with_tags(1, 1, &["odd", "small"]);
+        cache.insert_with_tags(2, 2, &["even", "small"]);
+        cache.insert_with_tags(3, 3, &["odd"]);
+        cache.insert(4, 4);
+        assert_eq!(cache.invalidate_tag("odd"), 2);
+        assert_eq!(cache.invalidate_tag("odd"), 0);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(2));
+
+        // a later write of the key does not carry the old tags
+        cache.insert(2, 20);
+        assert_eq!(cache.invalidate_tag("small"), 0);
+        assert_eq!(cache.get(&2), Some(20));
+
+        // neither do entries that have been evicted, whose slots are reused
+        cache.insert_with_tags(5, 5, &["big"]);
+        cache.insert_with_tags(6, 6, &["big"]);
+        cache.insert(7, 7);
+        cache.insert(8, 8);
+        assert!(cache.inner.tags.tagged("big").len() < 2);
+
+        let mut log = mem::take(&mut *removed.lock().unwrap());
+        log.retain(|&(_, _, cause)| cause == RemovalCause::Explicit);
+        log.sort_by_key(|&(k, _, _)| k);
+        assert_eq!(
+            log,
+            vec![
+                (1, 1, RemovalCause::Explicit),
+                (3, 3, RemovalCause::Explicit)
+            ]
+        );
+
+        let n = cache.len();
+        let evens = cache.invalidate_where(|&k, _| k % 2 == 0);
+        assert_eq!(cache.len(), n - evens);
+        assert!(cache.invalidate_where(|&k, _| k % 2 == 0) == 0);
+        assert_eq!(cache.invalidate_where(|_, _| true), n - evens);
+        assert!(cache.is_empty());
+        assert!(cache.inner.tags.tagged("big").is_empty());
+
+        // concurrent readers and writers see either the old value or none
+        let cache = Cache::new(64);
+        for i in 0..64u32 {
+            cache.insert(i, i);
+        }
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let k = thread_rng().gen_range(0, 64);
+                        if let Some(v) = cache.get(&k) {
+                            assert_eq!(v, k);
+                        }
+                    }
+                })
+            })
+            .collect();
+        cache.invalidate_where(|&k, _| k >= 32);
+        for t in readers {
+            t.join().unwrap();
+        }
+        assert!((32..64).all(|k| cache.get(&k).is_none()));
+    }
+
+    #[test]
+    fn cache_tiny_lfu() {
+        let plain = Cache::new(100);
+        let tiny_lfu = Cache::builder(100).tiny_lfu().build();
+        assert_eq!(tiny_lfu.capacity(), 100);
+        for cache in &[&plain, &tiny_lfu] {
+            for _ in 0..10 {
+                for k in 0..50 {
+                    if cache.get(&k).is_none() {
+                        cache.insert(k, k);
+                    }
+                }
+            }
+            for k in 1000..2000 {
+                cache.insert(k, k);
+            }
+        }
+
+        // the scan flushes the hot keys out of a plain cache, but not out of one with TinyLFU
+        let hot = |cache: &Cache<u32, u32>| (0..50).filter(|k| cache.get(k).is_some()).count();
+        assert!(hot(&plain) < 10);
+        assert!(hot(&tiny_lfu) >= 45);
+        assert_eq!(tiny_lfu.len(), 100);
+
+        // keys that keep missing earn their way in
+        for _ in 0..10 {
+            if tiny_lfu.get(&3000).is_none() {
+                tiny_lfu.insert(3000, 3000);
+            }
+            tiny_lfu.insert(3001 + thread_rng().gen_range(0, 1000), 0);
+        }
+        assert_eq!(tiny_lfu.get(&3000), Some(3000));
+    }
+
+    #[test]
+    fn cache_stats() {
+        let clock = ManualClock::new();
+        let cache = clock.builder(2).build();
+        cache.insert(1, 1);
+        cache.insert(1, 2);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        assert_eq!(cache.get(&3), Some(3));
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.remove(&3), Some(3));
+        assert_eq!(cache.remove(&3), None);
+        cache.insert_with_ttl(5, 5, Duration::from_millis(1));
+        clock.advance(TICK * 2);
+        cache.run_pending_tasks();
+        assert_eq!(cache.get(&5), None);
+
+        assert_eq!(cache.get_or_insert_with(6, || 6), 6);
+        assert_eq!(cache.get_or_insert_with(6, || unreachable!()), 6);
+        assert_eq!(cache.try_get_or_insert_with(7, || Err(())), Err(()));
+
+        let stats = cache.stats();
+        assert_eq!(
+            stats,
+            CacheStats {
+                hits: 2,
+                misses: 4,
+                inserts: 5,
+                updates: 1,
+                removes: 1,
+                // 1 was touched by its update, so 2 made way for 3
+                evictions: 1,
+                expirations: 1,
+                load_successes: 1,
+                load_failures: 1,
+                total_load_time: stats.total_load_time,
+            }
+        );
+        assert!(stats.total_load_time > Duration::from_secs(0));
+        assert_eq!(stats.hit_ratio(), 2.0 / 6.0);
+    }
+
+    #[test]
+    fn cache_refresh() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&errors);
+        let clock = ManualClock::new();
+        let cache = clock
+            .builder(16)
+            .refresh_after_write(TICK, |&k: &u32, v: u32| {
+                if k == 0 {
+                    Err("backend down".into())
+                } else {
+                    Ok(v + 1)
+                }
+            })
+            .on_refresh_error(move |&k, e| log.lock().unwrap().push((k, e.to_string())))
+            .build();
+        cache.insert(0, 0);
+        cache.insert(1, 10);
+        cache.insert(2, 20);
+        // fresh entries are left alone
+        assert_eq!(cache.get(&1), Some(10));
+
+        // stale ones are served while they are reloaded, once
+        clock.advance(TICK + TICK / 2);
+        assert_eq!(cache.get(&0), Some(0));
+        assert_eq!(cache.get(&1), Some(10));
+        assert_eq!(cache.get(&1), Some(10));
+        cache.run_pending_tasks();
+
+        let stats = cache.stats();
+        assert_eq!(stats.load_successes, 1);
+        assert_eq!(stats.load_failures, 1);
+        assert_eq!(stats.updates, 1);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![(0, "backend down".to_string())]
+        );
+
+        assert_eq!(cache.get(&1), Some(11));
+        // a failed reload keeps the old value
+        assert_eq!(cache.get(&0), Some(0));
+        // and entries that are not read are not refreshed
+        assert_eq!(cache.get(&2), Some(20));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn cache_refresh_race() {
+        // the reload writes to the cache itself, as another thread could while it runs
+        let cell: Arc<Mutex<Option<Cache<u32, u32>>>> = Arc::new(Mutex::new(None));
+        let shared = Arc::clone(&cell);
+        let clock = ManualClock::new();
+        let cache = clock
+            .builder(16)
+            .refresh_after_write(TICK, move |&k: &u32, v: u32| {
+                let cache = shared.lock().unwrap().clone().unwrap();
+                match k {
+                    1 => {
+                        cache.insert(1, 100);
+                    }
+                    2 => {
+                        cache.remove(&2);
+                    }
+                    _ => {}
+                }
+                Ok(v + 1)
+            })
+            .build();
+        *cell.lock().unwrap() = Some(cache.clone());
+        for k in 0..3 {
+            cache.insert(k, k * 10);
+        }
+
+        clock.advance(TICK * 2);
+        for k in 0..3 {
+            assert_eq!(cache.get(&k), Some(k * 10));
+        }
+        cache.run_pending_tasks();
+
+        // writes made during a reload win over it, and removed keys stay removed
+        assert_eq!(cache.get(&0), Some(1));
+        assert_eq!(cache.get(&1), Some(100));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.weight(), 2);
+        cell.lock().unwrap().take();
+    }
+
+    /// A write to a store, where `None` deletes the key.
+    type Write = (u32, Option<u32>);
+
+    /// An in-memory stand-in for the store a cache fronts, which keeps track of what is done to
+    /// it.
+    #[derive(Clone, Default)]
+    struct Store {
+        data: Arc<Mutex<HashMap<u32, u32>>>,
+        loads: Arc<AtomicUsize>,
+        writes: Arc<Mutex<Vec<Write>>>,
+        down: Arc<AtomicBool>,
+        /// Held by a test to keep writes from finishing.
+        hold: Arc<Mutex<()>>,
+        /// Set once a write has started.
+        writing: Arc<AtomicBool>,
+        /// Makes writes panic.
+        panicking: Arc<AtomicBool>,
+    }
+
+    impl Store {
+        fn check(&self) -> Result<(), StoreError> {
+            if self.down.load(Ordering::SeqCst) {
+                Err(Arc::new(io::Error::other("store down")))
+            } else {
+                Ok(())
+            }
+        }
+
+        fn writes(&self) -> Vec<Write> {
+            self.writes.lock().unwrap().clone()
+        }
+    }
+
+    impl CacheLoader<u32, u32> for Store {
+        fn load(&self, key: &u32) -> Result<Option<u32>, StoreError> {
+            self.loads.fetch_add(1, Ordering::SeqCst);
+            self.check()?;
+            Ok(self.data.lock().unwrap().get(key).cloned())
+        }
+    }
+
+    impl CacheWriter<u32, u32> for Store {
+        fn write(&self, key: &u32, value: u32) -> Result<(), StoreError> {
+            self.writing.store(true, Ordering::SeqCst);
+            drop(self.hold.lock().unwrap());
+            if self.panicking.load(Ordering::SeqCst) {
+                panic!("the store panicked");
+            }
+            self.check()?;
+            self.data.lock().unwrap().insert(*key, value);
+            self.writes.lock().unwrap().push((*key, Some(value)));
+            Ok(())
+        }
+
+        fn delete(&self, key: &u32) -> Result<(), StoreError> {
+            self.check()?;
+            self.data.lock().unwrap().remove(key);
+            self.writes.lock().unwrap().push((*key, None));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cache_write_through() {
+        let store = Store::default();
+        store.data.lock().unwrap().insert(1, 10);
+        let failed = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&failed);
+        let cache = Cache::builder(4)
+            .loader(store.clone())
+            .write_through(store.clone())
+            .on_write_error(move |&k, _| log.lock().unwrap().push(k))
+            .build();
+
+        // misses are loaded from the store, once, and not written back
+        assert_eq!(cache.get(&1), Some(10));
+        assert_eq!(cache.get(&1), Some(10));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(store.loads.load(Ordering::SeqCst), 2);
+        assert!(store.writes().is_empty());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!((stats.load_successes, stats.load_failures), (1, 1));
+
+        cache.insert(2, 20);
+        assert_eq!(cache.remove(&1), Some(10));
+        assert_eq!(store.writes(), vec![(2, Some(20)), (1, None)]);
+
+        // failed writes leave the cache as it was
+        store.down.store(true, Ordering::SeqCst);
+        assert_eq!(cache.insert(2, 21), None);
+        assert_eq!(cache.remove(&2), None);
+        assert_eq!(cache.get(&2), Some(20));
+        assert_eq!(*failed.lock().unwrap(), vec![2, 2]);
+        assert!(cache.try_get(&3).is_err());
+        assert_eq!(cache.get(&3), None);
+        store.down.store(false, Ordering::SeqCst);
+
+        // entries that leave the cache any other way stay in the store
+        for k in 10..20 {
+            cache.insert(k, k);
+        }
+        cache.clear();
+        assert_eq!(store.writes().len(), 12);
+        assert_eq!(store.data.lock().unwrap().len(), 11);
+
+        // the store ends up with the cache's value
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        cache.insert(100, t * 1000 + i);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(
+            store.data.lock().unwrap().get(&100).cloned(),
+            cache.get(&100)
+        );
+    }
+
+    #[test]
+    fn cache_load_race() {
+        // a loader that blocks until it is told to go on
+        let (started_tx, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new((started_tx, released)));
+        let wait = move || {
+            let gate = gate.lock().unwrap();
+            gate.0.send(()).unwrap();
+            gate.1.recv().unwrap();
+        };
+
+        // a value inserted while the key is being loaded is newer than the loaded one
+        let cache = Cache::new(4);
+        let loading = {
+            let (cache, wait) = (cache.clone(), wait.clone());
+            thread::spawn(move || {
+                cache.get_or_insert_with(1, || {
+                    wait();
+                    10
+                })
+            })
+        };
+        started.recv().unwrap();
+        assert_eq!(cache.insert(1, 20), None);
+        release.send(()).unwrap();
+        assert_eq!(loading.join().unwrap(), 20);
+        assert_eq!(cache.get(&1), Some(20));
+
+        // and the cache goes on to serve what was written through, not what the store had
+        let store = Store::default();
+        store.data.lock().unwrap().insert(1, 10);
+        let loader = {
+            let store = store.clone();
+            move |k: &u32| {
+                let v = store.load(k);
+                wait();
+                v
+            }
+        };
+        let cache = Cache::builder(4)
+            .loader(loader)
+            .write_through(store.clone())
+            .build();
+        let loading = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.get(&1))
+        };
+        started.recv().unwrap();
+        assert_eq!(cache.insert(1, 11), None);
+        release.send(()).unwrap();
+        assert_eq!(loading.join().unwrap(), Some(11));
+        assert_eq!(cache.get(&1), Some(11));
+        assert_eq!(store.data.lock().unwrap().get(&1), Some(&11));
+    }
+
+    #[test]
+    fn cache_write_behind() {
+        let store = Store::default();
+        let clock = ManualClock::new();
+        let cache = clock
+            .builder(16)
+            .loader(store.clone())
+            .write_behind(store.clone(), TICK)
+            .build();
+
+        for i in 0..10 {
+            cache.insert(1, i);
+        }
+        cache.insert(2, 2);
+        assert_eq!(cache.remove(&2), Some(2));
+        cache.insert(3, 3);
+        cache.run_pending_tasks();
+        assert!(store.writes().is_empty());
+
+        // a write that has not reached the store yet is newer than what the store has
+        assert_eq!(cache.invalidate_where(|&k, _| k == 3), 1);
+        assert_eq!(cache.get(&3), Some(3));
+        assert_eq!(store.loads.load(Ordering::SeqCst), 0);
+
+        clock.advance(TICK);
+        cache.run_pending_tasks();
+        let mut writes = store.writes();
+        writes.sort();
+        assert_eq!(writes, vec![(1, Some(9)), (2, None), (3, Some(3))]);
+
+        // what is still queued is written when the cache goes away
+        cache.insert(4, 4);
+        drop(cache);
+        assert_eq!(store.writes().last(), Some(&(4, Some(4))));
+    }
+
+    #[test]
+    fn cache_write_behind_inflight() {
+        let store = Store::default();
+        let cache = ManualClock::new()
+            .builder(16)
+            .loader(store.clone())
+            .write_behind(store.clone(), TICK)
+            .build();
+        cache.insert(1, 1);
+
+        // a batch that is being written is still newer than what the store has
+        let held = store.hold.lock().unwrap();
+        let flusher = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.flush())
+        };
+        while !store.writing.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        assert_eq!(cache.invalidate_where(|_, _| true), 1);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(store.loads.load(Ordering::SeqCst), 0);
+
+        drop(held);
+        flusher.join().unwrap();
+        assert_eq!(store.writes(), vec![(1, Some(1))]);
+        assert_eq!(cache.invalidate_where(|_, _| true), 1);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(store.loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn cache_write_behind_panic() {
+        let store = Store::default();
+        let cache = ManualClock::new()
+            .builder(16)
+            .loader(store.clone())
+            .write_behind(store.clone(), TICK)
+            .build();
+        cache.insert(1, 1);
+
+        // a batch the store panicked on is not newer than what the store has anymore
+        store.panicking.store(true, Ordering::SeqCst);
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| cache.flush())).is_err());
+        assert_eq!(cache.invalidate_where(|_, _| true), 1);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(store.loads.load(Ordering::SeqCst), 1);
+
+        // and the writer carries on
+        store.panicking.store(false, Ordering::SeqCst);
+        cache.insert(2, 2);
+        cache.flush();
+        assert_eq!(store.writes(), vec![(2, Some(2))]);
+    }
+
+    #[test]
+    fn cache_write_listener() {
+        // the listener hands the replaced value to another thread, which writes it back to the
+        // cache under the same key, so it needs the key's write lock
+        let cell: Arc<Mutex<Option<Cache<u32, u32>>>> = Arc::new(Mutex::new(None));
+        let shared = Arc::clone(&cell);
+        let store = Store::default();
+        let cache = Cache::builder(4)
+            .write_through(store.clone())
+            .removal_listener(move |&k: &u32, v, cause| {
+                if cause != RemovalCause::Explicit {
+                    return;
+                }
+                let cache = shared.lock().unwrap().clone().unwrap();
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || tx.send(cache.insert(k, v + 1)).unwrap());
+                assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(None));
+            })
+            .build();
+        *cell.lock().unwrap() = Some(cache.clone());
+
+        cache.insert(1, 1);
+        assert_eq!(cache.remove(&1), Some(1));
+        assert_eq!(cache.get(&1), Some(2));
+        assert_eq!(store.writes(), vec![(1, Some(1)), (1, None), (1, Some(2))]);
+        cell.lock().unwrap().take();
+    }
+}
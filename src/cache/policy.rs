@@ -0,0 +1,332 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Decides which entry a [`Cache`](super::Cache) evicts when it needs room.
+///
+/// A cache keeps its entries in a fixed number of slots, numbered from 0, and creates its policy
+/// with the number of slots it has. It then tells the policy whenever an entry is put in a slot,
+/// used or removed, and asks it for a victim whenever it needs a slot and none is free, or its
+/// entries weigh more than their [`max_weight`](super::CacheBuilder::max_weight).
+///
+/// The methods are called by every thread that uses the cache, at the same time, and some of them
+/// while the cache holds the lock on a slot, so they should be quick, and must not use the cache.
+/// They need not be exact either: the cache locks the victim's slot only once the policy has
+/// chosen it, and copes with victims that have been removed or replaced in the meantime.
+///
+/// # Examples
+///
+/// A policy that evicts the heaviest entry, to make as much room as it can:
+///
+/// ```
+/// use concache::cache::{Cache, EvictionPolicy};
+/// use std::sync::atomic::{AtomicU32, Ordering};
+///
+/// struct HeaviestFirst(Vec<AtomicU32>);
+///
+/// impl EvictionPolicy for HeaviestFirst {
+///     fn on_insert(&self, slot: usize, weight: u32) {
+///         self.0[slot].store(weight, Ordering::Relaxed);
+///     }
+///     fn on_access(&self, _: usize) {}
+///     fn on_remove(&self, slot: usize) {
+///         self.0[slot].store(0, Ordering::Relaxed);
+///     }
+///     fn choose_victim(&self) -> usize {
+///         (0..self.0.len())
+///             .max_by_key(|&i| self.0[i].load(Ordering::Relaxed))
+///             .unwrap()
+///     }
+/// }
+///
+/// let cache = Cache::builder(3)
+///     .weigher(|_, &v: &u32| v)
+///     .eviction_policy(|n| HeaviestFirst((0..n).map(|_| AtomicU32::new(0)).collect()))
+///     .build();
+/// cache.insert("a", 10);
+/// cache.insert("b", 500);
+/// cache.insert("c", 20);
+/// cache.insert("d", 30);
+/// assert_eq!(cache.get(&"b"), None);
+/// assert_eq!(cache.len(), 3);
+/// ```
+pub trait EvictionPolicy: Send + Sync {
+    /// Called when an entry that weighs `weight` is put in `slot`, in place of the entry that was
+    /// there before, if any.
+    fn on_insert(&self, slot: usize, weight: u32);
+
+    /// Called when the entry in `slot` is read, or its value replaced.
+    fn on_access(&self, slot: usize);
+
+    /// Called when the entry in `slot` is removed, leaving the slot empty.
+    fn on_remove(&self, slot: usize);
+
+    /// Returns the slot of the entry to evict next, which must be less than the number of slots.
+    fn choose_victim(&self) -> usize;
+}
+
+/// The [CLOCK](https://en.wikipedia.org/wiki/Page_replacement_algorithm#Clock), or
+/// second-chance, policy, which approximates evicting the least recently used entry. This is the
+/// default.
+///
+/// Using an entry only sets its slot's reference bit. To choose a victim, a hand sweeps over the
+/// slots, clearing the reference bits it finds set, and stops at the first slot whose bit was
+/// already clear; that is, at the first entry that has not been used since the hand last came by.
+pub struct Clock {
+    referenced: Vec<AtomicBool>,
+    hand: AtomicUsize,
+}
+
+impl Clock {
+    /// Creates the policy for a cache with `nslots` slots.
+    pub fn new(nslots: usize) -> Self {
+        Clock {
+            referenced: (0..nslots).map(|_| AtomicBool::new(false)).collect(),
+            hand: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl EvictionPolicy for Clock {
+    fn on_insert(&self, slot: usize, _: u32) {
+        // don't let the new entry inherit the last one's second chance
+        self.referenced[slot].store(false, Ordering::Relaxed);
+    }
+
+    fn on_access(&self, slot: usize) {
+        self.referenced[slot].store(true, Ordering::Relaxed);
+    }
+
+    fn on_remove(&self, slot: usize) {
+        self.referenced[slot].store(false, Ordering::Relaxed);
+    }
+
+    fn choose_victim(&self) -> usize {
+        loop {
+            let i = self.hand.fetch_add(1, Ordering::SeqCst) % self.referenced.len();
+            if !self.referenced[i].swap(false, Ordering::SeqCst) {
+                return i;
+            }
+            // second chance
+        }
+    }
+}
+
+/// A sampled least-recently-used policy, in the style of Redis: to choose a victim, it looks at a
+/// few slots at random, and picks the one whose entry was used longest ago.
+///
+/// Looking at more slots makes it closer to true LRU, at the cost of longer evictions.
+pub struct SampledLru {
+    /// When each slot's entry was last used, by `clock`, or `u64::MAX` if the slot is empty.
+    last_used: Vec<AtomicU64>,
+    clock: AtomicU64,
+    samples: usize,
+}
+
+impl SampledLru {
+    /// Creates the policy for a cache with `nslots` slots, looking at five of them for each
+    /// victim.
+    pub fn new(nslots: usize) -> Self {
+        Self::with_samples(nslots, 5)
+    }
+
+    /// Creates the policy for a cache with `nslots` slots, looking at `samples` of them for each
+    /// victim.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `samples` is 0.
+    pub fn with_samples(nslots: usize, samples: usize) -> Self {
+        assert!(
+            samples > 0,
+            "a victim must be chosen from at least one slot"
+        );
+        SampledLru {
+            last_used: (0..nslots).map(|_| AtomicU64::new(u64::MAX)).collect(),
+            clock: AtomicU64::new(0),
+            samples,
+        }
+    }
+}
+
+impl EvictionPolicy for SampledLru {
+    fn on_insert(&self, slot: usize, _: u32) {
+        self.on_access(slot);
+    }
+
+    fn on_access(&self, slot: usize) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        self.last_used[slot].store(now, Ordering::Relaxed);
+    }
+
+    fn on_remove(&self, slot: usize) {
+        self.last_used[slot].store(u64::MAX, Ordering::Relaxed);
+    }
+
+    fn choose_victim(&self) -> usize {
+        (0..self.samples)
+            .map(|_| random_below(self.last_used.len()))
+            .min_by_key(|&i| self.last_used[i].load(Ordering::Relaxed))
+            .unwrap()
+    }
+}
+
+/// A first-in, first-out policy, which evicts the entry that was inserted longest ago, however
+/// much it has been used since.
+///
+/// A victim that the cache ends up keeping, such as one that a [TinyLFU
+/// candidate](super::CacheBuilder::tiny_lfu) fails to displace, goes to the back of the queue.
+pub struct Fifo {
+    /// The insertion number of each slot's entry, or 0 if the slot is empty.
+    inserted: Vec<AtomicU64>,
+    next: AtomicU64,
+    /// Slots in the order their entries were inserted, with their insertion numbers. Entries that
+    /// have left their slot are skipped, and dropped, when they come up.
+    queue: Mutex<VecDeque<(usize, u64)>>,
+}
+
+impl Fifo {
+    /// Creates the policy for a cache with `nslots` slots.
+    pub fn new(nslots: usize) -> Self {
+        Fifo {
+            inserted: (0..nslots).map(|_| AtomicU64::new(0)).collect(),
+            next: AtomicU64::new(1),
+            queue: Mutex::new(VecDeque::with_capacity(nslots)),
+        }
+    }
+
+    fn is_current(&self, &(slot, n): &(usize, u64)) -> bool {
+        self.inserted[slot].load(Ordering::Relaxed) == n
+    }
+}
+
+impl EvictionPolicy for Fifo {
+    fn on_insert(&self, slot: usize, _: u32) {
+        let mut queue = self.queue.lock().unwrap();
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        self.inserted[slot].store(n, Ordering::Relaxed);
+        queue.push_back((slot, n));
+        // entries that are removed rather than evicted never come up on their own
+        if queue.len() > 2 * self.inserted.len() {
+            queue.retain(|e| self.is_current(e));
+        }
+    }
+
+    fn on_access(&self, _: usize) {}
+
+    fn on_remove(&self, slot: usize) {
+        self.inserted[slot].store(0, Ordering::Relaxed);
+    }
+
+    fn choose_victim(&self) -> usize {
+        let mut queue = self.queue.lock().unwrap();
+        while let Some(e) = queue.pop_front() {
+            if self.is_current(&e) {
+                queue.push_back(e);
+                return e.0;
+            }
+        }
+        // every slot is empty
+        0
+    }
+}
+
+/// A policy that evicts an entry at random.
+pub struct Random {
+    nslots: usize,
+}
+
+impl Random {
+    /// Creates the policy for a cache with `nslots` slots.
+    pub fn new(nslots: usize) -> Self {
+        Random { nslots }
+    }
+}
+
+impl EvictionPolicy for Random {
+    fn on_insert(&self, _: usize, _: u32) {}
+
+    fn on_access(&self, _: usize) {}
+
+    fn on_remove(&self, _: usize) {}
+
+    fn choose_victim(&self) -> usize {
+        random_below(self.nslots)
+    }
+}
+
+thread_local! {
+    /// The state of this thread's xorshift generator, which must never be 0.
+    static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+/// Returns a random number below `n`, which is good enough for choosing slots.
+fn random_below(n: usize) -> usize {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        (x % n as u64) as usize
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eviction_policies() {
+        let clock = Clock::new(3);
+        for i in 0..3 {
+            clock.on_insert(i, 1);
+        }
+        clock.on_access(0);
+        assert_eq!(clock.choose_victim(), 1);
+        clock.on_insert(1, 1);
+        assert_eq!(clock.choose_victim(), 2);
+        clock.on_insert(2, 1);
+        // 0 has had its second chance
+        assert_eq!(clock.choose_victim(), 0);
+
+        // with as many samples as slots, every slot is almost surely looked at
+        let lru = SampledLru::with_samples(4, 64);
+        for i in 0..4 {
+            lru.on_insert(i, 1);
+        }
+        lru.on_access(0);
+        lru.on_access(1);
+        assert_eq!(lru.choose_victim(), 2);
+        lru.on_remove(3);
+        lru.on_access(2);
+        assert_eq!(lru.choose_victim(), 0);
+
+        let fifo = Fifo::new(3);
+        for &i in &[2, 0, 1] {
+            fifo.on_insert(i, 1);
+        }
+        fifo.on_access(2);
+        assert_eq!(fifo.choose_victim(), 2);
+        fifo.on_insert(2, 1);
+        fifo.on_remove(0);
+        assert_eq!(fifo.choose_victim(), 1);
+        // a victim that stays goes to the back
+        assert_eq!(fifo.choose_victim(), 2);
+        for _ in 0..100 {
+            fifo.on_insert(0, 1);
+            fifo.on_remove(0);
+        }
+        assert!(fifo.queue.lock().unwrap().len() <= 7);
+
+        let random = Random::new(5);
+        let mut seen = [false; 5];
+        for _ in 0..1000 {
+            seen[random.choose_victim()] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+/// The number of counters each key has, one in each row of the sketch.
+const DEPTH: usize = 4;
+/// Counters saturate here, which is as high as TinyLFU needs to tell keys apart.
+const MAX: u8 = 15;
+
+/// A count-min sketch of how often keys have been used recently, for TinyLFU admission.
+///
+/// Each key is hashed to one counter in each of a few rows, and its frequency is estimated as the
+/// smallest of those counters, which overestimates it only if all of them are shared with other
+/// keys. Counters are atomic, so recording a use never takes a lock. Once the sketch has recorded
+/// ten times as many uses as the cache has entries, every counter is halved, so that keys that were
+/// popular a long time ago make way for ones that are popular now. Rows have a few times as many
+/// counters as the cache has entries, which keeps the keys that share counters from adding up to
+/// much before then.
+pub(super) struct FrequencySketch {
+    counters: Vec<AtomicU8>,
+    width: usize,
+    additions: AtomicUsize,
+    sample: usize,
+    aging: AtomicBool,
+}
+
+impl FrequencySketch {
+    /// Creates a sketch for a cache of `capacity` entries.
+    pub(super) fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(16);
+        let width = (4 * capacity).next_power_of_two();
+        FrequencySketch {
+            counters: (0..DEPTH * width).map(|_| AtomicU8::new(0)).collect(),
+            width,
+            additions: AtomicUsize::new(0),
+            sample: 10 * capacity,
+            aging: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the index of `key`'s counter in each row.
+    fn counters_of<K: Hash>(&self, key: &K) -> [usize; DEPTH] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        // double hashing, with an odd step so that the rows use different bits
+        let step = hash.rotate_left(32).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+
+        let mut idx = [0; DEPTH];
+        for (row, i) in idx.iter_mut().enumerate() {
+            let h = hash.wrapping_add((row as u64).wrapping_mul(step));
+            *i = row * self.width + (h as usize & (self.width - 1));
+        }
+        idx
+    }
+
+    /// Records a use of `key`.
+    pub(super) fn increment<K: Hash>(&self, key: &K) {
+        for &i in &self.counters_of(key) {
+            let _ = self.counters[i].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+                if c < MAX {
+                    Some(c + 1)
+                } else {
+                    None
+                }
+            });
+        }
+
+        if self.additions.fetch_add(1, Ordering::Relaxed) + 1 >= self.sample {
+            self.age();
+        }
+    }
+
+    /// Returns roughly how often `key` has been used recently.
+    pub(super) fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        self.counters_of(key)
+            .iter()
+            .map(|&i| self.counters[i].load(Ordering::Relaxed))
+            .min()
+            .unwrap()
+    }
+
+    /// Halves every counter.
+    fn age(&self) {
+        if self.aging.swap(true, Ordering::Acquire) {
+            // someone else is on it
+            return;
+        }
+        for c in &self.counters {
+            let _ = c.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| Some(c / 2));
+        }
+        self.additions.store(self.sample / 2, Ordering::Relaxed);
+        self.aging.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sketch_frequency() {
+        let sketch = FrequencySketch::new(64);
+        for _ in 0..20 {
+            sketch.increment(&"hot");
+        }
+        for _ in 0..3 {
+            sketch.increment(&"warm");
+        }
+        assert_eq!(sketch.frequency(&"hot"), MAX);
+        assert!(sketch.frequency(&"warm") >= 3);
+        assert!(sketch.frequency(&"warm") < MAX);
+
+        // once enough uses have been recorded, everything is halved
+        let n = sketch.sample - sketch.additions.load(Ordering::Relaxed);
+        for i in 0..n {
+            sketch.increment(&i);
+        }
+        assert_eq!(sketch.frequency(&"hot"), MAX / 2);
+        assert!(sketch.frequency(&"warm") <= MAX / 2);
+    }
+}
@@ -0,0 +1,93 @@
+use super::policy::EvictionPolicy;
+use std::sync::{Mutex, MutexGuard};
+
+/// The slots that hold the entries of a cache, or of part of one.
+///
+/// Every entry of the cache lives in one of a fixed number of slots, which bounds the size of the
+/// cache. The slot of an entry holds its key, behind a lock that is held while the slot changes
+/// hands. Slots that are empty, because the cache has not filled up yet or because their entry
+/// was removed, are kept on a free list and used before anything is evicted; otherwise the
+/// eviction policy chooses which entry's slot to take.
+///
+/// A cache may divide its slots between several sets, so a set's slots are numbered from `first`
+/// on. Its policy only ever sees the slots numbered from 0.
+pub(super) struct Slots<K> {
+    first: usize,
+    keys: Vec<Mutex<Option<K>>>,
+    free: Mutex<Vec<usize>>,
+    policy: Box<dyn EvictionPolicy>,
+}
+
+impl<K> Slots<K> {
+    pub(super) fn new(first: usize, nslots: usize, policy: Box<dyn EvictionPolicy>) -> Self {
+        assert!(
+            nslots > 0,
+            "a cache must be able to hold at least one entry"
+        );
+        Slots {
+            first,
+            keys: (0..nslots).map(|_| Mutex::new(None)).collect(),
+            free: Mutex::new((first..first + nslots).rev().collect()),
+            policy,
+        }
+    }
+
+    /// Returns the number of slots, which is the most entries the cache can hold.
+    pub(super) fn capacity(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns true if `slot` is one of these.
+    pub(super) fn contains(&self, slot: usize) -> bool {
+        slot >= self.first && slot < self.first + self.keys.len()
+    }
+
+    /// Records a use of the entry in `slot`.
+    pub(super) fn touch(&self, slot: usize) {
+        self.policy.on_access(slot - self.first);
+    }
+
+    /// Locks `slot`, giving access to the key of the entry in it.
+    pub(super) fn lock(&self, slot: usize) -> MutexGuard<'_, Option<K>> {
+        self.keys[slot - self.first].lock().unwrap()
+    }
+
+    /// Puts the entry for `key`, which weighs `weight`, in `slot`. The caller must hold the lock
+    /// on the slot, and pass in what it guards as `held`.
+    pub(super) fn fill(&self, slot: usize, held: &mut Option<K>, key: K, weight: u32) {
+        *held = Some(key);
+        self.policy.on_insert(slot - self.first, weight);
+    }
+
+    /// Returns an empty slot to the free list. The caller must hold the lock on the slot, and have
+    /// emptied it.
+    pub(super) fn release(&self, slot: usize) {
+        self.policy.on_remove(slot - self.first);
+        self.free.lock().unwrap().push(slot);
+    }
+
+    /// Finds and locks a slot for a new entry: a free one if there is any, and otherwise the one
+    /// chosen by `next_victim`.
+    pub(super) fn claim(&self) -> (usize, MutexGuard<'_, Option<K>>) {
+        loop {
+            let free = self.free.lock().unwrap().pop();
+            match free {
+                Some(i) => {
+                    let key = self.lock(i);
+                    if key.is_none() {
+                        return (i, key);
+                    }
+                    // the policy got to it first
+                }
+                None => return self.next_victim(),
+            }
+        }
+    }
+
+    /// Asks the policy for the slot whose entry should be evicted next, and locks that slot so its
+    /// entry can be replaced.
+    pub(super) fn next_victim(&self) -> (usize, MutexGuard<'_, Option<K>>) {
+        let i = self.policy.choose_victim();
+        (self.first + i, self.keys[i].lock().unwrap())
+    }
+}
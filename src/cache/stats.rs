@@ -0,0 +1,183 @@
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// A snapshot of how a [`Cache`](super::Cache) has been used since it was created.
+///
+/// # Examples
+///
+/// ```
+/// use concache::cache::Cache;
+///
+/// let cache = Cache::new(16);
+/// cache.insert(1, "a");
+/// cache.get(&1);
+/// cache.get(&2);
+///
+/// let stats = cache.stats();
+/// assert_eq!((stats.hits, stats.misses, stats.inserts), (1, 1, 1));
+/// assert_eq!(stats.hit_ratio(), 0.5);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups that found a value.
+    pub hits: u64,
+    /// Lookups that found no value, or an expired one.
+    pub misses: u64,
+    /// Inserts of keys that were not cached.
+    pub inserts: u64,
+    /// Inserts that replaced the value of a cached key.
+    pub updates: u64,
+    /// Entries removed with [`Cache::remove`](super::Cache::remove).
+    pub removes: u64,
+    /// Entries evicted to keep the cache within its bounds.
+    pub evictions: u64,
+    /// Entries removed because they expired.
+    pub expirations: u64,
+    /// Values loaded by [`Cache::get_or_insert_with`](super::Cache::get_or_insert_with),
+    /// [`Cache::try_get_or_insert_with`](super::Cache::try_get_or_insert_with), or the cache's
+    /// [loader](super::CacheBuilder::loader).
+    pub load_successes: u64,
+    /// Loads by [`Cache::try_get_or_insert_with`](super::Cache::try_get_or_insert_with) that
+    /// failed, and loads by the cache's loader that failed or found no value.
+    pub load_failures: u64,
+    /// The time spent loading values, whether they loaded or not.
+    pub total_load_time: Duration,
+}
+
+impl CacheStats {
+    /// Returns the number of lookups, which is the number of hits plus the number of misses.
+    pub fn requests(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// Returns the fraction of lookups that found a value, or 1 if there have been none.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.requests() {
+            0 => 1.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+
+    /// Returns the average time it took to load a value, or zero if no values have been loaded.
+    pub fn average_load_time(&self) -> Duration {
+        match self.load_successes + self.load_failures {
+            0 => Duration::from_secs(0),
+            n => self.total_load_time / n as u32,
+        }
+    }
+}
+
+/// The things the cache counts.
+#[derive(Clone, Copy)]
+pub(super) enum Counter {
+    Hit,
+    Miss,
+    Insert,
+    Update,
+    Remove,
+    Eviction,
+    Expiration,
+    LoadSuccess,
+    LoadFailure,
+    LoadNanos,
+}
+
+const COUNTERS: usize = Counter::LoadNanos as usize + 1;
+const STRIPES: usize = 16;
+
+/// One set of counters, on a cache line of its own.
+#[repr(align(64))]
+#[derive(Default)]
+struct Stripe([AtomicU64; COUNTERS]);
+
+impl Deref for Stripe {
+    type Target = [AtomicU64; COUNTERS];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Hands out stripes to threads in turn.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The stripe this thread records to, in every cache.
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % STRIPES;
+}
+
+/// The cache's counters, striped so that threads recording at the same time rarely touch the same
+/// cache line.
+#[derive(Default)]
+pub(super) struct Stats {
+    stripes: [Stripe; STRIPES],
+}
+
+impl Stats {
+    /// Adds `n` to `counter`.
+    pub(super) fn record(&self, counter: Counter, n: u64) {
+        let stripe = STRIPE.with(|&s| s);
+        self.stripes[stripe][counter as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Sums up the counters.
+    pub(super) fn snapshot(&self) -> CacheStats {
+        let sum = |counter: Counter| -> u64 {
+            self.stripes
+                .iter()
+                .map(|s| s[counter as usize].load(Ordering::Relaxed))
+                .sum()
+        };
+        CacheStats {
+            hits: sum(Counter::Hit),
+            misses: sum(Counter::Miss),
+            inserts: sum(Counter::Insert),
+            updates: sum(Counter::Update),
+            removes: sum(Counter::Remove),
+            evictions: sum(Counter::Eviction),
+            expirations: sum(Counter::Expiration),
+            load_successes: sum(Counter::LoadSuccess),
+            load_failures: sum(Counter::LoadFailure),
+            total_load_time: Duration::from_nanos(sum(Counter::LoadNanos)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn stats_striped() {
+        let stats = Arc::new(Stats::default());
+        let threads: Vec<_> = (0..20)
+            .map(|_| {
+                let stats = Arc::clone(&stats);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        stats.record(Counter::Hit, 1);
+                    }
+                    stats.record(Counter::Miss, 1);
+                    stats.record(Counter::LoadNanos, 1_000);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.hits, 2000);
+        assert_eq!(snapshot.misses, 20);
+        assert_eq!(snapshot.requests(), 2020);
+        assert_eq!(snapshot.total_load_time, Duration::from_micros(20));
+        assert!((snapshot.hit_ratio() - 2000.0 / 2020.0).abs() < 1e-9);
+        assert_eq!(CacheStats::default().hit_ratio(), 1.0);
+        assert_eq!(
+            CacheStats::default().average_load_time(),
+            Duration::from_secs(0)
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// The error a backing store fails with.
+///
+/// It is reference-counted so that it can be shared with every thread waiting for the same load.
+pub type StoreError = Arc<dyn Error + Send + Sync>;
+
+/// Loads values from the store that a [`Cache`](super::Cache) fronts, when they are not cached.
+///
+/// See [`CacheBuilder::loader`](super::CacheBuilder::loader). Any function with the signature of
+/// [`load`](CacheLoader::load) is a loader.
+pub trait CacheLoader<K, V>: Send + Sync {
+    /// Returns the value the store has for `key`, if any.
+    fn load(&self, key: &K) -> Result<Option<V>, StoreError>;
+}
+
+impl<K, V, F> CacheLoader<K, V> for F
+where
+    F: Fn(&K) -> Result<Option<V>, StoreError> + Send + Sync,
+{
+    fn load(&self, key: &K) -> Result<Option<V>, StoreError> {
+        self(key)
+    }
+}
+
+/// Writes the values inserted into a [`Cache`](super::Cache), and the keys removed from it, to the
+/// store that the cache fronts.
+///
+/// See [`CacheBuilder::write_through`](super::CacheBuilder::write_through) and
+/// [`CacheBuilder::write_behind`](super::CacheBuilder::write_behind).
+pub trait CacheWriter<K, V>: Send + Sync {
+    /// Writes `value` as the value of `key`.
+    fn write(&self, key: &K, value: V) -> Result<(), StoreError>;
+
+    /// Deletes `key`.
+    fn delete(&self, key: &K) -> Result<(), StoreError>;
+
+    /// Writes a batch of writes queued up by a write-behind cache, where `None` deletes the key.
+    /// Each key appears at most once.
+    ///
+    /// By default, this writes the batch one key at a time, and fails with the first error, once
+    /// every key has been tried.
+    fn write_batch(&self, batch: Vec<(K, Option<V>)>) -> Result<(), StoreError> {
+        let mut res = Ok(());
+        for (key, value) in batch {
+            let r = match value {
+                Some(v) => self.write(&key, v),
+                None => self.delete(&key),
+            };
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+}
+
+pub(super) type WriteErrorHandler<K> = Box<dyn Fn(&K, &StoreError) + Send + Sync>;
+
+const STRIPES: usize = 64;
+
+/// The writes that a write-behind cache has yet to make.
+struct Pending<K, V> {
+    /// The latest write of each key, so that repeated writes to a key are coalesced into one.
+    writes: HashMap<K, Option<V>>,
+    /// When the oldest of the writes was queued.
+    since: Option<Instant>,
+    /// The batch that is being written, which the store may not have yet either.
+    inflight: HashMap<K, Option<V>>,
+}
+
+/// Clears the batch that is being written once the store is done with it, whether it returned or
+/// panicked, since the store then has the batch, or never will.
+struct Inflight<'a, K: 'a, V: 'a>(&'a Mutex<Pending<K, V>>);
+
+impl<'a, K, V> Drop for Inflight<'a, K, V> {
+    fn drop(&mut self) {
+        let mut pending = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        pending.inflight.clear();
+    }
+}
+
+/// A cache's writer, along with what it needs to write through or behind.
+pub(super) struct Writer<K, V> {
+    store: Box<dyn CacheWriter<K, V>>,
+    /// How long writes wait to be written behind, or `None` to write through.
+    delay: Option<Duration>,
+    on_error: Option<WriteErrorHandler<K>>,
+    /// Locks that serialize the writes to each key, so that the cache and the store see them in
+    /// the same order.
+    stripes: Vec<Mutex<()>>,
+    hasher: RandomState,
+    pending: Mutex<Pending<K, V>>,
+    flushing: Mutex<()>,
+}
+
+impl<K, V> Writer<K, V> {
+    pub(super) fn new(
+        store: Box<dyn CacheWriter<K, V>>,
+        delay: Option<Duration>,
+        on_error: Option<WriteErrorHandler<K>>,
+    ) -> Self {
+        Writer {
+            store,
+            delay,
+            on_error,
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+            pending: Mutex::new(Pending {
+                writes: HashMap::new(),
+                since: None,
+                inflight: HashMap::new(),
+            }),
+            flushing: Mutex::new(()),
+        }
+    }
+
+    /// Returns true if writes are queued up rather than made straight away.
+    pub(super) fn is_behind(&self) -> bool {
+        self.delay.is_some()
+    }
+}
+
+impl<K, V> Writer<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    /// Locks `key` against writes by other threads.
+    ///
+    /// The lock is only held while the write is made to the store and the cache, and not while
+    /// the removal listener is told about what the write replaced, so the listener may write to
+    /// the cache itself.
+    pub(super) fn lock(&self, key: &K) -> MutexGuard<'_, ()> {
+        let stripe = &self.stripes[self.hasher.hash_one(key) as usize % STRIPES];
+        stripe.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Writes `value` for `key`, or deletes `key` if it is `None`: straight to the store when
+    /// writing through, in which case the error is returned, and onto the queue otherwise, as of
+    /// `now`. The caller must hold the lock on `key`.
+    pub(super) fn write(&self, key: &K, value: Option<V>, now: Instant) -> Result<(), StoreError> {
+        if self.delay.is_none() {
+            let res = match value {
+                Some(v) => self.store.write(key, v),
+                None => self.store.delete(key),
+            };
+            if let Err(ref e) = res {
+                if let Some(ref on_error) = self.on_error {
+                    on_error(key, e);
+                }
+            }
+            return res;
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.since.get_or_insert(now);
+        pending.writes.insert(key.clone(), value);
+        Ok(())
+    }
+
+    /// Returns the queued write of `key`, if there is one, or the write of `key` that is being
+    /// written, either of which is newer than what the store has.
+    pub(super) fn pending(&self, key: &K) -> Option<Option<V>> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .writes
+            .get(key)
+            .or_else(|| pending.inflight.get(key))
+            .cloned()
+    }
+
+    /// Writes out every queued write, however recently it was queued.
+    pub(super) fn flush(&self) {
+        // a batch must not overtake the one before it
+        let _flushing = self.flushing.lock().unwrap_or_else(PoisonError::into_inner);
+        let batch: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.since = None;
+            pending.inflight = mem::take(&mut pending.writes);
+            pending
+                .inflight
+                .iter()
+                .map(|(k, &v)| (k.clone(), v))
+                .collect()
+        };
+        if batch.is_empty() {
+            return;
+        }
+
+        let keys: Vec<_> = match self.on_error {
+            Some(_) => batch.iter().map(|(k, _)| k.clone()).collect(),
+            None => Vec::new(),
+        };
+        let res = {
+            let _inflight = Inflight(&self.pending);
+            self.store.write_batch(batch)
+        };
+        if let Err(e) = res {
+            if let Some(ref on_error) = self.on_error {
+                for key in &keys {
+                    on_error(key, &e);
+                }
+            }
+        }
+    }
+
+    /// Writes out the queued writes if the oldest of them has waited long enough by `now`.
+    pub(super) fn flush_due(&self, now: Instant) {
+        let due = match (self.delay, self.pending.lock().unwrap().since) {
+            (Some(delay), Some(since)) => since + delay <= now,
+            _ => false,
+        };
+        if due {
+            self.flush();
+        }
+    }
+}
+
+impl<K, V> Drop for Writer<K, V> {
+    fn drop(&mut self) {
+        // don't lose the writes that are still queued
+        let writes = mem::take(&mut self.pending.get_mut().unwrap().writes);
+        if !writes.is_empty() {
+            // there is no one left to tell about errors
+            let _ = self.store.write_batch(writes.into_iter().collect());
+        }
+    }
+}
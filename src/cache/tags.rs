@@ -0,0 +1,116 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The tags of the entries in a cache, for invalidating entries by tag.
+///
+/// Tags belong to a single write of a key rather than to the key, so that invalidating a tag does
+/// not remove a value that was written after the tagged one. Each tagged write gets an id, which
+/// its entry carries, and the index maps each tag to the ids of the writes that carry it. A write
+/// is forgotten when its entry leaves the cache, however that happens.
+///
+/// Untagged entries never touch the index, which is behind a single lock.
+pub(super) struct TagIndex<K> {
+    next_id: AtomicU64,
+    inner: Mutex<Tags<K>>,
+}
+
+struct Tags<K> {
+    by_tag: HashMap<String, HashSet<u64>>,
+    writes: HashMap<u64, (K, Vec<String>)>,
+}
+
+impl<K> Default for TagIndex<K> {
+    fn default() -> Self {
+        TagIndex {
+            next_id: AtomicU64::new(0),
+            inner: Mutex::new(Tags {
+                by_tag: HashMap::new(),
+                writes: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl<K> TagIndex<K> {
+    /// Records a write of `key` with `tags`, and returns its id.
+    pub(super) fn register<T: AsRef<str>>(&self, key: K, tags: &[T]) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let tags: Vec<String> = tags.iter().map(|t| t.as_ref().to_string()).collect();
+
+        let mut inner = self.inner.lock().unwrap();
+        for tag in &tags {
+            inner.by_tag.entry(tag.clone()).or_default().insert(id);
+        }
+        inner.writes.insert(id, (key, tags));
+        id
+    }
+
+    /// Forgets the write with the given id.
+    pub(super) fn forget(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((_, tags)) = inner.writes.remove(&id) {
+            for tag in tags {
+                let now_empty = inner.by_tag.get_mut(&tag).is_some_and(|ids| {
+                    ids.remove(&id);
+                    ids.is_empty()
+                });
+                if now_empty {
+                    inner.by_tag.remove(&tag);
+                }
+            }
+        }
+    }
+
+    /// Returns the tags of the write with the given id.
+    pub(super) fn tags_of(&self, id: u64) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .writes
+            .get(&id)
+            .map_or_else(Vec::new, |(_, tags)| tags.clone())
+    }
+
+    /// Returns the id and key of every write tagged with `tag`.
+    pub(super) fn tagged(&self, tag: &str) -> Vec<(u64, K)>
+    where
+        K: Clone,
+    {
+        let inner = self.inner.lock().unwrap();
+        inner.by_tag.get(tag).map_or_else(Vec::new, |ids| {
+            ids.iter()
+                .map(|id| (*id, inner.writes[id].0.clone()))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_index() {
+        let index = TagIndex::default();
+        let a = index.register("a", &["red", "big"]);
+        let b = index.register("b", &["red"]);
+        let c = index.register("c", &[] as &[&str]);
+
+        let mut red = index.tagged("red");
+        red.sort();
+        assert_eq!(red, vec![(a, "a"), (b, "b")]);
+        assert_eq!(index.tagged("big"), vec![(a, "a")]);
+        assert!(index.tagged("blue").is_empty());
+        assert_eq!(index.tags_of(a), vec!["red", "big"]);
+
+        index.forget(a);
+        index.forget(c);
+        assert_eq!(index.tagged("red"), vec![(b, "b")]);
+        assert!(index.tagged("big").is_empty());
+        index.forget(b);
+
+        let inner = index.inner.lock().unwrap();
+        assert!(inner.by_tag.is_empty());
+        assert!(inner.writes.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// How far apart the ticks of the wheel are.
+pub(super) const TICK: Duration = Duration::from_millis(100);
+
+/// log2 of the number of slots in each level of the wheel.
+const BITS: u32 = 6;
+const SLOTS: usize = 1 << BITS;
+const LEVELS: usize = 4;
+
+/// A hierarchical timer wheel that keeps track of when keys expire.
+///
+/// Each level of the wheel has 64 slots, and each slot of a level spans as many ticks as the whole
+/// level below it. A key is put into the lowest level whose range reaches its expiry, and moves
+/// down a level each time the wheel comes around to its slot, until it is due. Scheduling and
+/// expiring a key are therefore constant time, however many keys are waiting. Keys that expire
+/// further out than the top level reaches just wait in the top level's last slot.
+///
+/// The wheel only gives a rough time for a key's expiry: keys may be handed out up to a tick
+/// late, and keys whose expiry has changed since they were scheduled are handed out anyway. The
+/// cache checks the real expiry of each key it is given, and schedules the key again if it has
+/// moved later, rather than scheduling it on every write.
+pub(super) struct TimerWheel<K> {
+    start: Instant,
+    /// The last tick that has been processed.
+    now: u64,
+    levels: Vec<Vec<Vec<(K, u64)>>>,
+}
+
+impl<K> TimerWheel<K> {
+    /// Creates an empty wheel, whose first tick starts at `start`.
+    pub(super) fn new(start: Instant) -> Self {
+        TimerWheel {
+            start,
+            now: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+        }
+    }
+
+    /// Returns the first tick at or after `at`.
+    fn tick_of(&self, at: Instant) -> u64 {
+        let nanos = at.saturating_duration_since(self.start).as_nanos();
+        nanos.div_ceil(TICK.as_nanos()) as u64
+    }
+
+    /// Returns the last tick at or before `at`.
+    fn tick_before(&self, at: Instant) -> u64 {
+        let nanos = at.saturating_duration_since(self.start).as_nanos();
+        (nanos / TICK.as_nanos()) as u64
+    }
+
+    /// Arranges for `key` to be handed out by `advance` once `at` has passed.
+    pub(super) fn schedule(&mut self, key: K, at: Instant) {
+        let tick = self.tick_of(at);
+        self.insert(key, tick);
+    }
+
+    /// Returns the number of keys waiting in the wheel.
+    #[cfg(test)]
+    pub(super) fn len(&self) -> usize {
+        self.levels.iter().flatten().map(Vec::len).sum()
+    }
+
+    fn insert(&mut self, key: K, tick: u64) {
+        // anything that is already due goes in the next slot to be processed
+        let tick = tick.max(self.now + 1);
+        let delta = tick - self.now;
+
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >= 1 << (BITS * (level as u32 + 1)) {
+            level += 1;
+        }
+        let slot = if level == LEVELS - 1 && delta >= 1 << (BITS * LEVELS as u32) {
+            // too far out to place exactly; wait in the slot that comes around last
+            ((self.now >> (BITS * level as u32)) as usize + SLOTS - 1) % SLOTS
+        } else {
+            (tick >> (BITS * level as u32)) as usize % SLOTS
+        };
+        self.levels[level][slot].push((key, tick));
+    }
+
+    /// Moves the wheel forward to `to`, and returns the keys that are due by then.
+    pub(super) fn advance(&mut self, to: Instant) -> Vec<K> {
+        // a tick that is only partly over may still have keys that are not due
+        let target = self.tick_before(to);
+        let mut due = Vec::new();
+        while self.now < target {
+            self.now += 1;
+
+            // bring down the keys of any higher level slots the wheel has come around to
+            for level in (1..LEVELS).rev() {
+                let span = BITS * level as u32;
+                if self.now & ((1 << span) - 1) == 0 {
+                    let slot = (self.now >> span) as usize % SLOTS;
+                    for (key, tick) in mem::take(&mut self.levels[level][slot]) {
+                        if tick <= self.now {
+                            due.push(key);
+                        } else {
+                            self.insert(key, tick);
+                        }
+                    }
+                }
+            }
+
+            let slot = self.now as usize % SLOTS;
+            for (key, tick) in mem::take(&mut self.levels[0][slot]) {
+                if tick <= self.now {
+                    due.push(key);
+                } else {
+                    self.insert(key, tick);
+                }
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_wheel() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(start);
+        for &ticks in &[1, 5, 63, 64, 65, 100, 4095, 4096, 5000, 300000] {
+            wheel.schedule(ticks, start + TICK * ticks);
+        }
+        wheel.schedule(0, start);
+
+        let mut due = wheel.advance(start + TICK);
+        due.sort();
+        assert_eq!(due, vec![0, 1]);
+        assert!(wheel.advance(start + TICK * 4).is_empty());
+        // nothing is handed out before its time
+        assert!(wheel.advance(start + TICK * 5 - TICK / 2).is_empty());
+        assert_eq!(wheel.advance(start + TICK * 5), vec![5]);
+        assert_eq!(wheel.len(), 8);
+
+        let mut due = wheel.advance(start + TICK * 5000);
+        due.sort();
+        assert_eq!(due, vec![63, 64, 65, 100, 4095, 4096, 5000]);
+
+        assert!(wheel.advance(start + TICK * 299999).is_empty());
+        assert_eq!(wheel.advance(start + TICK * 300000), vec![300000]);
+    }
+}
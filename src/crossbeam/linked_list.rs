@@ -1,27 +1,228 @@
-use cx::epoch::{self, Atomic, Owned};
+use action::Action;
+use cx::epoch::{self, Atomic, Guard, Owned, Shared};
+use inline;
 use std::fmt;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-struct Node<K, V> {
-    kv: (K, Atomic<V>),
-    active: AtomicBool,
+/// A node's value.
+///
+/// Values of types that can be stored inline live in `inline`, which holds either the value itself
+/// or, if it does not fit, a pointer to a [`Retirable`] holding it; values of all other types are
+/// allocated and pointed to by `boxed`. Which of the two fields is used depends only on `V`.
+/// Either way, an empty slot means that the key has been removed.
+struct Value<V: 'static> {
+    boxed: Atomic<V>,
+    inline: AtomicUsize,
+}
+
+/// The value a `Value` held when it was loaded, which is needed to replace it.
+enum Current<'a, V: 'static> {
+    Boxed(Shared<'a, V>),
+    Inline(usize),
+}
+
+impl<'a, V> Clone for Current<'a, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, V> Copy for Current<'a, V> {}
+
+impl<'a, V: Copy> Current<'a, V> {
+    fn get(&self) -> V {
+        match *self {
+            Current::Boxed(v) => **v,
+            Current::Inline(w) if inline::is_inline::<V>(w) => unsafe { inline::decode(w) },
+            Current::Inline(w) => unsafe { (*(w as *const Retirable<V>)).val },
+        }
+    }
+}
+
+/// An allocation that is only known by a raw pointer, but that can still be retired.
+///
+/// Retiring memory takes a `Shared` pointer to it, which crossbeam only hands out for pointers
+/// loaded from an `Atomic`. So the allocation keeps a pointer to itself in `this`, which gives a
+/// new `Shared` every time it is loaded.
+pub(super) struct Retirable<T> {
+    pub(super) val: T,
+    this: Atomic<Retirable<T>>,
+}
+
+impl<T> Retirable<T> {
+    pub(super) fn new(val: T) -> *const Self {
+        let guard = epoch::pin();
+        let home = Atomic::null();
+        let r = Owned::new(Retirable {
+            val,
+            this: Atomic::null(),
+        });
+        let r = home.store_and_ref(r, Ordering::SeqCst, &guard);
+        r.this.store_shared(Some(r), Ordering::SeqCst);
+        r.as_raw()
+    }
+
+    /// Frees `r` once no thread can still be using it. Its value is not dropped.
+    pub(super) unsafe fn retire(r: *const Self, guard: &Guard) {
+        if let Some(r) = (*r).this.load(Ordering::SeqCst, guard) {
+            guard.unlinked(r);
+        }
+    }
+}
+
+/// Turns `val` into what `Value::inline` holds for it.
+fn into_word<V: 'static>(val: V) -> usize {
+    match inline::encode(&val) {
+        Some(w) => w,
+        None => Retirable::new(val) as usize,
+    }
+}
+
+/// Retires the value held by a word of `Value::inline` that has just been replaced.
+unsafe fn retire_word<V: 'static>(word: usize, guard: &Guard) {
+    if word == 0 || inline::is_inline::<V>(word) {
+        return;
+    }
+    Retirable::retire(word as *const Retirable<V>, guard);
+}
+
+impl<V> Value<V> {
+    fn new(val: V) -> Self {
+        if inline::inlinable::<V>() {
+            Value {
+                boxed: Atomic::null(),
+                inline: AtomicUsize::new(into_word(val)),
+            }
+        } else {
+            Value {
+                boxed: Atomic::new(val),
+                inline: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    fn empty() -> Self {
+        Value {
+            boxed: Atomic::null(),
+            inline: AtomicUsize::new(0),
+        }
+    }
+
+    fn load<'a>(&self, guard: &'a Guard) -> Option<Current<'a, V>> {
+        if inline::inlinable::<V>() {
+            match self.inline.load(Ordering::SeqCst) {
+                0 => None,
+                w => Some(Current::Inline(w)),
+            }
+        } else {
+            self.boxed.load(Ordering::SeqCst, guard).map(Current::Boxed)
+        }
+    }
+
+    /// Replaces the value with `new`, or empties the slot if `new` is `None`, if the value is
+    /// still `old`. The replaced value is retired.
+    fn cas<'a>(&self, old: Current<'a, V>, new: Option<V>, guard: &'a Guard) -> bool {
+        match old {
+            Current::Inline(w) => {
+                let new = new.map(into_word).unwrap_or(0);
+                if self
+                    .inline
+                    .compare_exchange(w, new, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    unsafe { retire_word::<V>(new, guard) };
+                    return false;
+                }
+                unsafe { retire_word::<V>(w, guard) };
+                true
+            }
+            Current::Boxed(old) => {
+                if self
+                    .boxed
+                    .cas(Some(old), new.map(Owned::new), Ordering::SeqCst)
+                    .is_err()
+                {
+                    return false;
+                }
+                unsafe { guard.unlinked(old) };
+                true
+            }
+        }
+    }
+
+    /// Like `cas`, but hands over the value held by `from`, which must not have been shared with
+    /// anyone, instead of allocating a new one.
+    fn cas_from<'a>(&self, old: Current<'a, V>, from: &Value<V>, guard: &'a Guard) -> bool {
+        match old {
+            Current::Inline(w) => {
+                let new = from.inline.load(Ordering::SeqCst);
+                if self
+                    .inline
+                    .compare_exchange(w, new, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    return false;
+                }
+                unsafe { retire_word::<V>(w, guard) };
+                true
+            }
+            Current::Boxed(old) => {
+                let new = from.boxed.load(Ordering::SeqCst, guard);
+                if !self.boxed.cas_shared(Some(old), new, Ordering::SeqCst) {
+                    return false;
+                }
+                unsafe { guard.unlinked(old) };
+                true
+            }
+        }
+    }
+
+    /// Retires the value of a node that was never linked into a list.
+    fn discard(&self, guard: &Guard) {
+        if let Some(v) = self.boxed.load(Ordering::SeqCst, guard) {
+            unsafe { guard.unlinked(v) };
+        }
+        unsafe { retire_word::<V>(self.inline.load(Ordering::SeqCst), guard) };
+    }
+}
+
+/// A node in a bucket's list.
+///
+/// A node's value is empty once the node has been removed from the map; that is the point at which
+/// the removal takes effect. Physically unlinking the node is done by first appending a marker
+/// node (a node without a key) to it, which stops anyone from linking a new node in after it, and
+/// then swinging its predecessor past both. This is the same scheme as Harris' mark bits, but
+/// without needing spare bits in `next`.
+struct Node<K, V: 'static> {
+    kv: (Option<K>, Value<V>),
     next: Atomic<Node<K, V>>,
-    prev: Atomic<Node<K, V>>,
 }
 
 impl<K, V> Node<K, V> {
     fn new(k: K, v: V) -> Self {
         Node {
-            kv: (k, Atomic::new(v)),
-            active: AtomicBool::new(true),
+            kv: (Some(k), Value::new(v)),
             next: Atomic::null(),
-            prev: Atomic::null(),
         }
     }
+
+    fn marker() -> Self {
+        Node {
+            kv: (None, Value::empty()),
+            next: Atomic::null(),
+        }
+    }
+
+    fn is_marker(&self) -> bool {
+        self.kv.0.is_none()
+    }
 }
 
-pub(super) struct LinkedList<K, V> {
+/// A link in a bucket's list, along with the node it points to.
+type Link<'a, K, V> = (&'a Atomic<Node<K, V>>, Option<Shared<'a, Node<K, V>>>);
+
+pub(super) struct LinkedList<K, V: 'static> {
     first: Atomic<Node<K, V>>,
 }
 
@@ -33,131 +234,291 @@ impl<K, V> Default for LinkedList<K, V> {
     }
 }
 
+impl<K, V> LinkedList<K, V> {
+    /// Makes sure a removed node has a marker after it, so it can be unlinked.
+    fn help_delete(node: &Node<K, V>, guard: &Guard) {
+        loop {
+            let next = node.next.load(Ordering::SeqCst, guard);
+            if next.map(|n| n.is_marker()).unwrap_or(false) {
+                return;
+            }
+
+            let marker = Owned::new(Node::marker());
+            marker.next.store_shared(next, Ordering::SeqCst);
+            if node.next.cas(next, Some(marker), Ordering::SeqCst).is_ok() {
+                return;
+            }
+        }
+    }
+
+    /// Retires a list holding a single node that was never shared with anyone, and gives back the
+    /// node's key.
+    pub(super) unsafe fn into_only_key(list: *const Retirable<Self>, guard: &Guard) -> K {
+        let first = (*list).val.first.load(Ordering::SeqCst, guard).unwrap();
+        // retiring the node does not drop it, so the key is moved out here
+        let key = ptr::read(&first.kv.0).unwrap();
+        first.kv.1.discard(guard);
+        guard.unlinked(first);
+        Retirable::retire(list, guard);
+        key
+    }
+
+    /// Retires a [closed](LinkedList::close) list that can no longer be reached.
+    pub(super) unsafe fn retire(list: *const Retirable<Self>, guard: &Guard) {
+        if let Some(marker) = (*list).val.first.load(Ordering::SeqCst, guard) {
+            guard.unlinked(marker);
+        }
+        Retirable::retire(list, guard);
+    }
+}
+
 impl<K, V> LinkedList<K, V>
 where
     K: Eq,
     V: Copy,
 {
-    pub(super) fn insert(&self, kv: (K, V)) -> Option<*mut V> {
+    /// Finds the node holding `key`, along with the link that points to it.
+    ///
+    /// If there is no such node, the returned link is the (null) `next` of the last node, where a
+    /// node for `key` can be appended. Removed nodes that are passed along the way are unlinked.
+    fn find<'a>(&'a self, key: &K, guard: &'a Guard) -> Link<'a, K, V> {
+        'retry: loop {
+            let mut pred = &self.first;
+            let mut cur = pred.load(Ordering::SeqCst, guard);
+
+            while let Some(c) = cur {
+                if c.is_marker() {
+                    // only a closed list starts with a marker, and nothing follows it
+                    return (pred, None);
+                }
+                let next = c.next.load(Ordering::SeqCst, guard);
+
+                if let Some(m) = next {
+                    if m.is_marker() {
+                        // c has been removed, so take it and its marker out of the list
+                        let after = m.next.load(Ordering::SeqCst, guard);
+                        if !pred.cas_shared(Some(c), after, Ordering::SeqCst) {
+                            continue 'retry;
+                        }
+                        unsafe {
+                            guard.unlinked(c);
+                            guard.unlinked(m);
+                        }
+                        cur = after;
+                        continue;
+                    }
+                }
+
+                if c.kv.1.load(guard).is_none() {
+                    // c has been removed, but is not yet marked
+                    Self::help_delete(*c, guard);
+                    continue;
+                }
+
+                if c.kv.0.as_ref() == Some(key) {
+                    return (pred, Some(c));
+                }
+
+                pred = &c.next;
+                cur = next;
+            }
+
+            return (pred, None);
+        }
+    }
+
+    /// Removes `node`, whose current value is `old`, if its value is still `old`.
+    fn remove_node<'a>(
+        &'a self,
+        node: Shared<'a, Node<K, V>>,
+        old: Current<'a, V>,
+        guard: &'a Guard,
+    ) -> bool {
+        if !node.kv.1.cas(old, None, guard) {
+            return false;
+        }
+
+        Self::help_delete(*node, guard);
+        // walking past the node unlinks it
+        let _ = self.find(node.kv.0.as_ref().unwrap(), guard);
+        true
+    }
+
+    /// Inserts `kv`, replacing the current value for its key if there is one and `overwrite` is
+    /// set. Returns the previous value.
+    pub(super) fn insert(&self, kv: (K, V), overwrite: bool) -> Option<V> {
         let guard = epoch::pin();
 
-        let mut node = &self.first;
+        let mut ins = Owned::new(Node::new(kv.0, kv.1));
         loop {
-            let l = node.load(Ordering::SeqCst, &guard);
-            match l {
-                Some(k) => {
-                    let mut raw = k.as_raw();
-                    let mut cur = unsafe { &*raw };
-                    if &cur.kv.0 == &kv.0 && cur.active.load(Ordering::SeqCst) {
-                        // if let Some(old) = cur.kv.1.load(Ordering::SeqCst, &guard) {
-                        //     unsafe { guard.unlinked(old); }
-                        // }
-                        let mut ins = Owned::new(kv.1);
-                        let old = cur.kv.1.load(Ordering::SeqCst, &guard);
-                        cur.kv.1.cas_and_ref(old, ins, Ordering::SeqCst, &guard);
-                        return Some(old.unwrap().as_raw());
+            let (pred, cur) = self.find(ins.kv.0.as_ref().unwrap(), &guard);
+            match cur {
+                Some(c) => {
+                    let old = match c.kv.1.load(&guard) {
+                        Some(old) => old,
+                        None => continue,
+                    };
+                    if !overwrite {
+                        ins.kv.1.discard(&guard);
+                        return Some(old.get());
                     }
-                    node = &k.next;
-
-                    // key does not exist
-                    if cur.next.load(Ordering::SeqCst, &guard).is_none() {
-                        let mut ins = Owned::new(Node::new(kv.0, kv.1));
-                        ins.prev.store_shared(l, Ordering::SeqCst);
-                        cur.next.store_and_ref(ins, Ordering::SeqCst, &guard);
-                        return None;
+                    // move our freshly allocated value over to the existing node
+                    if c.kv.1.cas_from(old, &ins.kv.1, &guard) {
+                        return Some(old.get());
                     }
                 }
-                None => {
-                    // first is null
-                    let mut ins = Owned::new(Node::new(kv.0, kv.1));
-                    self.first.store_and_ref(ins, Ordering::SeqCst, &guard);
-                    return None;
-                }
+                None => match pred.cas(None, Some(ins), Ordering::SeqCst) {
+                    Ok(()) => return None,
+                    Err(n) => ins = n.unwrap(),
+                },
             };
         }
     }
 
-    pub(super) fn get(&self, key: &K) -> Option<V> {
+    /// Inserts `kv` if its key is not in the list and the list has not been
+    /// [closed](LinkedList::close). Otherwise the key is given back.
+    pub(super) fn try_insert(&self, kv: (K, V)) -> Result<(), K> {
+        let guard = epoch::pin();
+
+        let mut ins = Owned::new(Node::new(kv.0, kv.1));
+        loop {
+            let (pred, cur) = self.find(ins.kv.0.as_ref().unwrap(), &guard);
+            if cur.is_some() || self.is_closed(&guard) {
+                ins.kv.1.discard(&guard);
+                return Err(ins.into_inner().kv.0.unwrap());
+            }
+            match pred.cas(None, Some(ins), Ordering::SeqCst) {
+                Ok(()) => return Ok(()),
+                Err(n) => ins = n.unwrap(),
+            }
+        }
+    }
+
+    /// Closes the list if it holds no keys, returning `true` if this call closed it.
+    ///
+    /// A closed list stays empty: it is closed by making a marker its first node, which no node
+    /// can be linked in after, and [`try_insert`](LinkedList::try_insert) refuses to insert into
+    /// it. Nodes that were removed but are still linked are unlinked first, so that an insert
+    /// that links its node in after one of them cannot be lost.
+    pub(super) fn close(&self) -> bool {
         let guard = epoch::pin();
 
-        let mut node = &self.first;
         loop {
-            match node.load(Ordering::SeqCst, &guard) {
-                Some(k) => {
-                    let mut raw = k.as_raw();
-                    let mut cur = unsafe { &*raw };
-                    if &cur.kv.0 == key && cur.active.load(Ordering::SeqCst) {
-                        let value = cur.kv.1.load(Ordering::SeqCst, &guard).unwrap();
-                        return Some(**value);
+            match self.first.load(Ordering::SeqCst, &guard) {
+                None => {
+                    let marker = Owned::new(Node::marker());
+                    if self.first.cas(None, Some(marker), Ordering::SeqCst).is_ok() {
+                        return true;
                     }
-                    node = &k.next;
                 }
-                None => {
-                    return None;
+                Some(f) if f.is_marker() => return false,
+                Some(f) => {
+                    if f.kv.1.load(&guard).is_some() {
+                        return false;
+                    }
+                    // f has been removed, and walking past it unlinks it
+                    Self::help_delete(*f, &guard);
+                    let _ = self.find(f.kv.0.as_ref().unwrap(), &guard);
                 }
+            }
+        }
+    }
+
+    /// Returns true if the list has been [closed](LinkedList::close).
+    pub(super) fn is_closed(&self, guard: &Guard) -> bool {
+        self.first
+            .load(Ordering::SeqCst, guard)
+            .map(|f| f.is_marker())
+            .unwrap_or(false)
+    }
+
+    /// Calls `f` with every key in the list and its value. Keys that are inserted or removed
+    /// during the walk may or may not be seen.
+    pub(super) fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, V),
+    {
+        let guard = epoch::pin();
+
+        let mut cur = self.first.load(Ordering::SeqCst, &guard);
+        while let Some(c) = cur {
+            // markers have no key, and removed nodes no value
+            if let (Some(k), Some(v)) = (c.kv.0.as_ref(), c.kv.1.load(&guard)) {
+                f(k, v.get());
+            }
+            cur = c.next.load(Ordering::SeqCst, &guard);
+        }
+    }
+
+    pub(super) fn get(&self, key: &K) -> Option<V> {
+        let guard = epoch::pin();
+
+        let (_, cur) = self.find(key, &guard);
+        cur.and_then(|c| c.kv.1.load(&guard)).map(|v| v.get())
+    }
+
+    pub(super) fn remove(&self, key: &K) -> Option<V> {
+        let guard = epoch::pin();
+
+        loop {
+            let (_, cur) = self.find(key, &guard);
+            let c = cur?;
+            let old = match c.kv.1.load(&guard) {
+                Some(old) => old,
+                None => continue,
             };
+            if self.remove_node(c, old, &guard) {
+                return Some(old.get());
+            }
         }
     }
 
-    pub(super) fn remove(&self, key: &K) -> bool {
+    /// Atomically applies the [`Action`] that `f` picks given the current value for `key`, or
+    /// `None` if the key is not in the map.
+    ///
+    /// `new_key` is called for the key of the new node if the key has to be inserted. `f` may be
+    /// called more than once if other threads change the key at the same time. Returns the
+    /// previous and new value.
+    pub(super) fn compute<F, G>(&self, key: &K, mut new_key: G, mut f: F) -> (Option<V>, Option<V>)
+    where
+        F: FnMut(Option<&V>) -> Action<V>,
+        G: FnMut() -> K,
+    {
         let guard = epoch::pin();
 
-        let mut node = &self.first;
         loop {
-            match node.load(Ordering::SeqCst, &guard) {
-                Some(k) => {
-                    let mut raw = k.as_raw();
-                    let mut cur = unsafe { &*raw };
-                    if &cur.kv.0 == key && cur.active.load(Ordering::SeqCst) {
-                        cur.active.store(false, Ordering::SeqCst);
-
-                        let next = k.next.load(Ordering::SeqCst, &guard);
-                        let prev = k.prev.load(Ordering::SeqCst, &guard);
-
-                        if next.is_some() {
-                            if prev.is_some() {
-                                let n = next.unwrap();
-                                let p = prev.unwrap();
-
-                                if !p.next.cas_shared(Some(k), next, Ordering::SeqCst) {
-                                    return false;
-                                }
-                                if !n.prev.cas_shared(Some(k), next, Ordering::SeqCst) {
-                                    return false;
-                                }
-                            } else {
-                                let n = next.unwrap();
-                                if !n.prev.cas_shared(Some(k), None, Ordering::SeqCst) {
-                                    return false;
-                                }
-                                if !self.first.cas_shared(Some(k), next, Ordering::SeqCst) {
-                                    return false;
-                                }
+            let (pred, cur) = self.find(key, &guard);
+            match cur {
+                Some(c) => {
+                    let old = match c.kv.1.load(&guard) {
+                        Some(old) => old,
+                        None => continue,
+                    };
+                    match f(Some(&old.get())) {
+                        Action::Keep => return (Some(old.get()), Some(old.get())),
+                        Action::Set(v) => {
+                            if c.kv.1.cas(old, Some(v), &guard) {
+                                return (Some(old.get()), Some(v));
                             }
-                        } else {
-                            if prev.is_some() {
-                                let p = prev.unwrap();
-
-                                if !p.next.cas_shared(Some(k), None, Ordering::SeqCst) {
-                                    return false;
-                                }
-                            } else {
-                                if !self.first.cas_shared(Some(k), next, Ordering::SeqCst) {
-                                    return false;
-                                }
+                        }
+                        Action::Remove => {
+                            if self.remove_node(c, old, &guard) {
+                                return (Some(old.get()), None);
                             }
                         }
-
-                        unsafe { guard.unlinked(k) };
-                        return true;
                     }
-                    node = &k.next;
-                },
-                None => {
-                    // the node with key key didn't exist
-                    return false;
                 }
-            };
+                None => match f(None) {
+                    Action::Set(v) => {
+                        let ins = Owned::new(Node::new(new_key(), v));
+                        match pred.cas(None, Some(ins), Ordering::SeqCst) {
+                            Ok(()) => return (None, Some(v)),
+                            Err(ins) => ins.unwrap().kv.1.discard(&guard),
+                        }
+                    }
+                    Action::Keep | Action::Remove => return (None, None),
+                },
+            }
         }
     }
 }
@@ -165,7 +526,7 @@ where
 impl<K, V> fmt::Debug for LinkedList<K, V>
 where
     K: fmt::Debug,
-    V: fmt::Debug,
+    V: fmt::Debug + Copy,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // TODO: use https://doc.rust-lang.org/std/fmt/struct.DebugList.html
@@ -174,16 +535,11 @@ where
         let mut ret = String::new();
         let mut node = &self.first;
         while let Some(k) = node.load(Ordering::SeqCst, &guard) {
-            let mut raw = k.as_raw();
-            let mut cur = unsafe { &*raw };
-            if cur.active.load(Ordering::SeqCst) {
-                let key = &cur.kv.0;
-                let value = cur.kv.1.load(Ordering::SeqCst, &guard).unwrap();
-
-                ret.push_str("(");
+            if let (Some(key), Some(value)) = (&k.kv.0, k.kv.1.load(&guard)) {
+                ret.push('(');
                 ret.push_str(&format!("{:?}", key));
                 ret.push_str(", ");
-                ret.push_str(&format!("{:?}", value));
+                ret.push_str(&format!("{:?}", value.get()));
                 ret.push_str("), ");
             }
             node = &k.next;
@@ -192,3 +548,36 @@ where
         write!(f, "{}", ret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn value_inline_integers() {
+        let guard = epoch::pin();
+        let value = Value::new(1u64);
+        for i in 1..100 {
+            let old = value.load(&guard).unwrap();
+            assert_eq!(old.get(), i);
+            assert!(value.cas(old, Some(i + 1), &guard));
+        }
+        // nothing was ever allocated, so nothing was retired
+        assert!(value.boxed.load(Ordering::SeqCst, &guard).is_none());
+        assert!(inline::is_inline::<u64>(
+            value.inline.load(Ordering::SeqCst)
+        ));
+
+        // a value that does not fit in the word is boxed, and then replaced inline again
+        let old = value.load(&guard).unwrap();
+        assert!(value.cas(old, Some(u64::MAX), &guard));
+        let old = value.load(&guard).unwrap();
+        assert_eq!(old.get(), u64::MAX);
+        assert!(value.cas(old, Some(0), &guard));
+        assert_eq!(value.load(&guard).unwrap().get(), 0);
+        let old = value.load(&guard).unwrap();
+        assert!(value.cas(old, None, &guard));
+        assert!(value.load(&guard).is_none());
+    }
+}
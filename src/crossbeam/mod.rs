@@ -23,27 +23,46 @@
 //! provide temporary access through closures, similar to `evmap`'s
 //! [`ReadHandle::get_and`](https://docs.rs/evmap/4/evmap/struct.ReadHandle.html#method.get_and),
 //! but for the time being, values have to be `Copy`.
+//! Values also have to be `'static`, which is what lets the map tell the integers that it stores
+//! inline apart from other values.
+//!
+//! Changes to a key can also be made atomically with [`MapHandle::update`], or only under a
+//! condition with [`MapHandle::insert_if_absent`], [`MapHandle::replace`] and
+//! [`MapHandle::remove_if`]. Maps of integers can be used as counters through
+//! [`MapHandle::fetch_add`] and friends. Integers that fit in a
+//! word are stored directly in the map's nodes, so updating them does not allocate.
+//!
+//! Missing values can be filled in with [`MapHandle::get_or_insert_with`], which makes sure that
+//! only one thread computes the value for a key that many threads miss on at once.
 
 mod linked_list;
+mod multimap;
+mod set;
 
 use self::linked_list::LinkedList;
+pub use self::multimap::MultiMap;
+pub use self::set::{Set, SetIter};
+use action::Action;
+use flight::{self, Flights};
 use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
+use Integer;
 
 /// A handle to a shared [`Map`].
 ///
 /// Any operation performed on this handle affects the map seen by all other related `MapHandle`
 /// instances. To get another handle to the `Map`, simply clone any of its handles.
-#[derive(Clone)]
-pub struct MapHandle<K, V> {
+pub struct MapHandle<K, V: 'static> {
     bsize: usize,
     size: Arc<AtomicUsize>,
     mp: Arc<Vec<LinkedList<K, V>>>,
+    flights: Arc<Flights<K, V>>,
 }
 
 /// A shared, concurrent hash map.
@@ -68,6 +87,7 @@ impl<K, V> MapHandle<K, V> {
             bsize: nbuckets,
             size: Arc::new(AtomicUsize::new(0)),
             mp: Arc::new(v),
+            flights: Arc::default(),
         }
     }
 
@@ -136,15 +156,72 @@ where
         let h = hsh.finish() as usize;
 
         let ndx = h % self.bsize;
-        let ret = self.mp[ndx].insert((key, value));
+        let ret = self.mp[ndx].insert((key, value), true);
 
-        return match ret {
-            Some(v) => Some(unsafe{ *v }),
-            None => {
-                self.size.fetch_add(1, Ordering::SeqCst);
-                None
-            }
-        };
+        if ret.is_none() {
+            self.size.fetch_add(1, Ordering::SeqCst);
+        }
+        ret
+    }
+
+    /// Inserts a key-value pair into the map, unless the key is already present.
+    ///
+    /// If the map did not have this key present, the pair is inserted and `None` is returned.
+    ///
+    /// If the map did have this key present, the map is left unchanged, and the current value is
+    /// returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// assert_eq!(map.insert_if_absent(37, "a"), None);
+    /// assert_eq!(map.insert_if_absent(37, "b"), Some("a"));
+    /// assert_eq!(map.get(&37), Some("a"));
+    /// ```
+    pub fn insert_if_absent(&self, key: K, value: V) -> Option<V> {
+        let mut hsh = DefaultHasher::new();
+        key.hash(&mut hsh);
+        let h = hsh.finish() as usize;
+
+        let ndx = h % self.bsize;
+        let ret = self.mp[ndx].insert((key, value), false);
+
+        if ret.is_none() {
+            self.size.fetch_add(1, Ordering::SeqCst);
+        }
+        ret
+    }
+
+    /// Replaces the value for a key that is in the map, returning the previous value.
+    ///
+    /// If the map did not have this key present, nothing is inserted and `None` is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// assert_eq!(map.replace(&37, "a"), None);
+    /// assert_eq!(map.get(&37), None);
+    ///
+    /// map.insert(37, "a");
+    /// assert_eq!(map.replace(&37, "b"), Some("a"));
+    /// assert_eq!(map.get(&37), Some("b"));
+    /// ```
+    pub fn replace(&self, key: &K, value: V) -> Option<V> {
+        self.compute(
+            key,
+            || unreachable!("replace never inserts"),
+            |v| match v {
+                Some(_) => Action::Set(value),
+                None => Action::Keep,
+            },
+        )
+        .0
     }
 
     /// Returns a reference to the value corresponding to the key.
@@ -173,7 +250,7 @@ where
     ///
     /// # Examples
     ///
-    /// ```no_run
+    /// ```
     /// use concache::crossbeam::Map;
     ///
     /// let mut map = Map::with_capacity(16);
@@ -188,18 +265,340 @@ where
 
         let ndx = h % self.bsize;
 
-        if self.mp[ndx].remove(key) {
+        if self.mp[ndx].remove(key).is_some() {
             self.size.fetch_sub(1, Ordering::SeqCst);
             return true;
         }
         false
     }
+
+    /// Removes a key from the map if `f` returns true for its current value, returning the
+    /// removed value.
+    ///
+    /// The check and the removal happen atomically: the key is only removed if its value has not
+    /// changed since `f` was called on it. If it has, `f` is called again with the new value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// map.insert(1, 10);
+    /// assert_eq!(map.remove_if(&1, |&v| v > 10), None);
+    /// assert_eq!(map.remove_if(&1, |&v| v == 10), Some(10));
+    /// assert_eq!(map.get(&1), None);
+    /// ```
+    pub fn remove_if<F>(&self, key: &K, mut f: F) -> Option<V>
+    where
+        F: FnMut(&V) -> bool,
+    {
+        let (old, new) = self.compute(
+            key,
+            || unreachable!("remove_if never inserts"),
+            |v| match v {
+                Some(v) if f(v) => Action::Remove,
+                _ => Action::Keep,
+            },
+        );
+        if new.is_none() {
+            old
+        } else {
+            None
+        }
+    }
+
+    /// Removes a key from the map if its value is equal to `expected`, returning true if it was
+    /// removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// map.insert("lease", 7);
+    /// assert!(!map.remove_if_eq(&"lease", &8));
+    /// assert!(map.remove_if_eq(&"lease", &7));
+    /// assert_eq!(map.get(&"lease"), None);
+    /// ```
+    pub fn remove_if_eq(&self, key: &K, expected: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        self.remove_if(key, |v| v == expected).is_some()
+    }
+
+    fn compute<F, G>(&self, key: &K, new_key: G, f: F) -> (Option<V>, Option<V>)
+    where
+        F: FnMut(Option<&V>) -> Action<V>,
+        G: FnMut() -> K,
+    {
+        let mut hsh = DefaultHasher::new();
+        key.hash(&mut hsh);
+        let h = hsh.finish() as usize;
+
+        let ndx = h % self.bsize;
+        let ret = self.mp[ndx].compute(key, new_key, f);
+
+        match ret {
+            (None, Some(_)) => {
+                self.size.fetch_add(1, Ordering::SeqCst);
+            }
+            (Some(_), None) => {
+                self.size.fetch_sub(1, Ordering::SeqCst);
+            }
+            _ => {}
+        }
+        ret
+    }
+}
+
+impl<K, V> Map<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Copy,
+{
+    /// Atomically updates the value for `key` with the result of `f`, and returns the new value.
+    ///
+    /// `f` is given the current value, or `None` if the key is not in the map. If it returns
+    /// `Some`, that value is stored for the key, inserting it if necessary. If it returns `None`,
+    /// the key is removed. No other write to the key can happen between `f` observing the current
+    /// value and its result being stored; if one races with it, `f` is called again with the new
+    /// current value, so it may be called more than once.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// assert_eq!(map.update(&"hits", |v| Some(v.map(|v| v + 1).unwrap_or(1))), Some(1));
+    /// assert_eq!(map.update(&"hits", |v| Some(v.map(|v| v + 1).unwrap_or(1))), Some(2));
+    /// assert_eq!(map.update(&"hits", |_| None), None);
+    /// assert_eq!(map.get(&"hits"), None);
+    /// ```
+    pub fn update<F>(&self, key: &K, mut f: F) -> Option<V>
+    where
+        F: FnMut(Option<&V>) -> Option<V>,
+    {
+        self.compute(key, || key.clone(), |v| f(v).into()).1
+    }
+
+    /// Atomically updates the value for `key` with the result of `f`, and returns the previous
+    /// value.
+    ///
+    /// See [`MapHandle::update`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// map.insert(1, 10);
+    /// assert_eq!(map.fetch_update(&1, |v| v.map(|v| v * 2)), Some(10));
+    /// assert_eq!(map.get(&1), Some(20));
+    /// ```
+    pub fn fetch_update<F>(&self, key: &K, mut f: F) -> Option<V>
+    where
+        F: FnMut(Option<&V>) -> Option<V>,
+    {
+        self.compute(key, || key.clone(), |v| f(v).into()).0
+    }
+
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not in
+    /// the map.
+    ///
+    /// If several threads miss on the same key at once, only one of them calls its `f`, and the
+    /// others wait for it and return the value it inserted. If that `f` panics, the threads
+    /// waiting for it panic as well, and the key is left out of the map, so the next call tries
+    /// again. `f` must not look up `key` in this map with `get_or_insert_with` itself, since it
+    /// would end up waiting for itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// assert_eq!(map.get_or_insert_with(1, || 10), 10);
+    /// assert_eq!(map.get_or_insert_with(1, || unreachable!()), 10);
+    /// ```
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        match self.try_get_or_insert_with(key, || Ok::<V, Infallible>(f())) {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not in
+    /// the map and `f` succeeds.
+    ///
+    /// This is [`MapHandle::get_or_insert_with`] for fallible `f`. If `f` fails, nothing is
+    /// inserted, and its error is returned both by this call and by the calls that were waiting
+    /// for it. A waiting call that expects a different type of error instead goes on to call its
+    /// own `f`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// assert_eq!(map.try_get_or_insert_with(1, || "1x".parse::<u32>()).is_err(), true);
+    /// assert_eq!(map.get(&1), None);
+    /// assert_eq!(map.try_get_or_insert_with(1, || "10".parse::<u32>()), Ok(10));
+    /// ```
+    pub fn try_get_or_insert_with<F, E>(&self, key: K, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+        E: Clone + Send + Sync + 'static,
+    {
+        flight::get_or_load(
+            &self.flights,
+            self,
+            key,
+            |m, k| m.get(k),
+            |m, k, v| m.insert_if_absent(k, v),
+            f,
+        )
+    }
+
+    /// Removes every key from the map.
+    ///
+    /// The map is walked one bucket at a time, while other handles keep using it, so keys that
+    /// are inserted during the walk may be left in the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// map.clear();
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn clear(&self) {
+        for bucket in self.mp.iter() {
+            let mut keys = Vec::new();
+            bucket.for_each(|k, _| keys.push(k.clone()));
+            for k in keys {
+                if bucket.remove(&k).is_some() {
+                    self.size.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> Map<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Integer,
+{
+    /// Atomically adds `val` to the value for `key`, and returns the previous value.
+    ///
+    /// A key that is not in the map is treated as if it held zero, and is inserted. Like
+    /// `AtomicUsize::fetch_add`, the addition wraps around on overflow. Integers that fit in a
+    /// word are updated in place, without allocating; see [`Integer`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// assert_eq!(map.fetch_add(&"hits", 1u32), None);
+    /// assert_eq!(map.fetch_add(&"hits", 2), Some(1));
+    /// assert_eq!(map.get(&"hits"), Some(3));
+    /// ```
+    pub fn fetch_add(&self, key: &K, val: V) -> Option<V> {
+        self.fetch_update(key, |v| {
+            Some(v.cloned().unwrap_or(V::ZERO).wrapping_add(val))
+        })
+    }
+
+    /// Atomically subtracts `val` from the value for `key`, and returns the previous value.
+    ///
+    /// See [`MapHandle::fetch_add`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// map.insert("stock", 10i32);
+    /// assert_eq!(map.fetch_sub(&"stock", 3), Some(10));
+    /// assert_eq!(map.fetch_sub(&"missing", 3), None);
+    /// assert_eq!(map.get(&"missing"), Some(-3));
+    /// ```
+    pub fn fetch_sub(&self, key: &K, val: V) -> Option<V> {
+        self.fetch_update(key, |v| {
+            Some(v.cloned().unwrap_or(V::ZERO).wrapping_sub(val))
+        })
+    }
+
+    /// Atomically sets the value for `key` to the maximum of its current value and `val`, and
+    /// returns the previous value.
+    ///
+    /// A key that is not in the map is inserted with `val`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// assert_eq!(map.fetch_max(&"peak", 5u8), None);
+    /// assert_eq!(map.fetch_max(&"peak", 3), Some(5));
+    /// assert_eq!(map.get(&"peak"), Some(5));
+    /// ```
+    pub fn fetch_max(&self, key: &K, val: V) -> Option<V> {
+        self.fetch_update(key, |v| Some(v.map(|&v| v.max(val)).unwrap_or(val)))
+    }
+
+    /// Atomically sets the value for `key` to the minimum of its current value and `val`, and
+    /// returns the previous value.
+    ///
+    /// A key that is not in the map is inserted with `val`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    ///
+    /// let map = Map::with_capacity(16);
+    /// assert_eq!(map.fetch_min(&"low", 5u8), None);
+    /// assert_eq!(map.fetch_min(&"low", 3), Some(5));
+    /// assert_eq!(map.get(&"low"), Some(3));
+    /// ```
+    pub fn fetch_min(&self, key: &K, val: V) -> Option<V> {
+        self.fetch_update(key, |v| Some(v.map(|&v| v.min(val)).unwrap_or(val)))
+    }
+}
+
+impl<K, V> Clone for MapHandle<K, V> {
+    fn clone(&self) -> Self {
+        MapHandle {
+            bsize: self.bsize,
+            size: Arc::clone(&self.size),
+            mp: Arc::clone(&self.mp),
+            flights: Arc::clone(&self.flights),
+        }
+    }
 }
 
 impl<K, V> fmt::Debug for Map<K, V>
 where
     K: fmt::Debug,
-    V: fmt::Debug,
+    V: fmt::Debug + Copy,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // TODO: use https://doc.rust-lang.org/std/fmt/struct.DebugMap.html
@@ -208,7 +607,7 @@ where
             // TODO: I'm _sure_ there's a better way to do this
             all.push_str(&format!("{:?}", &self.mp[i]));
         }
-        let ret: String = all.chars().skip(0).take(all.len() - 2).collect();
+        let ret: String = all.chars().take(all.len() - 2).collect();
         write!(f, "[{}]", ret)
     }
 }
@@ -382,9 +781,12 @@ mod benchmarks {
 }
 
 #[cfg(test)]
+#[allow(unused_mut, clippy::bool_assert_comparison, clippy::unnecessary_unwrap)]
 mod tests {
     use super::*;
     use rand::{thread_rng, Rng};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Barrier;
     use std::thread;
 
     /*
@@ -456,7 +858,7 @@ mod tests {
     #[test]
     fn hashmap_basics() {
         let new_hashmap = Map::with_capacity(8); //init with 2 buckets
-                                                     //input values
+                                                 //input values
         new_hashmap.insert(1, 1);
         new_hashmap.insert(2, 5);
         new_hashmap.insert(12, 5);
@@ -485,4 +887,173 @@ mod tests {
         assert_eq!(new_hashmap.get(&0).unwrap(), 0);
         assert!(new_hashmap.get(&3).unwrap() != 2); // test that it changed
     }
+
+    #[test]
+    fn hashmap_update() {
+        let handle = Map::with_capacity(8);
+        let mut threads = vec![];
+        let nthreads = 5;
+        let num_iterations = 10000;
+        for _ in 0..nthreads {
+            let new_handle = handle.clone();
+            threads.push(thread::spawn(move || {
+                for i in 0..num_iterations {
+                    new_handle.update(&(i % 4), |v| Some(v.map(|v| v + 1).unwrap_or(1)));
+                    // keep a key that comes and goes, to race removals against updates
+                    new_handle.update(&4, |v| match v {
+                        Some(_) => None,
+                        None => Some(0),
+                    });
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        for k in 0..4 {
+            assert_eq!(handle.get(&k), Some(nthreads * num_iterations / 4));
+        }
+        assert_eq!(handle.len(), 4 + handle.get(&4).map(|_| 1).unwrap_or(0));
+
+        assert_eq!(handle.fetch_update(&0, |v| v.map(|v| v * 2)), Some(12500));
+        assert_eq!(handle.get(&0), Some(25000));
+        assert_eq!(handle.fetch_update(&0, |_| None), Some(25000));
+        assert_eq!(handle.get(&0), None);
+        assert_eq!(handle.update(&0, |_| None), None);
+        assert_eq!(handle.fetch_update(&5, |_| Some(1)), None);
+        assert_eq!(handle.get(&5), Some(1));
+    }
+
+    #[test]
+    fn hashmap_counters() {
+        let small = Map::with_capacity(8);
+        let large = Map::with_capacity(8);
+        let mut threads = vec![];
+        let nthreads = 5;
+        let num_iterations = 10000;
+        for t in 0..nthreads {
+            let small = small.clone();
+            let large = large.clone();
+            threads.push(thread::spawn(move || {
+                for i in 0..num_iterations {
+                    small.fetch_add(&(i % 4), 1u16);
+                    large.fetch_add(&(i % 4), 1u64);
+                    large.fetch_max(&4, (t * num_iterations + i) as u64);
+                    large.fetch_min(&5, (t * num_iterations + i) as u64);
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        for k in 0..4 {
+            assert_eq!(small.get(&k), Some((nthreads * num_iterations / 4) as u16));
+            assert_eq!(large.get(&k), Some((nthreads * num_iterations / 4) as u64));
+        }
+        assert_eq!(large.get(&4), Some((nthreads * num_iterations - 1) as u64));
+        assert_eq!(large.get(&5), Some(0));
+
+        assert_eq!(small.fetch_sub(&0, 12500), Some(12500));
+        assert_eq!(small.get(&0), Some(0));
+        assert_eq!(small.fetch_sub(&0, 1), Some(0));
+        assert_eq!(small.get(&0), Some(u16::MAX));
+        assert_eq!(small.fetch_add(&0, 1), Some(u16::MAX));
+        assert_eq!(small.get(&0), Some(0));
+        assert!(small.remove(&0));
+        assert_eq!(small.get(&0), None);
+    }
+
+    #[test]
+    fn hashmap_conditional() {
+        let handle = Map::with_capacity(8);
+        let mut threads = vec![];
+        let nthreads = 5;
+        let nkeys = 1000;
+        let barrier = Arc::new(Barrier::new(nthreads));
+        for t in 0..nthreads {
+            let barrier = Arc::clone(&barrier);
+            let new_handle = handle.clone();
+            threads.push(thread::spawn(move || {
+                // exactly one thread gets to insert each key, and then to remove it
+                let mut inserted = 0;
+                let mut removed = 0;
+                for k in 0..nkeys {
+                    if new_handle.insert_if_absent(k, t).is_none() {
+                        inserted += 1;
+                    }
+                }
+                barrier.wait();
+                for k in 0..nkeys {
+                    if let Some(v) = new_handle.get(&k) {
+                        if new_handle.remove_if_eq(&k, &v) {
+                            removed += 1;
+                        }
+                    }
+                }
+                (inserted, removed)
+            }));
+        }
+        let (mut inserted, mut removed) = (0, 0);
+        for t in threads {
+            let (i, r) = t.join().unwrap();
+            inserted += i;
+            removed += r;
+        }
+        assert_eq!(inserted, nkeys);
+        assert_eq!(removed, nkeys);
+
+        assert_eq!(handle.len(), 0);
+        assert_eq!(handle.replace(&1, 1), None);
+        assert_eq!(handle.get(&1), None);
+        assert_eq!(handle.insert_if_absent(1, 1), None);
+        assert_eq!(handle.insert_if_absent(1, 2), Some(1));
+        assert_eq!(handle.replace(&1, 3), Some(1));
+        assert_eq!(handle.remove_if(&1, |&v| v == 1), None);
+        assert_eq!(handle.remove_if(&1, |&v| v == 3), Some(3));
+        assert_eq!(handle.len(), 0);
+    }
+
+    #[test]
+    fn hashmap_get_or_insert() {
+        let handle = Map::with_capacity(8);
+        let loads = Arc::new(AtomicUsize::new(0));
+        let nthreads = 8;
+        let barrier = Arc::new(Barrier::new(nthreads));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                let loads = Arc::clone(&loads);
+                let new_handle = handle.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    new_handle.get_or_insert_with(1, || {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(::std::time::Duration::from_millis(50));
+                        42
+                    })
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), 42);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        // failed and panicked loads leave nothing behind
+        assert_eq!(
+            handle.try_get_or_insert_with(2, || Err("nope")),
+            Err("nope")
+        );
+        assert_eq!(handle.get(&2), None);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle.get_or_insert_with(2, || panic!("loader"))
+        }));
+        assert!(res.is_err());
+        assert_eq!(handle.get(&2), None);
+        assert_eq!(handle.try_get_or_insert_with(2, || Ok::<_, ()>(7)), Ok(7));
+        assert_eq!(handle.get_or_insert_with(2, || 8), 7);
+        assert_eq!(handle.len(), 2);
+    }
 }
@@ -0,0 +1,471 @@
+use super::linked_list::{LinkedList, Retirable};
+use action::Action;
+use cx::epoch::{self, Guard};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::vec;
+
+/// A shared, concurrent map from each key to a set of values.
+///
+/// The map is made of the same buckets of lock-free lists as [`Map`](super::Map), and its memory
+/// is reclaimed the same way. A key's node in its bucket points to a lock-free list of its own
+/// that holds the key's values, so working with the values of a key takes time in the number of
+/// values of that key only, and adding or removing one value of a key never waits for, or gets in
+/// the way of, the key's other values.
+///
+/// A key is in the map for as long as it has at least one value. Once its last value is removed,
+/// its list of values is closed, which keeps a value that is added at the same time from being
+/// added to a list that is about to go away, and the key is then removed from its bucket.
+///
+/// A key holds each value at most once. Operations on all of a key's values, such as
+/// [`get_all`](MultiMap::get_all) and [`remove_all`](MultiMap::remove_all), go over the values
+/// one at a time while other threads keep changing them, so values that are added or removed in
+/// the meantime may or may not be included.
+///
+/// Like the map, the multimap is used through handles: cloning a `MultiMap` gives another handle
+/// to the same multimap.
+///
+/// # Examples
+///
+/// ```
+/// use concache::crossbeam::MultiMap;
+///
+/// let sessions = MultiMap::with_capacity(16);
+/// sessions.insert("alice", 1);
+/// sessions.insert("alice", 2);
+/// sessions.insert("bob", 3);
+///
+/// let mut alice = sessions.get_all(&"alice");
+/// alice.sort();
+/// assert_eq!(alice, [1, 2]);
+/// assert!(sessions.remove_value(&"alice", &1));
+/// assert_eq!(sessions.count(&"alice"), 1);
+/// assert_eq!(sessions.remove_all(&"alice"), 1);
+/// assert_eq!(sessions.len(), 1);
+/// ```
+pub struct MultiMap<K, V: 'static> {
+    bsize: usize,
+    /// The number of values, over all keys.
+    size: Arc<AtomicUsize>,
+    mp: Arc<Vec<LinkedList<K, Values<V>>>>,
+}
+
+/// The list of a key's values, which its node in the bucket points to.
+///
+/// The list is allocated when the key is inserted, and retired by whoever removes the key after
+/// the list has been closed.
+struct Values<V: 'static>(*const Retirable<LinkedList<V, ()>>);
+
+impl<V: Eq> Values<V> {
+    /// Makes a list that holds just `value`.
+    fn new(value: V) -> Self {
+        let list = LinkedList::default();
+        if list.try_insert((value, ())).is_err() {
+            unreachable!("a new list is neither closed nor holds the value");
+        }
+        Values(Retirable::new(list))
+    }
+
+    /// Retires a list made by `new` that was never shared with anyone, and gives back its value.
+    unsafe fn into_value(self, guard: &Guard) -> V {
+        LinkedList::into_only_key(self.0, guard)
+    }
+}
+
+impl<V> Values<V> {
+    /// Returns the list. It is not retired before the key's node is removed, so it can be used
+    /// for as long as the guard that the node was read under.
+    fn get<'g>(&self, _guard: &'g Guard) -> &'g LinkedList<V, ()> {
+        unsafe { &(*self.0).val }
+    }
+}
+
+impl<V> Clone for Values<V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V> Copy for Values<V> {}
+
+impl<V> PartialEq for Values<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+unsafe impl<V: Send + Sync> Send for Values<V> {}
+unsafe impl<V: Send + Sync> Sync for Values<V> {}
+
+impl<K, V> MultiMap<K, V> {
+    /// Creates a new, shared multimap and returns a handle to it.
+    ///
+    /// See [`MapHandle::with_capacity`](super::MapHandle::with_capacity) for the meaning of
+    /// `nbuckets`, which here is the number of buckets for the keys.
+    pub fn with_capacity(nbuckets: usize) -> Self {
+        let mut v = Vec::with_capacity(nbuckets);
+
+        for _i in 0..nbuckets {
+            v.push(LinkedList::default());
+        }
+
+        MultiMap {
+            bsize: nbuckets,
+            size: Arc::new(AtomicUsize::new(0)),
+            mp: Arc::new(v),
+        }
+    }
+
+    /// Returns the number of values in the multimap, over all keys.
+    pub fn len(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the multimap contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.size.load(Ordering::SeqCst) == 0
+    }
+}
+
+impl<K, V> MultiMap<K, V>
+where
+    K: Eq + Hash,
+    V: Eq,
+{
+    fn bucket(&self, key: &K) -> &LinkedList<K, Values<V>> {
+        let mut hsh = DefaultHasher::new();
+        key.hash(&mut hsh);
+        let h = hsh.finish() as usize;
+
+        &self.mp[h % self.bsize]
+    }
+
+    /// Removes `key` if its values are still `values`, which have been closed, and retires them.
+    fn unlink(&self, key: &K, values: Values<V>, guard: &Guard) {
+        let removed = self.bucket(key).compute(
+            key,
+            || unreachable!(),
+            |cur| {
+                if cur == Some(&values) {
+                    Action::Remove
+                } else {
+                    Action::Keep
+                }
+            },
+        );
+        if let (Some(_), None) = removed {
+            unsafe { LinkedList::retire(values.0, guard) };
+        }
+    }
+
+    /// Adds `value` to the values of `key`, returning `true` if the key did not already hold it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::MultiMap;
+    ///
+    /// let map = MultiMap::with_capacity(16);
+    /// assert_eq!(map.insert(1, "a"), true);
+    /// assert_eq!(map.insert(1, "b"), true);
+    /// assert_eq!(map.insert(1, "a"), false);
+    /// assert_eq!(map.count(&1), 2);
+    /// ```
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let guard = epoch::pin();
+
+        let (mut key, mut value) = (key, value);
+        loop {
+            let bucket = self.bucket(&key);
+            match bucket.get(&key) {
+                Some(values) => {
+                    let list = values.get(&guard);
+                    match list.try_insert((value, ())) {
+                        Ok(()) => {
+                            self.size.fetch_add(1, Ordering::SeqCst);
+                            return true;
+                        }
+                        Err(_) if !list.is_closed(&guard) => return false,
+                        Err(v) => {
+                            // the key's last value was just removed, so replace its values
+                            value = v;
+                            self.unlink(&key, values, &guard);
+                        }
+                    }
+                }
+                None => {
+                    let values = Values::new(value);
+                    match bucket.try_insert((key, values)) {
+                        Ok(()) => {
+                            self.size.fetch_add(1, Ordering::SeqCst);
+                            return true;
+                        }
+                        Err(k) => {
+                            // someone else inserted the key first, and our list was never shared
+                            key = k;
+                            value = unsafe { values.into_value(&guard) };
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with every value of `key`.
+    fn for_each_value<F>(&self, key: &K, mut f: F)
+    where
+        F: FnMut(&V),
+    {
+        let guard = epoch::pin();
+
+        if let Some(values) = self.bucket(key).get(key) {
+            values.get(&guard).for_each(|v, ()| f(v));
+        }
+    }
+
+    /// Returns the number of values of `key`.
+    pub fn count(&self, key: &K) -> usize {
+        let mut n = 0;
+        self.for_each_value(key, |_| n += 1);
+        n
+    }
+
+    /// Returns true if `key` has at least one value.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.count(key) > 0
+    }
+
+    /// Returns true if `value` is one of the values of `key`.
+    pub fn contains(&self, key: &K, value: &V) -> bool {
+        let guard = epoch::pin();
+
+        match self.bucket(key).get(key) {
+            Some(values) => values.get(&guard).get(value).is_some(),
+            None => false,
+        }
+    }
+
+    /// Removes `value` from the values of `key`, returning `true` if the key held it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::MultiMap;
+    ///
+    /// let map = MultiMap::with_capacity(16);
+    /// map.insert(1, "a");
+    /// assert_eq!(map.remove_value(&1, &"a"), true);
+    /// assert_eq!(map.remove_value(&1, &"a"), false);
+    /// ```
+    pub fn remove_value(&self, key: &K, value: &V) -> bool {
+        let guard = epoch::pin();
+
+        let values = match self.bucket(key).get(key) {
+            Some(values) => values,
+            None => return false,
+        };
+        let list = values.get(&guard);
+        if list.remove(value).is_none() {
+            return false;
+        }
+        self.size.fetch_sub(1, Ordering::SeqCst);
+        if list.close() {
+            self.unlink(key, values, &guard);
+        }
+        true
+    }
+
+    /// Removes every value of `key`, returning how many were removed.
+    ///
+    /// The values are removed one at a time, so a value that another thread adds in the meantime
+    /// may be left in the map.
+    pub fn remove_all(&self, key: &K) -> usize {
+        let guard = epoch::pin();
+
+        let values = match self.bucket(key).get(key) {
+            Some(values) => values,
+            None => return 0,
+        };
+        let list = values.get(&guard);
+        let mut n = 0;
+        list.for_each(|v, ()| {
+            if list.remove(v).is_some() {
+                n += 1;
+            }
+        });
+        self.size.fetch_sub(n, Ordering::SeqCst);
+        if n > 0 && list.close() {
+            self.unlink(key, values, &guard);
+        }
+        n
+    }
+}
+
+impl<K, V> MultiMap<K, V>
+where
+    K: Eq + Hash,
+    V: Eq + Clone,
+{
+    /// Returns the values of `key`, in no particular order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::MultiMap;
+    ///
+    /// let map = MultiMap::with_capacity(16);
+    /// map.insert(1, "a");
+    /// assert_eq!(map.get_all(&1), ["a"]);
+    /// assert!(map.get_all(&2).is_empty());
+    /// ```
+    pub fn get_all(&self, key: &K) -> Vec<V> {
+        let mut values = Vec::new();
+        self.for_each_value(key, |v| values.push(v.clone()));
+        values
+    }
+
+    /// Returns an iterator over the values of `key`, in no particular order.
+    ///
+    /// The values are read when this is called; see [`get_all`](MultiMap::get_all).
+    pub fn iter_values(&self, key: &K) -> vec::IntoIter<V> {
+        self.get_all(key).into_iter()
+    }
+}
+
+impl<K, V> Clone for MultiMap<K, V> {
+    fn clone(&self) -> Self {
+        MultiMap {
+            bsize: self.bsize,
+            size: Arc::clone(&self.size),
+            mp: Arc::clone(&self.mp),
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for MultiMap<K, V>
+where
+    K: Eq + fmt::Debug,
+    V: Eq + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let guard = epoch::pin();
+
+        let mut entries = f.debug_list();
+        for bucket in self.mp.iter() {
+            bucket.for_each(|k, values| {
+                values.get(&guard).for_each(|v, ()| {
+                    entries.entry(&(k, v));
+                });
+            });
+        }
+        entries.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn multimap_basics() {
+        let map = MultiMap::with_capacity(4);
+        assert!(map.is_empty());
+        for k in 0..10 {
+            for v in 0..k {
+                assert!(map.insert(k, v));
+            }
+        }
+        assert!(!map.insert(5, 3));
+        assert_eq!(map.len(), 45);
+        assert_eq!(map.count(&0), 0);
+        assert!(!map.contains_key(&0));
+        assert_eq!(map.count(&9), 9);
+        assert!(map.contains(&9, &8));
+        assert!(!map.contains(&8, &8));
+
+        let mut values: Vec<_> = map.iter_values(&5).collect();
+        values.sort();
+        assert_eq!(values, [0, 1, 2, 3, 4]);
+
+        assert!(map.remove_value(&5, &3));
+        assert!(!map.remove_value(&5, &3));
+        assert_eq!(map.count(&5), 4);
+        assert_eq!(map.remove_all(&5), 4);
+        assert_eq!(map.remove_all(&5), 0);
+        assert!(map.get_all(&5).is_empty());
+        assert_eq!(map.len(), 40);
+        // the key went away with its last value
+        assert!(map.bucket(&5).get(&5).is_none());
+        assert!(map.insert(5, 7));
+        assert_eq!(map.get_all(&5), [7]);
+        assert!(map.remove_value(&5, &7));
+        assert!(map.bucket(&5).get(&5).is_none());
+        // other keys are untouched
+        assert_eq!(map.count(&4), 4);
+        assert_eq!(format!("{:?}", MultiMap::<u8, u8>::with_capacity(1)), "[]");
+    }
+
+    #[test]
+    fn multimap_concurrent() {
+        let map = MultiMap::with_capacity(8);
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        map.insert(i % 10, t * 1000 + i);
+                        if i % 2 == 1 {
+                            assert!(map.remove_value(&(i % 10), &(t * 1000 + i)));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(map.len(), 1000);
+        for k in 0..10 {
+            let values = map.get_all(&k);
+            assert_eq!(values.len(), map.count(&k));
+            assert!(values.iter().all(|v| v % 10 == k && v % 2 == 0));
+        }
+        assert_eq!(map.count(&0), 200);
+    }
+
+    #[test]
+    fn multimap_key_churn() {
+        // every thread keeps emptying the one key while the others add to it, so the key's
+        // values are closed and replaced over and over; no value may be lost along the way
+        let map = MultiMap::with_capacity(1);
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..2000 {
+                        assert!(map.insert(0, t * 10_000 + i));
+                        assert!(map.contains(&0, &(t * 10_000 + i)));
+                        assert!(map.remove_value(&0, &(t * 10_000 + i)));
+                    }
+                    assert!(map.insert(0, t));
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(map.len(), 4);
+        let mut values = map.get_all(&0);
+        values.sort();
+        assert_eq!(values, [0, 1, 2, 3]);
+        assert_eq!(map.remove_all(&0), 4);
+        assert!(map.is_empty());
+        assert!(!map.contains_key(&0));
+    }
+}
@@ -0,0 +1,314 @@
+use super::Map;
+use std::fmt;
+use std::hash::Hash;
+use std::iter::FromIterator;
+use std::vec;
+
+/// A shared, concurrent hash set, built on a [`Map`] whose values are `()`.
+///
+/// Like the map, the set is used through handles: cloning a `Set` gives another handle to the
+/// same set. The keys are the only thing stored; `()` is small enough to live in the word that
+/// would otherwise point to a value, so the set allocates nothing beyond each key's node.
+///
+/// # Examples
+///
+/// ```
+/// use concache::crossbeam::Set;
+/// use std::thread;
+///
+/// let set = Set::with_capacity(16);
+/// let threads: Vec<_> = (0..4)
+///     .map(|t| {
+///         let set = set.clone();
+///         thread::spawn(move || {
+///             for i in 0..10 {
+///                 set.insert(t * 10 + i);
+///             }
+///         })
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(set.len(), 40);
+/// assert!(set.contains(&39));
+/// ```
+pub struct Set<K> {
+    map: Map<K, ()>,
+}
+
+impl<K> Set<K> {
+    /// Creates a new, shared set and returns a handle to it.
+    ///
+    /// See [`MapHandle::with_capacity`](super::MapHandle::with_capacity) for the meaning of
+    /// `nbuckets`.
+    pub fn with_capacity(nbuckets: usize) -> Self {
+        Set {
+            map: Map::with_capacity(nbuckets),
+        }
+    }
+
+    /// Returns the number of keys in the set.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if the set contains no keys.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl<K> Set<K>
+where
+    K: Hash + Eq,
+{
+    /// Adds a key to the set, returning `true` if it was not already in it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Set;
+    ///
+    /// let set = Set::with_capacity(16);
+    /// assert_eq!(set.insert(1), true);
+    /// assert_eq!(set.insert(1), false);
+    /// ```
+    pub fn insert(&self, key: K) -> bool {
+        self.map.insert_if_absent(key, ()).is_none()
+    }
+
+    /// Returns true if the key is in the set.
+    pub fn contains(&self, key: &K) -> bool {
+        self.map.get(key).is_some()
+    }
+
+    /// Removes a key from the set, returning `true` if it was in it.
+    pub fn remove(&self, key: &K) -> bool {
+        self.map.remove(key)
+    }
+}
+
+impl<K> Set<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Returns an iterator over the keys in the set.
+    ///
+    /// The iterator walks the set one bucket at a time, while other threads keep using it, so
+    /// keys that are inserted or removed during the walk may or may not be seen. Every key that is
+    /// in the set throughout the walk is seen exactly once.
+    pub fn iter(&self) -> SetIter<'_, K> {
+        SetIter {
+            set: self,
+            bucket: 0,
+            keys: Vec::new().into_iter(),
+        }
+    }
+
+    /// Returns a new set of the keys that are in this set, `other`, or both.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Set;
+    ///
+    /// let a: Set<_> = vec![1, 2, 3].into_iter().collect();
+    /// let b: Set<_> = vec![2, 3, 4].into_iter().collect();
+    ///
+    /// let mut union: Vec<_> = a.union(&b).iter().collect();
+    /// union.sort();
+    /// assert_eq!(union, [1, 2, 3, 4]);
+    /// assert_eq!(a.intersection(&b).len(), 2);
+    /// assert!(a.difference(&b).contains(&1));
+    /// assert_eq!(a.symmetric_difference(&b).len(), 2);
+    /// ```
+    pub fn union(&self, other: &Set<K>) -> Set<K> {
+        let set = self.with_room_for(other);
+        self.iter().chain(other.iter()).for_each(|k| {
+            set.insert(k);
+        });
+        set
+    }
+
+    /// Returns a new set of the keys that are in both this set and `other`.
+    pub fn intersection(&self, other: &Set<K>) -> Set<K> {
+        self.filtered(|k| other.contains(k))
+    }
+
+    /// Returns a new set of the keys that are in this set, but not in `other`.
+    pub fn difference(&self, other: &Set<K>) -> Set<K> {
+        self.filtered(|k| !other.contains(k))
+    }
+
+    /// Returns a new set of the keys that are in this set or `other`, but not in both.
+    pub fn symmetric_difference(&self, other: &Set<K>) -> Set<K> {
+        let set = self.difference(other);
+        for k in other.iter().filter(|k| !self.contains(k)) {
+            set.insert(k);
+        }
+        set
+    }
+
+    /// Returns a new, empty set with as many buckets as the larger of this set and `other`.
+    fn with_room_for(&self, other: &Set<K>) -> Set<K> {
+        Set::with_capacity(self.map.bsize.max(other.map.bsize))
+    }
+
+    /// Returns a new set of the keys in this set for which `f` returns true.
+    fn filtered<F>(&self, mut f: F) -> Set<K>
+    where
+        F: FnMut(&K) -> bool,
+    {
+        let set = Set::with_capacity(self.map.bsize);
+        for k in self.iter().filter(|k| f(k)) {
+            set.insert(k);
+        }
+        set
+    }
+}
+
+/// An iterator over the keys of a [`Set`].
+///
+/// See [`Set::iter`].
+pub struct SetIter<'a, K: 'a> {
+    set: &'a Set<K>,
+    bucket: usize,
+    /// The keys of the last bucket read that have yet to be returned.
+    keys: vec::IntoIter<K>,
+}
+
+impl<'a, K> Iterator for SetIter<'a, K>
+where
+    K: Hash + Eq + Clone,
+{
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        loop {
+            if let Some(k) = self.keys.next() {
+                return Some(k);
+            }
+            if self.bucket == self.set.map.bsize {
+                return None;
+            }
+
+            let mut keys = Vec::new();
+            self.set.map.mp[self.bucket].for_each(|k, ()| keys.push(k.clone()));
+            self.bucket += 1;
+            self.keys = keys.into_iter();
+        }
+    }
+}
+
+impl<'a, K> IntoIterator for &'a Set<K>
+where
+    K: Hash + Eq + Clone,
+{
+    type Item = K;
+    type IntoIter = SetIter<'a, K>;
+
+    fn into_iter(self) -> SetIter<'a, K> {
+        self.iter()
+    }
+}
+
+impl<K> FromIterator<K> for Set<K>
+where
+    K: Hash + Eq,
+{
+    /// Collects the keys into a new set with 16 buckets.
+    fn from_iter<I: IntoIterator<Item = K>>(iter: I) -> Self {
+        let set = Set::with_capacity(16);
+        for k in iter {
+            set.insert(k);
+        }
+        set
+    }
+}
+
+impl<K> Clone for Set<K> {
+    fn clone(&self) -> Self {
+        Set {
+            map: self.map.clone(),
+        }
+    }
+}
+
+impl<K> fmt::Debug for Set<K>
+where
+    K: Hash + Eq + Clone + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn set_basics() {
+        let set = Set::with_capacity(4);
+        assert!(set.is_empty());
+        for i in 0..20 {
+            assert!(set.insert(i));
+        }
+        assert!(!set.insert(7));
+        assert_eq!(set.len(), 20);
+        assert!(set.contains(&7));
+        assert!(set.remove(&7));
+        assert!(!set.remove(&7));
+        assert!(!set.contains(&7));
+
+        let mut keys: Vec<_> = set.iter().collect();
+        keys.sort();
+        assert_eq!(keys, (0..20).filter(|&i| i != 7).collect::<Vec<_>>());
+
+        let evens: Set<_> = (0..20).filter(|i| i % 2 == 0).collect();
+        let sorted = |s: Set<i32>| {
+            let mut v: Vec<_> = s.iter().collect();
+            v.sort();
+            v
+        };
+        assert_eq!(sorted(set.intersection(&evens)).len(), 10);
+        assert_eq!(sorted(evens.difference(&set)), vec![]);
+        assert_eq!(
+            sorted(set.difference(&evens)),
+            vec![1, 3, 5, 9, 11, 13, 15, 17, 19]
+        );
+        assert_eq!(sorted(evens.symmetric_difference(&set)).len(), 9);
+        assert_eq!(sorted(set.union(&evens)).len(), 19);
+        assert_eq!(format!("{:?}", Set::<u8>::with_capacity(1)), "{}");
+    }
+
+    #[test]
+    fn set_iter_concurrent() {
+        let set = Set::with_capacity(8);
+        for i in 0..1000 {
+            set.insert(i);
+        }
+        let writer = {
+            let set = set.clone();
+            thread::spawn(move || {
+                for i in 1000..2000 {
+                    set.insert(i);
+                    set.remove(&(i - 500));
+                }
+            })
+        };
+        // keys that stay in the set are seen once, however the set changes during the walk
+        for _ in 0..10 {
+            let mut seen = vec![0; 2000];
+            for k in &set {
+                seen[k] += 1;
+            }
+            assert!(seen.iter().all(|&n| n <= 1));
+            assert!(seen[..500].iter().all(|&n| n == 1));
+        }
+        writer.join().unwrap();
+        assert_eq!(set.len(), 1000);
+    }
+}
@@ -0,0 +1,354 @@
+//! Single-flight loading of missing values.
+//!
+//! When many threads miss on the same key at once, only one of them, the leader, should compute
+//! the key's value; the rest wait for it and share its result. Each map keeps a table of the keys
+//! whose values are being loaded, and a thread that misses joins the flight for its key if there
+//! is one, or starts a new one otherwise.
+//!
+//! A leader always lands its flight, even if its loader panics, so no key is left with a flight
+//! that never finishes. Errors are handed to the waiters as well, so that they fail along with the
+//! leader instead of piling onto whatever made the loader fail.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// How a flight ended.
+enum Outcome<V> {
+    Loaded(V),
+    Failed(Arc<dyn Any + Send + Sync>),
+    Panicked,
+}
+
+impl<V: Copy> Clone for Outcome<V> {
+    fn clone(&self) -> Self {
+        match *self {
+            Outcome::Loaded(v) => Outcome::Loaded(v),
+            Outcome::Failed(ref e) => Outcome::Failed(Arc::clone(e)),
+            Outcome::Panicked => Outcome::Panicked,
+        }
+    }
+}
+
+/// A value that is being loaded.
+struct Flight<V> {
+    outcome: Mutex<Option<Outcome<V>>>,
+    landed: Condvar,
+}
+
+/// The keys of a map whose values are being loaded.
+pub(crate) struct Flights<K, V> {
+    inflight: Mutex<HashMap<K, Arc<Flight<V>>>>,
+}
+
+impl<K, V> Default for Flights<K, V> {
+    fn default() -> Self {
+        Flights {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Locks `mutex`, even if a thread panicked while holding it.
+///
+/// The keys' `Hash` and `Eq` run while `inflight` is locked, and they are user code that may
+/// panic. They only ever panic before the table is changed, though, so it is still intact.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What a thread that missed on a key should do.
+pub(crate) enum Role<'a, K: 'a + Hash + Eq, V: 'a> {
+    /// Load the value, and land the flight with it.
+    Leader(Leader<'a, K, V>),
+    /// Wait for the flight that is already loading the value.
+    Waiter(Waiter<V>),
+}
+
+impl<K, V> Flights<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    /// Joins the flight for `key`, starting one if there is none.
+    pub(crate) fn join(&self, key: &K) -> Role<'_, K, V> {
+        // the key's `Clone` is user code, and may panic, so both clones are made before taking the
+        // lock: a panic once the flight is in the table would leave it there, with no leader to
+        // land it
+        let owned = key.clone();
+        let led = key.clone();
+        let flight = {
+            let mut inflight = lock(&self.inflight);
+            if let Some(flight) = inflight.get(key) {
+                return Role::Waiter(Waiter(Arc::clone(flight)));
+            }
+
+            let flight = Arc::new(Flight {
+                outcome: Mutex::new(None),
+                landed: Condvar::new(),
+            });
+            inflight.insert(owned, Arc::clone(&flight));
+            flight
+        };
+        Role::Leader(Leader {
+            flights: self,
+            key: led,
+            flight,
+            landed: false,
+        })
+    }
+}
+
+/// The thread responsible for loading a key's value.
+///
+/// If it is dropped without landing, its loader is taken to have panicked.
+pub(crate) struct Leader<'a, K: 'a + Hash + Eq, V: 'a> {
+    flights: &'a Flights<K, V>,
+    key: K,
+    flight: Arc<Flight<V>>,
+    landed: bool,
+}
+
+impl<'a, K, V> Leader<'a, K, V>
+where
+    K: Hash + Eq,
+{
+    /// Ends the flight, waking up everyone waiting for it.
+    ///
+    /// The value must already be in the map, so that threads that miss on the key after the flight
+    /// is gone find it there.
+    fn land(&mut self, outcome: Outcome<V>) {
+        self.landed = true;
+        lock(&self.flights.inflight).remove(&self.key);
+        *lock(&self.flight.outcome) = Some(outcome);
+        self.flight.landed.notify_all();
+    }
+
+    /// Lands the flight with the value that was loaded.
+    pub(crate) fn loaded(mut self, value: V) {
+        self.land(Outcome::Loaded(value));
+    }
+
+    /// Lands the flight with the error the loader failed with.
+    pub(crate) fn failed<E>(mut self, error: E)
+    where
+        E: Send + Sync + 'static,
+    {
+        self.land(Outcome::Failed(Arc::new(error)));
+    }
+}
+
+impl<'a, K, V> Drop for Leader<'a, K, V>
+where
+    K: Hash + Eq,
+{
+    fn drop(&mut self) {
+        if !self.landed {
+            self.land(Outcome::Panicked);
+        }
+    }
+}
+
+/// A thread waiting for another one to load a key's value.
+pub(crate) struct Waiter<V>(Arc<Flight<V>>);
+
+impl<V: Copy> Waiter<V> {
+    /// Waits for the flight to land, and returns the value that was loaded, or the error the
+    /// loader failed with.
+    ///
+    /// Returns `None` if the loader failed with an error of a different type than `E`, in which
+    /// case the caller should try loading the value itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the loader panicked.
+    pub(crate) fn wait<E>(self) -> Option<Result<V, E>>
+    where
+        E: Clone + 'static,
+    {
+        let mut outcome = lock(&self.0.outcome);
+        while outcome.is_none() {
+            outcome = self
+                .0
+                .landed
+                .wait(outcome)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+
+        match outcome.clone().unwrap() {
+            Outcome::Loaded(v) => Some(Ok(v)),
+            Outcome::Failed(e) => e.downcast_ref::<E>().cloned().map(Err),
+            Outcome::Panicked => {
+                panic!("the loader of a value this thread was waiting for panicked")
+            }
+        }
+    }
+}
+
+/// Returns the value for `key` in the map, loading it with `load` and inserting it if it is
+/// missing, in such a way that only one thread loads the value for a given key at a time.
+///
+/// `get` and `insert_if_absent` are the map's operations, given `cx`.
+pub(crate) fn get_or_load<C, K, V, E, G, I, F>(
+    flights: &Flights<K, V>,
+    mut cx: C,
+    key: K,
+    get: G,
+    insert_if_absent: I,
+    load: F,
+) -> Result<V, E>
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+    E: Clone + Send + Sync + 'static,
+    G: Fn(&mut C, &K) -> Option<V>,
+    I: FnOnce(&mut C, K, V) -> Option<V>,
+    F: FnOnce() -> Result<V, E>,
+{
+    loop {
+        if let Some(v) = get(&mut cx, &key) {
+            return Ok(v);
+        }
+
+        match flights.join(&key) {
+            Role::Waiter(waiter) => {
+                if let Some(res) = waiter.wait() {
+                    return res;
+                }
+            }
+            Role::Leader(leader) => {
+                // the previous flight may have landed between the miss and the join
+                if let Some(v) = get(&mut cx, &key) {
+                    leader.loaded(v);
+                    return Ok(v);
+                }
+
+                return match load() {
+                    Ok(v) => {
+                        // someone may have inserted a value without going through a flight
+                        let v = insert_if_absent(&mut cx, key, v).unwrap_or(v);
+                        leader.loaded(v);
+                        Ok(v)
+                    }
+                    Err(e) => {
+                        leader.failed(e.clone());
+                        Err(e)
+                    }
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hasher;
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn flight_roles() {
+        let flights = Flights::<u8, u8>::default();
+        let leader = match flights.join(&1) {
+            Role::Leader(l) => l,
+            Role::Waiter(_) => panic!("first to miss should lead"),
+        };
+        let waiter = match flights.join(&1) {
+            Role::Waiter(w) => w,
+            Role::Leader(_) => panic!("second to miss should wait"),
+        };
+        match flights.join(&2) {
+            Role::Leader(l) => drop(l),
+            Role::Waiter(_) => panic!("other keys get their own flights"),
+        }
+
+        let t = thread::spawn(move || waiter.wait::<()>());
+        leader.loaded(7);
+        assert_eq!(t.join().unwrap(), Some(Ok(7)));
+
+        // errors are shared with waiters that expect them
+        for &expected in &[true, false] {
+            let leader = match flights.join(&1) {
+                Role::Leader(l) => l,
+                Role::Waiter(_) => panic!("landed flights are gone"),
+            };
+            let waiter = match flights.join(&1) {
+                Role::Waiter(w) => w,
+                Role::Leader(_) => panic!("second to miss should wait"),
+            };
+            leader.failed("boom");
+            if expected {
+                assert_eq!(waiter.wait::<&str>(), Some(Err("boom")));
+            } else {
+                assert_eq!(waiter.wait::<String>(), None);
+            }
+        }
+
+        // and so are panics
+        let leader = match flights.join(&1) {
+            Role::Leader(l) => l,
+            Role::Waiter(_) => panic!("landed flights are gone"),
+        };
+        let waiter = match flights.join(&1) {
+            Role::Waiter(w) => w,
+            Role::Leader(_) => panic!("second to miss should wait"),
+        };
+        drop(leader);
+        assert!(thread::spawn(move || waiter.wait::<()>()).join().is_err());
+    }
+
+    #[test]
+    fn flight_panicking_key() {
+        // a key whose `Hash` panics for 0, whose `Clone` panics for 1, and whose second `Clone`
+        // panics for 3
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+        #[derive(PartialEq, Eq)]
+        struct Key(u8);
+        impl Hash for Key {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                assert_ne!(self.0, 0, "hash");
+                self.0.hash(state);
+            }
+        }
+        impl Clone for Key {
+            fn clone(&self) -> Self {
+                assert_ne!(self.0, 1, "clone");
+                if self.0 == 3 {
+                    assert_ne!(CLONES.fetch_add(1, Ordering::SeqCst), 1, "second clone");
+                }
+                Key(self.0)
+            }
+        }
+
+        let flights = Flights::<Key, u8>::default();
+        let first = match flights.join(&Key(2)) {
+            Role::Leader(l) => l,
+            Role::Waiter(_) => panic!("first to miss should lead"),
+        };
+        for &k in &[0, 1, 3] {
+            let joined = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                flights.join(&Key(k));
+            }));
+            assert!(joined.is_err());
+        }
+
+        // the table is still usable, and still knows about the flight that was already there
+        match flights.join(&Key(2)) {
+            Role::Waiter(_) => {}
+            Role::Leader(_) => panic!("the flight should still be there"),
+        }
+        // and no flight was left behind for the keys that panicked
+        match flights.join(&Key(3)) {
+            Role::Leader(l) => l.loaded(3),
+            Role::Waiter(_) => panic!("no flight should have been left behind"),
+        }
+        first.loaded(1);
+        match flights.join(&Key(2)) {
+            Role::Leader(l) => l.loaded(2),
+            Role::Waiter(_) => panic!("landed flights are gone"),
+        };
+    }
+}
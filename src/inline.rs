@@ -0,0 +1,182 @@
+//! Storage for values small enough to live directly in an atomic word.
+//!
+//! Both maps keep each value behind a pointer that is swapped with a compare-and-swap whenever
+//! the value changes, and use null to mean that the key has been removed. For integers, the word
+//! that would hold the pointer holds the value itself instead, as long as the value fits. That
+//! saves an allocation on every write, and makes an update a single compare-and-swap on the value,
+//! which is what makes counters cheap.
+//!
+//! The low bit of an inline word is always 1 and the value is stored in the bits above it, so an
+//! inline value is never mistaken for null, or for a pointer to a boxed value, whose low bit is 0.
+//! That leaves one bit fewer than a word for the value: integers narrower than a word always fit,
+//! and a word-sized one fits if it can do without its top bit, such as a `u64` below 2^63 or an
+//! `i64` between -2^62 and 2^62 on 64-bit platforms. Values that do not fit are boxed, so a map
+//! of word-sized integers can hold a mix of inline and boxed values.
+//!
+//! Only the primitive integers, `bool`, `char` and types without any bytes at all, such as `()`,
+//! are stored inline. Those are the types whose every byte is part of the value; copying any other
+//! type into a word could copy padding bytes, which hold no value, along with it. Types are told
+//! apart by their [`TypeId`], which is why the maps' values have to be `'static`.
+
+use std::any::TypeId;
+use std::mem;
+use std::ptr;
+
+/// How the values of a type are stored.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Repr {
+    /// Always boxed.
+    Boxed,
+    /// Inline if the value fits, with the bits above it read back as zeros.
+    Unsigned,
+    /// Inline if the value fits, with the bits above it read back as copies of its top bit.
+    Signed,
+}
+
+fn repr<V: 'static>() -> Repr {
+    let size = mem::size_of::<V>();
+    if size == 0 {
+        return Repr::Unsigned;
+    }
+    if size > mem::size_of::<usize>() {
+        return Repr::Boxed;
+    }
+    let id = TypeId::of::<V>();
+    let unsigned = [
+        TypeId::of::<u8>(),
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<bool>(),
+        TypeId::of::<char>(),
+    ];
+    let signed = [
+        TypeId::of::<i8>(),
+        TypeId::of::<i16>(),
+        TypeId::of::<i32>(),
+        TypeId::of::<i64>(),
+        TypeId::of::<isize>(),
+    ];
+    if unsigned.contains(&id) {
+        Repr::Unsigned
+    } else if signed.contains(&id) {
+        Repr::Signed
+    } else {
+        Repr::Boxed
+    }
+}
+
+/// Returns true if values of type `V` are stored inline whenever they fit.
+pub(crate) fn inlinable<V: 'static>() -> bool {
+    repr::<V>() != Repr::Boxed
+}
+
+/// Returns true if `word`, which holds a value of type `V` and is not null, holds it inline rather
+/// than pointing to it.
+pub(crate) fn is_inline<V: 'static>(word: usize) -> bool {
+    inlinable::<V>() && word & 1 == 1
+}
+
+/// Encodes `val` as an inline word, or returns `None` if it must be boxed.
+pub(crate) fn encode<V: 'static>(val: &V) -> Option<usize> {
+    let repr = repr::<V>();
+    if repr == Repr::Boxed {
+        return None;
+    }
+
+    let v = val as *const V;
+    let bits = unsafe {
+        match mem::size_of::<V>() {
+            0 => 0,
+            1 => ptr::read(v as *const u8) as usize,
+            2 => ptr::read_unaligned(v as *const u16) as usize,
+            4 => ptr::read_unaligned(v as *const u32) as usize,
+            _ => ptr::read_unaligned(v as *const u64) as usize,
+        }
+    };
+    let word = bits << 1 | 1;
+    if mem::size_of::<V>() == mem::size_of::<usize>() && unshift(repr, word) != bits {
+        // the top bit was lost
+        return None;
+    }
+    Some(word)
+}
+
+/// Returns the bits of the value held by an inline word.
+fn unshift(repr: Repr, word: usize) -> usize {
+    match repr {
+        Repr::Signed => ((word as isize) >> 1) as usize,
+        _ => word >> 1,
+    }
+}
+
+/// Decodes a word produced by [`encode`].
+pub(crate) unsafe fn decode<V: Copy + 'static>(word: usize) -> V {
+    debug_assert!(is_inline::<V>(word));
+    let bits = unshift(repr::<V>(), word);
+    let mut val = mem::MaybeUninit::<V>::uninit();
+    let v = val.as_mut_ptr();
+    match mem::size_of::<V>() {
+        0 => {}
+        1 => ptr::write(v as *mut u8, bits as u8),
+        2 => ptr::write_unaligned(v as *mut u16, bits as u16),
+        4 => ptr::write_unaligned(v as *mut u32, bits as u32),
+        _ => ptr::write_unaligned(v as *mut u64, bits as u64),
+    }
+    val.assume_init()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip<V: Copy + PartialEq + ::std::fmt::Debug + 'static>(v: V) -> bool {
+        match encode(&v) {
+            Some(w) => {
+                assert!(is_inline::<V>(w));
+                assert_eq!(unsafe { decode::<V>(w) }, v);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn inline_roundtrip() {
+        assert!(inlinable::<()>());
+        assert!(inlinable::<u8>());
+        assert!(inlinable::<usize>());
+        assert!(!inlinable::<u128>());
+        // these would copy padding, or the uninitialised payload of `None`
+        assert!(!inlinable::<(u8, u16)>());
+        assert!(!inlinable::<Option<u8>>());
+
+        for &v in &[0u8, 1, 127, 255] {
+            assert!(roundtrip(v));
+        }
+        for &v in &[0i16, -1, i16::MIN, i16::MAX] {
+            assert!(roundtrip(v));
+        }
+        for &v in &[0u32, u32::MAX] {
+            assert!(roundtrip(v));
+        }
+        assert!(roundtrip(()));
+        assert!(roundtrip(true));
+        assert!(roundtrip('é'));
+        assert_ne!(encode(&0u16), encode(&1u16));
+
+        // a word-sized integer fits unless it needs its top bit
+        assert!(roundtrip(usize::MAX >> 1));
+        assert!(!roundtrip(usize::MAX));
+        assert!(roundtrip(-1isize));
+        assert!(roundtrip(isize::MIN >> 1));
+        assert!(roundtrip(isize::MAX >> 1));
+        assert!(!roundtrip(isize::MIN));
+        assert!(!roundtrip(isize::MAX));
+
+        // pointers have a clear low bit
+        assert!(!is_inline::<usize>(&0u64 as *const u64 as usize));
+        assert!(!is_inline::<(u8, u16)>(1));
+    }
+}
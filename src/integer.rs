@@ -0,0 +1,41 @@
+/// A primitive integer type, for the counter operations of the maps.
+///
+/// This is implemented for all of the primitive integer types, and is what the `fetch_add`,
+/// `fetch_sub`, `fetch_max` and `fetch_min` methods of the map handles require of their values.
+/// Like the methods of the standard library's atomic integers, additions and subtractions wrap
+/// around on overflow.
+///
+/// Integers are stored directly in the map's nodes and updated in place, as long as they fit in a
+/// word with one bit to spare: all integers narrower than a pointer, and `u64` values below 2^63
+/// and `i64` values between -2^62 and 2^62 on 64-bit platforms. Values that do not fit are
+/// allocated on every write, like any other value.
+pub trait Integer: Copy + Ord {
+    /// The value an absent key is treated as having.
+    const ZERO: Self;
+
+    /// Adds `rhs` to `self`, wrapping around at the boundary of the type.
+    fn wrapping_add(self, rhs: Self) -> Self;
+
+    /// Subtracts `rhs` from `self`, wrapping around at the boundary of the type.
+    fn wrapping_sub(self, rhs: Self) -> Self;
+}
+
+macro_rules! impl_integer {
+    ($($t:ty)*) => {
+        $(
+            impl Integer for $t {
+                const ZERO: Self = 0;
+
+                fn wrapping_add(self, rhs: Self) -> Self {
+                    <$t>::wrapping_add(self, rhs)
+                }
+
+                fn wrapping_sub(self, rhs: Self) -> Self {
+                    <$t>::wrapping_sub(self, rhs)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize);
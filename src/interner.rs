@@ -0,0 +1,389 @@
+//! A concurrent string interner.
+//!
+//! Strings are looked up in a [`crossbeam::Map`] from their text to their symbol, and resolved
+//! from a chunked vector indexed by the symbol. The vector only ever grows: a chunk, once
+//! allocated, is never moved or freed until the interner is dropped, and neither is a string once
+//! it has been stored in one. This is what keeps symbols, and the strings they resolve to, valid
+//! for as long as the interner lives.
+
+use crossbeam::Map;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ptr;
+use std::slice;
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// The number of slots in the first chunk; each chunk after it is twice as large as the last.
+const FIRST_CHUNK: usize = 64;
+
+/// The number of chunks, which is enough to give every `u32` a slot.
+const NCHUNKS: usize = 27;
+
+/// An interned string.
+///
+/// Symbols are small and cheap to copy, compare and hash. A symbol is only meaningful to the
+/// [`Interner`] that returned it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    /// Returns the number behind this symbol.
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the symbol with the given number, as returned by [`as_u32`](Symbol::as_u32).
+    pub fn from_u32(n: u32) -> Self {
+        Symbol(n)
+    }
+}
+
+/// A string stored in an interner, used as a key of its forward map.
+///
+/// The key is only a pointer, and it is compared and hashed by the text it points to. Keys in the
+/// map point into the interner's chunks, which outlive the map; lookups build a temporary key
+/// that points at the string being looked up.
+struct Str(*const str);
+
+// Safe because a `Str` is only ever read through, like a `&str`.
+unsafe impl Send for Str {}
+unsafe impl Sync for Str {}
+
+impl Str {
+    fn text(&self) -> &str {
+        unsafe { &*self.0 }
+    }
+}
+
+impl PartialEq for Str {
+    fn eq(&self, other: &Str) -> bool {
+        self.text() == other.text()
+    }
+}
+
+impl Eq for Str {}
+
+impl Hash for Str {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.text().hash(state);
+    }
+}
+
+/// A slot of the reverse vector, holding the string of one symbol.
+///
+/// A slot is empty until `ptr` is set, which happens after `len`, so a reader that sees `ptr` also
+/// sees the length of the string it points to. A slot is filled before its symbol is known to be
+/// the one that made it into the map, and only resolves once `live` is set, which happens before
+/// the symbol is first handed out.
+struct Slot {
+    ptr: AtomicPtr<u8>,
+    len: AtomicUsize,
+    live: AtomicBool,
+}
+
+impl Slot {
+    fn get(&self) -> Option<&str> {
+        if !self.live.load(Ordering::SeqCst) {
+            return None;
+        }
+        let ptr = self.ptr.load(Ordering::SeqCst);
+        let len = self.len.load(Ordering::SeqCst);
+        Some(unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) })
+    }
+}
+
+/// Returns the chunk that holds symbol `n`, and the symbol's index within that chunk.
+fn locate(n: usize) -> (usize, usize) {
+    let chunk = (usize::BITS - 1 - (n / FIRST_CHUNK + 1).leading_zeros()) as usize;
+    (chunk, n - FIRST_CHUNK * ((1 << chunk) - 1))
+}
+
+/// Returns the number of slots in the given chunk.
+fn chunk_len(chunk: usize) -> usize {
+    FIRST_CHUNK << chunk
+}
+
+/// A concurrent string interner, which maps strings to [`Symbol`]s and back.
+///
+/// Interning a string returns the same symbol every time, from any thread, and
+/// [`resolve`](Interner::resolve) turns that symbol back into the string. Looking up a string
+/// that is already interned never waits for other threads, and neither does resolving a symbol.
+/// Interning a new string allocates a copy of it, which stays with the interner until the
+/// interner is dropped; strings are never removed.
+///
+/// Unlike the maps, an interner is not used through handles: it is shared by reference, such as
+/// through an [`Arc`](std::sync::Arc), so that the strings it resolves to can borrow from it.
+///
+/// Symbols are handed out in increasing order, starting at 0. When threads race to intern the
+/// same new string, only one of them gets its symbol into the map, and the numbers the others
+/// took are skipped and never resolve, so the numbers are not always contiguous.
+///
+/// # Examples
+///
+/// ```
+/// use concache::Interner;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let interner = Arc::new(Interner::with_capacity(16));
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let interner = Arc::clone(&interner);
+///         thread::spawn(move || interner.intern("hello"))
+///     })
+///     .collect();
+/// let symbols: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+/// assert!(symbols.iter().all(|&s| s == symbols[0]));
+/// assert_eq!(interner.resolve(symbols[0]), Some("hello"));
+/// assert_eq!(interner.len(), 1);
+/// ```
+pub struct Interner {
+    forward: Map<Str, u32>,
+    chunks: [AtomicPtr<Slot>; NCHUNKS],
+    /// The number of the next symbol to hand out.
+    next: AtomicUsize,
+}
+
+impl Interner {
+    /// Creates a new, empty interner.
+    ///
+    /// See [`MapHandle::with_capacity`](::crossbeam::MapHandle::with_capacity) for the meaning
+    /// of `nbuckets`, which is the number of buckets of the map used to look strings up.
+    pub fn with_capacity(nbuckets: usize) -> Self {
+        Interner {
+            forward: Map::with_capacity(nbuckets),
+            chunks: Default::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of distinct strings interned.
+    pub fn len(&self) -> usize {
+        self.forward.len()
+    }
+
+    /// Returns true if no strings have been interned.
+    pub fn is_empty(&self) -> bool {
+        self.forward.is_empty()
+    }
+
+    /// Returns the symbol of `s`, interning it if it has not been already.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every `u32` has been handed out as a symbol.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::Interner;
+    ///
+    /// let interner = Interner::with_capacity(16);
+    /// let a = interner.intern("a");
+    /// let b = interner.intern("b");
+    /// assert_ne!(a, b);
+    /// assert_eq!(interner.intern("a"), a);
+    /// ```
+    pub fn intern(&self, s: &str) -> Symbol {
+        if let Some(sym) = self.get(s) {
+            return sym;
+        }
+
+        let n = self.next.fetch_add(1, Ordering::SeqCst);
+        assert!(n <= u32::MAX as usize, "interner is out of symbols");
+        let text = self.store(n, s);
+
+        // if another thread got there first, our copy stays in its slot, which never goes live,
+        // until the interner is dropped
+        let sym = self
+            .forward
+            .insert_if_absent(Str(text), n as u32)
+            .unwrap_or(n as u32);
+        Symbol(self.publish(sym))
+    }
+
+    /// Returns the symbol of `s`, if it has been interned.
+    pub fn get(&self, s: &str) -> Option<Symbol> {
+        self.forward.get(&Str(s)).map(|n| Symbol(self.publish(n)))
+    }
+
+    /// Makes symbol `n`, which is in the map, resolve, and returns it.
+    ///
+    /// Every thread that hands out a symbol does this first, rather than only the thread that put
+    /// it in the map, so that a symbol never reaches anyone before its string can be resolved.
+    fn publish(&self, n: u32) -> u32 {
+        let (chunk, i) = locate(n as usize);
+        let slot = unsafe { &*self.chunks[chunk].load(Ordering::SeqCst).add(i) };
+        if !slot.live.load(Ordering::SeqCst) {
+            slot.live.store(true, Ordering::SeqCst);
+        }
+        n
+    }
+
+    /// Returns the string that `sym` was interned from, or `None` if this interner has not handed
+    /// out `sym`.
+    ///
+    /// The string lives as long as the interner does.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::{Interner, Symbol};
+    ///
+    /// let interner = Interner::with_capacity(16);
+    /// let sym = interner.intern("hello");
+    /// assert_eq!(interner.resolve(sym), Some("hello"));
+    /// assert_eq!(interner.resolve(Symbol::from_u32(1000)), None);
+    /// ```
+    pub fn resolve(&self, sym: Symbol) -> Option<&str> {
+        let (chunk, i) = locate(sym.0 as usize);
+        let slots = self.chunks[chunk].load(Ordering::SeqCst);
+        if slots.is_null() {
+            return None;
+        }
+        unsafe { &*slots.add(i) }.get()
+    }
+
+    /// Copies `s` into the slot of symbol `n`, without making it live, and returns the copy.
+    fn store(&self, n: usize, s: &str) -> *const str {
+        let (chunk, i) = locate(n);
+        let slot = unsafe { &*self.chunk(chunk).add(i) };
+
+        let text = Box::into_raw(Box::<str>::from(s));
+        slot.len.store(s.len(), Ordering::SeqCst);
+        slot.ptr.store(text as *mut u8, Ordering::SeqCst);
+        text
+    }
+
+    /// Returns the slots of the given chunk, allocating them if no thread has yet.
+    fn chunk(&self, chunk: usize) -> *mut Slot {
+        let slots = self.chunks[chunk].load(Ordering::SeqCst);
+        if !slots.is_null() {
+            return slots;
+        }
+
+        let new: Box<[Slot]> = (0..chunk_len(chunk))
+            .map(|_| Slot {
+                ptr: AtomicPtr::new(ptr::null_mut()),
+                len: AtomicUsize::new(0),
+                live: AtomicBool::new(false),
+            })
+            .collect();
+        let new = Box::into_raw(new) as *mut Slot;
+        match self.chunks[chunk].compare_exchange(
+            ptr::null_mut(),
+            new,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => new,
+            Err(current) => {
+                // another thread allocated the chunk first
+                drop(unsafe {
+                    Box::from_raw(ptr::slice_from_raw_parts_mut(new, chunk_len(chunk)))
+                });
+                current
+            }
+        }
+    }
+}
+
+impl Drop for Interner {
+    fn drop(&mut self) {
+        for (chunk, slots) in self.chunks.iter().enumerate() {
+            let slots = slots.load(Ordering::SeqCst);
+            if slots.is_null() {
+                continue;
+            }
+            let slots =
+                unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(slots, chunk_len(chunk))) };
+            for slot in slots.iter() {
+                let text = slot.ptr.load(Ordering::SeqCst);
+                if !text.is_null() {
+                    let len = slot.len.load(Ordering::SeqCst);
+                    drop(unsafe {
+                        Box::from_raw(ptr::slice_from_raw_parts_mut(text, len) as *mut str)
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Interner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interner")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn interner_basics() {
+        assert_eq!(locate(0), (0, 0));
+        assert_eq!(locate(63), (0, 63));
+        assert_eq!(locate(64), (1, 0));
+        assert_eq!(locate(191), (1, 127));
+        assert_eq!(locate(192), (2, 0));
+        assert_eq!(locate(u32::MAX as usize).0, NCHUNKS - 1);
+
+        let interner = Interner::with_capacity(4);
+        assert!(interner.is_empty());
+        assert_eq!(interner.get("a"), None);
+        let syms: Vec<_> = (0..1000).map(|i| interner.intern(&i.to_string())).collect();
+        assert_eq!(interner.len(), 1000);
+        for (i, &sym) in syms.iter().enumerate() {
+            assert_eq!(sym.as_u32() as usize, i);
+            assert_eq!(interner.intern(&i.to_string()), sym);
+            assert_eq!(interner.get(&i.to_string()), Some(sym));
+            assert_eq!(interner.resolve(sym), Some(&*i.to_string()));
+        }
+        assert_eq!(interner.intern(""), Symbol(1000));
+        assert_eq!(interner.resolve(Symbol(1000)), Some(""));
+        assert_eq!(interner.resolve(Symbol(1001)), None);
+        assert_eq!(interner.resolve(Symbol(u32::MAX)), None);
+
+        // the symbol a thread takes when it loses a race to intern a string never resolves
+        let n = interner.next.fetch_add(1, Ordering::SeqCst);
+        interner.store(n, "lost");
+        assert_eq!(interner.resolve(Symbol(n as u32)), None);
+        let sym = interner.intern("lost");
+        assert_ne!(sym, Symbol(n as u32));
+        assert_eq!(interner.resolve(sym), Some("lost"));
+        assert_eq!(interner.resolve(Symbol(n as u32)), None);
+    }
+
+    #[test]
+    fn interner_concurrent() {
+        let interner = Arc::new(Interner::with_capacity(16));
+        let threads: Vec<_> = vec![1, 3, 7, 9]
+            .into_iter()
+            .map(|step| {
+                let interner = Arc::clone(&interner);
+                thread::spawn(move || {
+                    (0..2000)
+                        .map(|i| {
+                            // every thread interns the same strings, in a different order
+                            let s = format!("sym{}", (i * step) % 2000);
+                            let sym = interner.intern(&s);
+                            assert_eq!(interner.resolve(sym), Some(&*s));
+                            (s, sym)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(interner.len(), 2000);
+        for (s, sym) in results.iter().flatten() {
+            assert_eq!(interner.get(s), Some(*sym));
+        }
+    }
+}
@@ -0,0 +1,489 @@
+//! A read-optimized concurrent hash map built on the left-right technique, in the style of
+//! [`evmap`](https://docs.rs/evmap/).
+//!
+//! The map keeps two copies of its data. Readers only ever look at one of them, which never
+//! changes while they are looking at it, so reads are wait-free and as fast as reads from a
+//! `HashMap`. The single [`WriteHandle`] makes its changes to the other copy, and they only become
+//! visible to readers when it calls [`WriteHandle::refresh`]. That swaps the two copies, waits for
+//! readers that may still be looking at the old one to finish, and then brings the old one up to
+//! date, so that it is ready for the next batch of changes.
+//!
+//! This suits data that is read much more often than it changes, such as configuration: readers
+//! see a consistent snapshot of the map as of the last refresh, and writers can batch many
+//! changes into a single refresh. Every change is applied twice, once to each copy, so keys and
+//! values must be `Clone`.
+//!
+//! Each thread that reads the map needs its own [`ReadHandle`], which it gets by cloning any
+//! other. Readers announce when they are in the middle of a read with the same epoch counters as
+//! the [`manual`](::manual) map's handles, and a refresh waits for them the same way that map
+//! waits before reclaiming memory.
+//!
+//! # Examples
+//!
+//! ```
+//! use concache::leftright;
+//! use std::thread;
+//!
+//! let (reader, mut writer) = leftright::new();
+//! writer.insert("timeout", 30);
+//! writer.insert("retries", 3);
+//! assert_eq!(reader.get(&"timeout"), None);
+//! writer.refresh();
+//!
+//! let t = {
+//!     let reader = reader.clone();
+//!     thread::spawn(move || reader.get(&"timeout"))
+//! };
+//! assert_eq!(t.join().unwrap(), Some(30));
+//! assert_eq!(reader.get_and(&"retries", |&r| r * 2), Some(6));
+//! ```
+
+use manual::{quiesce, CriticalSection};
+use std::cell::{Cell, UnsafeCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+const OSC: Ordering = Ordering::SeqCst;
+
+/// A change made by the [`WriteHandle`] that has yet to be made to the copy readers are using.
+enum Op<K, V> {
+    Insert(K, V),
+    Remove(K),
+    Clear,
+}
+
+impl<K, V> Op<K, V>
+where
+    K: Hash + Eq,
+{
+    fn apply(self, map: &mut HashMap<K, V>) {
+        match self {
+            Op::Insert(k, v) => {
+                map.insert(k, v);
+            }
+            Op::Remove(k) => {
+                map.remove(&k);
+            }
+            Op::Clear => map.clear(),
+        }
+    }
+}
+
+struct Inner<K, V> {
+    maps: [UnsafeCell<HashMap<K, V>>; 2],
+    /// Which of `maps` readers use.
+    read: AtomicUsize,
+    /// The epoch counters of every reader, which are odd while the reader is reading.
+    readers: RwLock<Vec<Arc<AtomicUsize>>>,
+}
+
+// readers only get shared access to the copy that is published, and the one writer only gets
+// mutable access to the other one, once no reader can be looking at it anymore
+unsafe impl<K, V> Sync for Inner<K, V>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+{
+}
+
+/// Creates a new, empty map, and returns the first handle to read it and the handle to write it.
+pub fn new<K, V>() -> (ReadHandle<K, V>, WriteHandle<K, V>)
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    with_capacity(0)
+}
+
+/// Creates a new, empty map with room for at least `capacity` keys in each copy without
+/// reallocating, and returns the first handle to read it and the handle to write it.
+pub fn with_capacity<K, V>(capacity: usize) -> (ReadHandle<K, V>, WriteHandle<K, V>)
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    let inner = Arc::new(Inner {
+        maps: [
+            UnsafeCell::new(HashMap::with_capacity(capacity)),
+            UnsafeCell::new(HashMap::with_capacity(capacity)),
+        ],
+        read: AtomicUsize::new(0),
+        readers: RwLock::new(Vec::new()),
+    });
+
+    let writer = WriteHandle {
+        inner: Arc::clone(&inner),
+        oplog: Vec::new(),
+    };
+    (ReadHandle::register(inner), writer)
+}
+
+/// A handle that reads the map as of the last [`WriteHandle::refresh`].
+///
+/// Reads never wait, not even for a refresh. A `ReadHandle` can be sent to another thread, but
+/// not shared between threads; each thread should clone its own.
+pub struct ReadHandle<K, V> {
+    inner: Arc<Inner<K, V>>,
+    epoch_counter: Arc<AtomicUsize>,
+    /// How many reads through this handle are in progress, counting reads nested in each other.
+    ///
+    /// Only the outermost read enters a critical section, since a nested one leaving its own
+    /// would tell a refresh that the outer read is done too. This also makes the handle `!Sync`:
+    /// two threads reading through it at once would confuse a refresh the same way.
+    depth: Cell<usize>,
+}
+
+/// A read in progress through a [`ReadHandle`].
+struct Reading<'a> {
+    depth: &'a Cell<usize>,
+    _critical: Option<CriticalSection<'a>>,
+}
+
+impl<'a> Reading<'a> {
+    fn enter(depth: &'a Cell<usize>, epoch_counter: &'a AtomicUsize) -> Self {
+        let critical = if depth.get() == 0 {
+            Some(CriticalSection::enter(epoch_counter))
+        } else {
+            None
+        };
+        depth.set(depth.get() + 1);
+        Reading {
+            depth,
+            _critical: critical,
+        }
+    }
+}
+
+impl<'a> Drop for Reading<'a> {
+    fn drop(&mut self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+}
+
+impl<K, V> ReadHandle<K, V> {
+    /// Creates a new read handle to `inner` and adds it to the readers that a refresh waits for.
+    fn register(inner: Arc<Inner<K, V>>) -> Self {
+        let epoch_counter = Arc::new(AtomicUsize::new(0));
+        inner
+            .readers
+            .write()
+            .unwrap()
+            .push(Arc::clone(&epoch_counter));
+        ReadHandle {
+            inner,
+            epoch_counter,
+            depth: Cell::new(0),
+        }
+    }
+
+    /// Calls `f` with the map as of the last refresh, and returns what it returns.
+    ///
+    /// A refresh waits for `f` to return, so `f` should not take long. `f` may read through this
+    /// handle again, in which case the refresh waits for the outermost read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::leftright;
+    ///
+    /// let (reader, mut writer) = leftright::new();
+    /// writer.insert(1, "a");
+    /// writer.insert(2, "b");
+    /// writer.refresh();
+    /// let mut keys = reader.read(|map| map.keys().cloned().collect::<Vec<_>>());
+    /// keys.sort();
+    /// assert_eq!(keys, [1, 2]);
+    /// ```
+    pub fn read<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&HashMap<K, V>) -> T,
+    {
+        let _reading = Reading::enter(&self.depth, &self.epoch_counter);
+        let map = &self.inner.maps[self.inner.read.load(OSC)];
+        f(unsafe { &*map.get() })
+    }
+
+    /// Returns the number of keys in the map as of the last refresh.
+    pub fn len(&self) -> usize {
+        self.read(HashMap::len)
+    }
+
+    /// Returns true if the map was empty as of the last refresh.
+    pub fn is_empty(&self) -> bool {
+        self.read(HashMap::is_empty)
+    }
+}
+
+impl<K, V> ReadHandle<K, V>
+where
+    K: Hash + Eq,
+{
+    /// Calls `f` with the value for `key` as of the last refresh, if there was one, and returns
+    /// what it returns.
+    ///
+    /// Like [`read`](ReadHandle::read), a refresh waits for `f` to return.
+    pub fn get_and<F, T>(&self, key: &K, f: F) -> Option<T>
+    where
+        F: FnOnce(&V) -> T,
+    {
+        self.read(|map| map.get(key).map(f))
+    }
+
+    /// Returns true if the map contained `key` as of the last refresh.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.read(|map| map.contains_key(key))
+    }
+}
+
+impl<K, V> ReadHandle<K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    /// Returns a clone of the value for `key` as of the last refresh, if there was one.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_and(key, V::clone)
+    }
+}
+
+impl<K, V> Clone for ReadHandle<K, V> {
+    fn clone(&self) -> Self {
+        ReadHandle::register(Arc::clone(&self.inner))
+    }
+}
+
+impl<K, V> Drop for ReadHandle<K, V> {
+    fn drop(&mut self) {
+        // stop the writer from waiting on us
+        let mut readers = self.inner.readers.write().unwrap();
+        readers.retain(|r| !Arc::ptr_eq(r, &self.epoch_counter));
+    }
+}
+
+impl<K, V> fmt::Debug for ReadHandle<K, V>
+where
+    K: Hash + Eq + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.read(|map| map.fmt(f))
+    }
+}
+
+/// The handle that changes the map.
+///
+/// There is only one, so writes never contend with each other; threads that all need to write
+/// must share it, for example behind a `Mutex`. Changes are invisible to readers until the next
+/// [`refresh`](WriteHandle::refresh).
+pub struct WriteHandle<K, V> {
+    inner: Arc<Inner<K, V>>,
+    /// The changes that have been made to the copy readers don't use, but not yet to the other.
+    oplog: Vec<Op<K, V>>,
+}
+
+impl<K, V> WriteHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Returns the copy of the map that readers don't use, which only the writer may touch.
+    fn write_side(&mut self) -> &mut HashMap<K, V> {
+        let w = 1 - self.inner.read.load(OSC);
+        unsafe { &mut *self.inner.maps[w].get() }
+    }
+
+    fn apply(&mut self, op: Op<K, V>) {
+        let copy = match op {
+            Op::Insert(ref k, ref v) => Op::Insert(k.clone(), v.clone()),
+            Op::Remove(ref k) => Op::Remove(k.clone()),
+            Op::Clear => Op::Clear,
+        };
+        copy.apply(self.write_side());
+        self.oplog.push(op);
+    }
+
+    /// Sets the value for `key`, as of the next refresh.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.apply(Op::Insert(key, value));
+    }
+
+    /// Removes `key`, as of the next refresh.
+    pub fn remove(&mut self, key: K) {
+        self.apply(Op::Remove(key));
+    }
+
+    /// Removes every key, as of the next refresh.
+    pub fn clear(&mut self) {
+        self.apply(Op::Clear);
+    }
+
+    /// Returns the value for `key` as of the next refresh, that is, including the changes that
+    /// readers don't see yet.
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.write_side().get(key).cloned()
+    }
+
+    /// Returns the number of changes that readers don't see yet.
+    pub fn pending(&self) -> usize {
+        self.oplog.len()
+    }
+
+    /// Makes every change so far visible to readers.
+    ///
+    /// This waits for the readers that are in the middle of a read of the old copy of the map to
+    /// finish it, and then makes the same changes to that copy.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::leftright;
+    ///
+    /// let (reader, mut writer) = leftright::new();
+    /// writer.insert(1, "a");
+    /// assert_eq!(writer.pending(), 1);
+    /// assert_eq!(reader.get(&1), None);
+    /// writer.refresh();
+    /// assert_eq!(writer.pending(), 0);
+    /// assert_eq!(reader.get(&1), Some("a"));
+    /// ```
+    pub fn refresh(&mut self) {
+        if self.oplog.is_empty() {
+            return;
+        }
+
+        let old = self.inner.read.load(OSC);
+        self.inner.read.store(1 - old, OSC);
+        // readers that started before the swap may still be looking at the old copy
+        quiesce(&self.inner.readers.read().unwrap());
+
+        // the old copy is ours now; catch it up with the new one
+        let map = unsafe { &mut *self.inner.maps[old].get() };
+        for op in self.oplog.drain(..) {
+            op.apply(map);
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for WriteHandle<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WriteHandle")
+            .field("pending", &self.oplog.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn leftright_basics() {
+        let (reader, mut writer) = with_capacity(16);
+        assert!(reader.is_empty());
+
+        writer.insert(1, "a".to_owned());
+        writer.insert(2, "b".to_owned());
+        assert_eq!(writer.get(&1), Some("a".to_owned()));
+        assert_eq!(reader.get(&1), None);
+        writer.refresh();
+        assert_eq!(reader.len(), 2);
+        assert_eq!(reader.get_and(&2, |v| v.len()), Some(1));
+
+        writer.remove(1);
+        writer.insert(2, "c".to_owned());
+        // readers still see the last refresh
+        assert_eq!(reader.get(&1), Some("a".to_owned()));
+        writer.refresh();
+        assert!(!reader.contains_key(&1));
+        assert_eq!(reader.get(&2), Some("c".to_owned()));
+
+        // both copies are caught up, whichever one is published
+        writer.insert(3, "d".to_owned());
+        writer.refresh();
+        assert_eq!(reader.len(), 2);
+        assert_eq!(writer.get(&2), Some("c".to_owned()));
+
+        writer.clear();
+        writer.refresh();
+        writer.refresh();
+        assert!(reader.is_empty());
+        assert_eq!(format!("{:?}", reader), "{}");
+        assert_eq!(format!("{:?}", writer), "WriteHandle { pending: 0 }");
+    }
+
+    #[test]
+    fn leftright_snapshots() {
+        // the writer keeps every key at the same value, so a reader that sees a mix of two
+        // refreshes would see two different values
+        let (reader, mut writer) = new();
+        for k in 0..16 {
+            writer.insert(k, 0);
+        }
+        writer.refresh();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let reader = reader.clone();
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while !done.load(OSC) {
+                        let v = reader.read(|map| {
+                            let v = map[&0];
+                            assert!(map.values().all(|&w| w == v));
+                            v
+                        });
+                        assert!(v >= last);
+                        last = v;
+                    }
+                })
+            })
+            .collect();
+
+        for i in 1..200 {
+            for k in 0..16 {
+                writer.insert(k, i);
+            }
+            writer.refresh();
+        }
+        done.store(true, OSC);
+        for r in readers {
+            r.join().unwrap();
+        }
+        assert_eq!(reader.get(&15), Some(199));
+    }
+
+    #[test]
+    fn leftright_nested_read() {
+        let (reader, mut writer) = new();
+        writer.insert(0, 0);
+        writer.refresh();
+
+        let (started, wait) = mpsc::channel();
+        let nested = thread::spawn(move || {
+            reader.read(|_| {
+                reader.get_and(&0, |v| {
+                    started.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(100));
+                    // no refresh may have changed the copy we are reading
+                    assert_eq!(*v, 0);
+                })
+            })
+        });
+
+        wait.recv().unwrap();
+        for i in 1..3 {
+            writer.insert(0, i);
+            writer.refresh();
+        }
+        nested.join().unwrap().unwrap();
+    }
+}
@@ -7,6 +7,24 @@
 //! Reclamation_ implementation. See the [`crossbeam`] and [`manual`] module documentations
 //! respectively for further details.
 //!
+//! Each map also comes with a concurrent hash set, [`crossbeam::Set`] and [`manual::Set`], whose
+//! `()` values are stored in the nodes themselves and so take no space of their own. The crossbeam
+//! one is re-exported as [`Set`]. [`crossbeam::MultiMap`] maps each key to a set of values, for
+//! one-to-many indexes.
+//!
+//! The [`skiplist`] module provides a concurrent map that keeps its keys in order, for range
+//! queries, built with the same techniques as the [`manual`] map.
+//!
+//! The [`leftright`] module provides a map for data that is read far more often than it is
+//! written, whose readers never wait and see changes only once the writer publishes them.
+//!
+//! [`Interner`] maps strings to small [`Symbol`]s and back, for programs that pass many copies of
+//! the same identifiers around.
+//!
+//! The [`cache`] module builds a concurrent cache with a bounded number of entries on top of the
+//! [`crossbeam`] map. [`Memo`] memoizes a function, keeping its results in either map or in a
+//! cache.
+//!
 //! Table resizing is not yet supported in either implementation, but the map will also never fill
 //! due to the linked implementation; instead, performance will decrease as the map is filled with
 //! more keys.
@@ -25,5 +43,19 @@ extern crate rand;
 #[cfg(feature = "bench")]
 extern crate test;
 
+mod action;
+mod flight;
+mod inline;
+mod integer;
+mod interner;
+mod memo;
+pub use integer::Integer;
+pub use interner::{Interner, Symbol};
+pub use memo::{Memo, MemoStore};
+
+pub mod cache;
 pub mod crossbeam;
+pub mod leftright;
 pub mod manual;
+pub mod skiplist;
+pub use crossbeam::Set;
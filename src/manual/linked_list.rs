@@ -1,10 +1,71 @@
+use action::Action;
+use inline;
 use std::ptr;
 use std::sync::atomic::{AtomicPtr, Ordering};
 
 const OSC: Ordering = Ordering::SeqCst;
 
+/// Turns `val` into what a node's `val` holds for it: the value itself if it can be stored inline,
+/// and otherwise a pointer to a new allocation holding it.
+pub(crate) fn into_slot<V: 'static>(val: V) -> *mut V {
+    match inline::encode(&val) {
+        Some(word) => word as *mut V,
+        None => Box::into_raw(Box::new(val)),
+    }
+}
+
+/// Reads the value out of a non-null slot made by `into_slot`.
+pub(crate) unsafe fn read_slot<V: Copy + 'static>(slot: *mut V) -> V {
+    if inline::is_inline::<V>(slot as usize) {
+        inline::decode(slot as usize)
+    } else {
+        *slot
+    }
+}
+
+/// Returns true if a non-null slot made by `into_slot` points to an allocation, which has to be
+/// freed once the slot is no longer reachable.
+pub(crate) fn is_boxed_slot<V: 'static>(slot: *mut V) -> bool {
+    !inline::is_inline::<V>(slot as usize)
+}
+
+/// Frees a slot made by `into_slot` that is no longer reachable.
+pub(crate) unsafe fn free_slot<V: 'static>(slot: *mut V) {
+    if is_boxed_slot(slot) {
+        drop(Box::from_raw(slot));
+    }
+}
+
+/// Returns true if `ptr` carries the mark that says the node holding it is being deleted.
+///
+/// Nodes are at least 2-byte aligned, so the low bit of a pointer to one is free to use as the
+/// mark, as in [Harris' list](https://www.microsoft.com/en-us/research/wp-content/uploads/2001/10/2001-disc.pdf).
+pub(crate) fn is_marked_reference<T>(ptr: *mut T) -> bool {
+    (ptr as usize & 0x1) == 1
+}
+
+/// Returns `ptr` with the deletion mark set.
+pub(crate) fn get_marked_reference<T>(ptr: *mut T) -> *mut T {
+    (ptr as usize | 0x1) as *mut _
+}
+
+/// Returns `ptr` without the deletion mark, so that it can be followed.
+pub(crate) fn get_unmarked_reference<T>(ptr: *mut T) -> *mut T {
+    (ptr as usize & !0x1) as *mut _
+}
+
+/// A node in a bucket's list.
+///
+/// Lists are kept sorted by the full hash of their keys, which lets searches stop early without
+/// requiring keys to be `Ord`. Keys with equal hashes are kept in no particular order, and are
+/// told apart by `Eq` alone.
+///
+/// A key is removed from the map the moment its node's value is swapped for null; every change
+/// to a key is a compare-and-swap on `val`. Only after that is the node marked, through the low
+/// bit of `next`, and unlinked.
 #[derive(Debug)]
-pub(super) struct Node<K, V> {
+pub(super) struct Node<K, V: 'static> {
+    hash: u64,
     key: Option<K>,
     pub val: AtomicPtr<V>,
     next: AtomicPtr<Node<K, V>>,
@@ -13,24 +74,46 @@ pub(super) struct Node<K, V> {
 impl<K, V> Node<K, V> {
     fn empty() -> Self {
         Node {
+            hash: 0,
             key: None,
             val: AtomicPtr::new(ptr::null_mut()),
             next: AtomicPtr::new(ptr::null_mut()),
         }
     }
 
-    fn new(key: K, val: V) -> Self {
-        let v = Box::new(val);
+    fn new(hash: u64, key: K, val: V) -> Self {
         Node {
+            hash,
             key: Some(key),
-            val: AtomicPtr::new(Box::into_raw(v)),
+            val: AtomicPtr::new(into_slot(val)),
             next: AtomicPtr::new(ptr::null_mut()),
         }
     }
 }
 
+impl<K, V> Node<K, V>
+where
+    K: Eq,
+{
+    /// Returns true if this node holds `key`, whose hash is `hash`.
+    fn holds(&self, hash: u64, key: &K) -> bool {
+        self.hash == hash && self.key.as_ref().map(|k| k == key).unwrap_or(false)
+    }
+}
+
+/// The outcome of a change to a key.
+#[derive(Debug)]
+pub(crate) struct Change<V> {
+    /// The key's value before the change, if it was in the map.
+    pub old: Option<V>,
+    /// The key's value after the change, if it is still in the map.
+    pub new: Option<V>,
+    /// The slot of the old value, if it was replaced or removed, which the caller must retire.
+    pub retired: Option<*mut V>,
+}
+
 #[derive(Debug)]
-pub(super) struct LinkedList<K, V> {
+pub(super) struct LinkedList<K, V: 'static> {
     head: AtomicPtr<Node<K, V>>,
     tail: AtomicPtr<Node<K, V>>,
 }
@@ -50,32 +133,61 @@ impl<K, V> Default for LinkedList<K, V> {
 
 impl<K, V> LinkedList<K, V>
 where
-    K: Ord,
+    K: Eq,
     V: Copy,
 {
+    /// Inserts `key` with `val`, replacing the current value if there is one and `overwrite` is
+    /// set.
     pub(super) fn insert(
         &self,
+        hash: u64,
         key: K,
         val: V,
+        overwrite: bool,
         remove_nodes: &mut Vec<*mut Node<K, V>>,
-    ) -> Option<*mut V> {
-        let mut new_node = Box::new(Node::new(key, val));
+    ) -> Change<V> {
+        let mut new_node = Box::new(Node::new(hash, key, val));
         let mut left_node = ptr::null_mut();
 
         loop {
-            let right_node =
-                self.search(new_node.key.as_ref().unwrap(), &mut left_node, remove_nodes);
-
-            if right_node != self.tail.load(OSC) && unsafe { &*right_node }
-                .key
-                .as_ref()
-                .map(|k| k == new_node.key.as_ref().unwrap())
-                .unwrap_or(false)
+            let right_node = self.search(
+                hash,
+                new_node.key.as_ref().unwrap(),
+                &mut left_node,
+                remove_nodes,
+            );
+
+            if right_node != self.tail.load(OSC)
+                && unsafe { &*right_node }.holds(hash, new_node.key.as_ref().unwrap())
             {
                 let rn = unsafe { &*right_node };
-                let v = Box::new(val);
-                let old = rn.val.swap(Box::into_raw(v), OSC);
-                return Some(old);
+                let old = rn.val.load(OSC);
+                if old.is_null() {
+                    // the node is being deleted; finish that and try again
+                    Self::mark(rn);
+                    continue;
+                }
+
+                let current = unsafe { read_slot(old) };
+                if !overwrite {
+                    unsafe { free_slot(new_node.val.load(OSC)) };
+                    return Change {
+                        old: Some(current),
+                        new: Some(current),
+                        retired: None,
+                    };
+                }
+
+                // hand the value we already allocated over to the existing node
+                let v = new_node.val.load(OSC);
+                if rn.val.compare_exchange(old, v, OSC, OSC).is_ok() {
+                    return Change {
+                        old: Some(current),
+                        new: Some(val),
+                        retired: Some(old),
+                    };
+                }
+                continue;
             }
 
             new_node.next.store(right_node, OSC);
@@ -83,92 +195,232 @@ where
             let new_node_ptr = Box::into_raw(new_node);
             if unsafe { &*left_node }
                 .next
-                .compare_and_swap(right_node, new_node_ptr, OSC)
-                == right_node
+                .compare_exchange(right_node, new_node_ptr, OSC, OSC)
+                .is_ok()
             {
-                return None;
+                return Change {
+                    old: None,
+                    new: Some(val),
+                    retired: None,
+                };
             }
             new_node = unsafe { Box::from_raw(new_node_ptr) };
         }
     }
 
-    pub(super) fn get(&self, search_key: &K, remove_nodes: &mut Vec<*mut Node<K, V>>) -> Option<V> {
+    pub(super) fn get(
+        &self,
+        hash: u64,
+        search_key: &K,
+        remove_nodes: &mut Vec<*mut Node<K, V>>,
+    ) -> Option<V> {
         let mut left_node = ptr::null_mut();
-        let right_node = self.search(&search_key, &mut left_node, remove_nodes);
-        if right_node == self.tail.load(OSC) || unsafe { &*right_node }
-            .key
-            .as_ref()
-            .map(|k| k != search_key)
-            .unwrap_or(true)
-        {
+        let right_node = self.search(hash, search_key, &mut left_node, remove_nodes);
+        if right_node == self.tail.load(OSC) || !unsafe { &*right_node }.holds(hash, search_key) {
             None
         } else {
-            unsafe { Some(*(&*right_node).val.load(OSC)) }
+            let v = unsafe { &*right_node }.val.load(OSC);
+            if v.is_null() {
+                None
+            } else {
+                unsafe { Some(read_slot(v)) }
+            }
+        }
+    }
+
+    /// Calls `f` with every key in the list and its value, without unlinking anything. Keys that
+    /// are inserted or removed during the walk may or may not be seen. The caller must be in a
+    /// critical section.
+    pub(super) fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, V),
+    {
+        let tail = self.tail.load(OSC);
+        let mut t = get_unmarked_reference(unsafe { &*self.head.load(OSC) }.next.load(OSC));
+        while t != tail {
+            let n = unsafe { &*t };
+            let next = n.next.load(OSC);
+            let v = n.val.load(OSC);
+            if !is_marked_reference(next) && !v.is_null() {
+                if let Some(ref k) = n.key {
+                    f(k, unsafe { read_slot(v) });
+                }
+            }
+            t = get_unmarked_reference(next);
         }
     }
 
     pub(super) fn delete(
         &self,
+        hash: u64,
         search_key: &K,
         remove_nodes: &mut Vec<*mut Node<K, V>>,
-    ) -> Option<V> {
+    ) -> Change<V> {
+        self.compute(
+            hash,
+            search_key,
+            || unreachable!("delete never inserts"),
+            |_| Action::Remove,
+            remove_nodes,
+        )
+    }
+
+    /// Atomically applies the [`Action`] that `f` picks given the current value for `key`, or
+    /// `None` if the key is not in the map.
+    ///
+    /// `new_key` is called for the key of the new node if the key has to be inserted. `f` may be
+    /// called more than once if other threads change the key at the same time.
+    pub(super) fn compute<F, G>(
+        &self,
+        hash: u64,
+        key: &K,
+        mut new_key: G,
+        mut f: F,
+        remove_nodes: &mut Vec<*mut Node<K, V>>,
+    ) -> Change<V>
+    where
+        F: FnMut(Option<&V>) -> Action<V>,
+        G: FnMut() -> K,
+    {
         let mut left_node = ptr::null_mut();
-        let mut right_node;
-        let mut right_node_next;
 
         loop {
-            right_node = self.search(search_key, &mut left_node, remove_nodes);
-            if (right_node == self.tail.load(OSC)) || unsafe { &*right_node }
-                .key
-                .as_ref()
-                .map(|k| k != search_key)
-                .unwrap_or(true)
-            {
-                return None; //failed delete
+            let right_node = self.search(hash, key, &mut left_node, remove_nodes);
+
+            if right_node != self.tail.load(OSC) && unsafe { &*right_node }.holds(hash, key) {
+                let rn = unsafe { &*right_node };
+                let old = rn.val.load(OSC);
+                if old.is_null() {
+                    Self::mark(rn);
+                    continue;
+                }
+
+                let current = unsafe { read_slot(old) };
+                match f(Some(&current)) {
+                    Action::Keep => {
+                        return Change {
+                            old: Some(current),
+                            new: Some(current),
+                            retired: None,
+                        };
+                    }
+                    Action::Set(new) => {
+                        let v = into_slot(new);
+                        if rn.val.compare_exchange(old, v, OSC, OSC).is_ok() {
+                            return Change {
+                                old: Some(current),
+                                new: Some(new),
+                                retired: Some(old),
+                            };
+                        }
+                        unsafe { free_slot(v) };
+                    }
+                    Action::Remove => {
+                        if self.remove_node(hash, key, left_node, right_node, old, remove_nodes) {
+                            return Change {
+                                old: Some(current),
+                                new: None,
+                                retired: Some(old),
+                            };
+                        }
+                    }
+                }
+                continue;
             }
-            right_node_next = unsafe { &*right_node }.next.load(OSC);
-            if !Self::is_marked_reference(right_node_next)
-                && unsafe { &*right_node }.next.compare_and_swap(
-                    right_node_next,
-                    Self::get_marked_reference(right_node_next),
-                    OSC,
-                ) == right_node_next
+
+            let new = match f(None) {
+                Action::Set(new) => new,
+                Action::Keep | Action::Remove => {
+                    return Change {
+                        old: None,
+                        new: None,
+                        retired: None,
+                    };
+                }
+            };
+
+            let new_node = Box::new(Node::new(hash, new_key(), new));
+            new_node.next.store(right_node, OSC);
+
+            let new_node_ptr = Box::into_raw(new_node);
+            if unsafe { &*left_node }
+                .next
+                .compare_exchange(right_node, new_node_ptr, OSC, OSC)
+                .is_ok()
             {
-                break;
+                return Change {
+                    old: None,
+                    new: Some(new),
+                    retired: None,
+                };
             }
+
+            let new_node = unsafe { Box::from_raw(new_node_ptr) };
+            unsafe { free_slot(new_node.val.load(OSC)) };
         }
+    }
 
-        //get value to return
+    /// Deletes `right_node`, found by searching for `key` and preceded by `left_node`, if its
+    /// value is still `old`.
+    ///
+    /// Replacing the value with null is what removes the key from the map. The node is then marked
+    /// and unlinked; `old` is left for the caller to retire.
+    fn remove_node(
+        &self,
+        hash: u64,
+        key: &K,
+        mut left_node: *mut Node<K, V>,
+        right_node: *mut Node<K, V>,
+        old: *mut V,
+        remove_nodes: &mut Vec<*mut Node<K, V>>,
+    ) -> bool {
         let rn = unsafe { &*right_node };
-        let old = unsafe { *rn.val.load(OSC) };
+        if rn
+            .val
+            .compare_exchange(old, ptr::null_mut(), OSC, OSC)
+            .is_err()
+        {
+            return false;
+        }
+
+        Self::mark(rn);
+        let right_node_next = get_unmarked_reference(rn.next.load(OSC));
 
         if unsafe { &*left_node }
             .next
-            .compare_and_swap(right_node, right_node_next, OSC)
-            != right_node
+            .compare_exchange(right_node, right_node_next, OSC, OSC)
+            .is_ok()
         {
-            let _ = self.search(
-                unsafe { &*right_node }.key.as_ref().unwrap(),
-                &mut left_node,
-                remove_nodes,
-            );
+            // we unlinked the node ourselves, so no search will come across it to retire it
+            remove_nodes.push(right_node);
+        } else {
+            let _ = self.search(hash, key, &mut left_node, remove_nodes);
         }
 
-        Some(old) //successful delete
+        true
     }
 
-    fn is_marked_reference(ptr: *mut Node<K, V>) -> bool {
-        (ptr as usize & 0x1) == 1
-    }
-    fn get_marked_reference(ptr: *mut Node<K, V>) -> *mut Node<K, V> {
-        (ptr as usize | 0x1) as *mut _
-    }
-    fn get_unmarked_reference(ptr: *mut Node<K, V>) -> *mut Node<K, V> {
-        (ptr as usize & !0x1) as *mut _
+    /// Marks `node` as deleted, which allows it to be unlinked. Its value must already be null.
+    fn mark(node: &Node<K, V>) {
+        loop {
+            let next = node.next.load(OSC);
+            if is_marked_reference(next)
+                || node
+                    .next
+                    .compare_exchange(next, get_marked_reference(next), OSC, OSC)
+                    .is_ok()
+            {
+                return;
+            }
+        }
     }
 
+    /// Finds the first unmarked node that either holds `search_key` or has a hash greater than
+    /// `hash`, along with the unmarked node immediately before it, unlinking any marked nodes
+    /// found between the two.
     fn search(
         &self,
+        hash: u64,
         search_key: &K,
         left_node: &mut *mut Node<K, V>,
         remove_nodes: &mut Vec<*mut Node<K, V>>,
@@ -183,21 +435,19 @@ where
 
             /* 1: Find left_node and right_node */
             loop {
-                if !Self::is_marked_reference(t_next) {
+                if !is_marked_reference(t_next) {
                     *left_node = t;
                     left_node_next = t_next;
                 }
-                t = Self::get_unmarked_reference(t_next);
+                t = get_unmarked_reference(t_next);
                 if t == self.tail.load(OSC) {
                     break;
                 }
                 t_next = unsafe { &*t }.next.load(OSC);
-                if !Self::is_marked_reference(t_next) && unsafe { &*t }
-                    .key
-                    .as_ref()
-                    .map(|k| k >= search_key)
-                    .unwrap_or(false)
-                {
+                if !is_marked_reference(t_next) && {
+                    let n = unsafe { &*t };
+                    n.hash > hash || n.holds(hash, search_key)
+                } {
                     break;
                 }
             }
@@ -206,7 +456,7 @@ where
             /* 2: Check nodes are adjacent */
             if left_node_next == right_node {
                 if right_node != self.tail.load(OSC)
-                    && Self::is_marked_reference(unsafe { &*right_node }.next.load(OSC))
+                    && is_marked_reference(unsafe { &*right_node }.next.load(OSC))
                 {
                     continue 'search_again;
                 } else {
@@ -217,8 +467,8 @@ where
             /* 3: Remove one or more marked nodes */
             if unsafe { &**left_node }
                 .next
-                .compare_and_swap(left_node_next, right_node, OSC)
-                == left_node_next
+                .compare_exchange(left_node_next, right_node, OSC, OSC)
+                .is_ok()
             {
                 //drop all of the Nodes that we crossed over,
                 //we know nothing inside can be modified so we can just drop all of them with
@@ -230,19 +480,19 @@ where
 
                 loop {
                     //start with left_node_next, then go to on until the right_node, but do use that one
-                    assert_eq!(Self::is_marked_reference(curr_node), false);
+                    assert!(!is_marked_reference(curr_node));
                     remove_nodes.push(curr_node);
                     curr_node = unsafe { &*curr_node }.next.load(OSC);
-                    assert_eq!(Self::is_marked_reference(curr_node), true);
-                    curr_node = Self::get_unmarked_reference(curr_node); //we need unmarked to deref and comp to right_node
-                                                                         // println!("curr_node: {:?}", curr_node);
+                    assert!(is_marked_reference(curr_node));
+                    curr_node = get_unmarked_reference(curr_node); //we need unmarked to deref and comp to right_node
+                                                                   // println!("curr_node: {:?}", curr_node);
                     if curr_node == right_node {
                         break;
                     }
                 }
 
                 if right_node != self.tail.load(OSC)
-                    && Self::is_marked_reference(unsafe { &*right_node }.next.load(OSC))
+                    && is_marked_reference(unsafe { &*right_node }.next.load(OSC))
                 {
                     continue 'search_again;
                 } else {
@@ -264,17 +514,17 @@ mod tests {
         let new_linked_list = LinkedList::default();
 
         println!("{:?}", new_linked_list);
-        new_linked_list.insert(3, 2, &mut remove_nodes);
-        new_linked_list.insert(3, 4, &mut remove_nodes);
-        new_linked_list.insert(5, 8, &mut remove_nodes);
-        new_linked_list.insert(4, 6, &mut remove_nodes);
-        new_linked_list.insert(1, 8, &mut remove_nodes);
-        new_linked_list.insert(6, 6, &mut remove_nodes);
+        new_linked_list.insert(3, 3, 2, true, &mut remove_nodes);
+        new_linked_list.insert(3, 3, 4, true, &mut remove_nodes);
+        new_linked_list.insert(5, 5, 8, true, &mut remove_nodes);
+        new_linked_list.insert(4, 4, 6, true, &mut remove_nodes);
+        new_linked_list.insert(1, 1, 8, true, &mut remove_nodes);
+        new_linked_list.insert(6, 6, 6, true, &mut remove_nodes);
         //new_linked_list.print();
 
-        assert_eq!(new_linked_list.get(&3, &mut remove_nodes).unwrap(), 4);
-        assert_eq!(new_linked_list.get(&5, &mut remove_nodes).unwrap(), 8);
-        assert_eq!(new_linked_list.get(&2, &mut remove_nodes), None);
+        assert_eq!(new_linked_list.get(3, &3, &mut remove_nodes).unwrap(), 4);
+        assert_eq!(new_linked_list.get(5, &5, &mut remove_nodes).unwrap(), 8);
+        assert_eq!(new_linked_list.get(2, &2, &mut remove_nodes), None);
     }
 
     #[test]
@@ -284,24 +534,62 @@ mod tests {
         let new_linked_list = LinkedList::default();
         println!(
             "Insert: {:?}",
-            new_linked_list.insert(5, 3, &mut remove_nodes)
+            new_linked_list.insert(5, 5, 3, true, &mut remove_nodes)
         );
         println!(
             "Insert: {:?}",
-            new_linked_list.insert(5, 8, &mut remove_nodes)
+            new_linked_list.insert(5, 5, 8, true, &mut remove_nodes)
         );
         println!(
             "Insert: {:?}",
-            new_linked_list.insert(2, 3, &mut remove_nodes)
+            new_linked_list.insert(2, 2, 3, true, &mut remove_nodes)
         );
 
-        println!("Get: {:?}", new_linked_list.get(&5, &mut remove_nodes));
+        println!("Get: {:?}", new_linked_list.get(5, &5, &mut remove_nodes));
 
         // println!("{:?}", new_linked_list.head.load(OSC));
         // new_linked_list.print();
 
-        new_linked_list.delete(&5, &mut remove_nodes);
+        new_linked_list.delete(5, &5, &mut remove_nodes);
 
         // new_linked_list.print();
     }
+
+    #[test]
+    fn linked_list_collisions() {
+        let mut remove_nodes = Vec::new();
+
+        // keys that are not ordered by their hashes, and that all share one hash
+        let new_linked_list = LinkedList::default();
+        for k in &[4, 1, 3, 0, 2] {
+            assert_eq!(
+                new_linked_list
+                    .insert(7, *k, *k, true, &mut remove_nodes)
+                    .old,
+                None
+            );
+        }
+        new_linked_list.insert(3, 10, 10, true, &mut remove_nodes);
+        new_linked_list.insert(9, 11, 11, true, &mut remove_nodes);
+
+        for k in 0..5 {
+            assert_eq!(new_linked_list.get(7, &k, &mut remove_nodes), Some(k));
+        }
+        assert_eq!(new_linked_list.get(7, &10, &mut remove_nodes), None);
+        assert_eq!(new_linked_list.get(3, &10, &mut remove_nodes), Some(10));
+
+        assert_eq!(new_linked_list.get(7, &3, &mut remove_nodes), Some(3));
+        assert!(new_linked_list
+            .insert(7, 3, 30, true, &mut remove_nodes)
+            .old
+            .is_some());
+        assert_eq!(
+            new_linked_list.delete(7, &1, &mut remove_nodes).old,
+            Some(1)
+        );
+        assert_eq!(new_linked_list.delete(7, &1, &mut remove_nodes).old, None);
+        assert_eq!(new_linked_list.get(7, &3, &mut remove_nodes), Some(30));
+        assert_eq!(new_linked_list.get(7, &4, &mut remove_nodes), Some(4));
+        assert_eq!(new_linked_list.get(9, &11, &mut remove_nodes), Some(11));
+    }
 }
@@ -9,32 +9,128 @@
 //! The interface to this map is somewhat different from `HashMap` to support concurrent operation.
 //! When you create a new [`Map`],you are given a [`MapHandle`], which allows access to the map's
 //! data. To read or mutate the map for elsewhere, you call [`MapHandle::clone`], which gives you
-//! a new `MapHandle` that provides concurrent access to the same map.
+//! a new `MapHandle` that provides concurrent access to the same map. If you would rather share a
+//! single handle between threads, for example in an `Arc` or a `static`, use
+//! [`MapHandle::sync_handle`] to get a [`SyncMapHandle`].
 //!
 //! Similarly to [`crossbeam::epoch`](https://docs.rs/crossbeam-epoch/), this `Map` does not
 //! guarantee that destructors are called. In practice though, as long as threads do not leak
 //! `MapHandle`s, destructors will all eventually be called.
 //!
+//! Each `MapHandle` holds on to the memory it unlinks from the map until it can prove that no
+//! other handle is still looking at it. How often it does so is controlled by a [`Reclamation`]
+//! policy, which can be set for the whole map with [`Map::with_reclamation`] or for a single
+//! handle with [`MapHandle::set_reclamation`]. [`MapHandle::flush`] reclaims immediately.
+//!
+//! Keys only need to be `Hash + Eq`: each bucket is kept sorted by the full hash of its keys,
+//! much like in a split-ordered list, and keys with equal hashes are told apart by `Eq`.
+//!
 //! Note that unlike `HashMap`, this `Map` requires its values to be `Copy`. This greatly
 //! simplifies the map's interface; accesses to the map's data have to be carefully guarded, and
 //! there is no simple way to expose references into the map through a method call. Later, we may
 //! provide temporary access through closures, similar to `evmap`'s
 //! [`ReadHandle::get_and`](https://docs.rs/evmap/4/evmap/struct.ReadHandle.html#method.get_and),
 //! but for the time being, values have to be `Copy`.
+//! Values also have to be `'static`, which is what lets the map tell the integers that it stores
+//! inline apart from other values.
+//!
+//! Changes to a key can also be made atomically with [`MapHandle::update`], or only under a
+//! condition with [`MapHandle::insert_if_absent`], [`MapHandle::replace`] and
+//! [`MapHandle::remove_if`]. Maps of integers can be used as counters through
+//! [`MapHandle::fetch_add`] and friends. Integers that fit in a
+//! word are stored directly in the map's nodes, so updating them does not allocate.
+//!
+//! Missing values can be filled in with [`MapHandle::get_or_insert_with`], which makes sure that
+//! only one thread computes the value for a key that many threads miss on at once.
 
 use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 
 mod linked_list;
+pub(crate) use self::linked_list::{
+    free_slot, get_marked_reference, get_unmarked_reference, into_slot, is_boxed_slot,
+    is_marked_reference, read_slot, Change,
+};
 use self::linked_list::{LinkedList, Node};
+use self::sync::Participant;
+use action::Action;
+use flight::{self, Flights};
+use Integer;
+
+mod set;
+mod sync;
+pub use self::set::{Set, SetIter};
+pub use self::sync::SyncMapHandle;
 
 const OSC: Ordering = Ordering::SeqCst;
 const REFRESH_RATE: usize = 1000;
 
-struct Table<K, V> {
+/// Gives every map a distinct id, which `SyncMapHandle` uses to find its thread-local state.
+static NEXT_MAP_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Controls when a [`MapHandle`] reclaims the memory it has retired.
+///
+/// Reclaiming requires waiting for every other handle of the map to leave any operation it is in
+/// the middle of, so it is not free. Handles count the operations they perform, and reclaim once
+/// `interval` counted operations have happened since the last reclamation. Setting `interval` to
+/// `None` turns inline reclamation off entirely, and memory is then only freed by
+/// [`MapHandle::flush`].
+///
+/// The default reclaims every 1000 reads or removals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reclamation {
+    /// Number of counted operations between reclamations, or `None` to only reclaim on `flush`.
+    pub interval: Option<usize>,
+    /// Whether [`MapHandle::get`] counts toward `interval`.
+    pub count_gets: bool,
+    /// Whether [`MapHandle::insert`] counts toward `interval`.
+    pub count_inserts: bool,
+    /// Whether [`MapHandle::remove`] counts toward `interval`.
+    pub count_removes: bool,
+}
+
+impl Default for Reclamation {
+    fn default() -> Self {
+        Reclamation {
+            interval: Some(REFRESH_RATE),
+            count_gets: true,
+            count_inserts: false,
+            count_removes: true,
+        }
+    }
+}
+
+impl Reclamation {
+    /// A policy that reclaims after every `n` operations of any kind.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn every(n: usize) -> Self {
+        assert!(n > 0, "reclamation interval must be at least 1");
+        Reclamation {
+            interval: Some(n),
+            count_gets: true,
+            count_inserts: true,
+            count_removes: true,
+        }
+    }
+
+    /// A policy that never reclaims inline; memory is only freed by [`MapHandle::flush`].
+    pub fn manual() -> Self {
+        Reclamation {
+            interval: None,
+            ..Default::default()
+        }
+    }
+}
+
+struct Table<K, V: 'static> {
     nbuckets: usize,
     map: Vec<LinkedList<K, V>>,
     nitems: AtomicUsize,
@@ -58,46 +154,123 @@ impl<K, V> Table<K, V> {
 
 impl<K, V> Table<K, V>
 where
-    K: Hash + Ord,
+    K: Hash + Eq,
     V: Copy,
 {
-    fn insert(&self, key: K, value: V, remove_nodes: &mut Vec<*mut Node<K, V>>) -> Option<*mut V> {
+    fn hash(key: &K) -> u64 {
         let mut hasher = DefaultHasher::new();
         key.hash(&mut hasher);
-        let hash: usize = hasher.finish() as usize;
-        let index = hash % self.nbuckets;
-
-        let ret = self.map[index].insert(key, value, remove_nodes);
+        hasher.finish()
+    }
 
-        if ret.is_none() {
-            self.nitems.fetch_add(1, OSC);
-        }
+    fn bucket(&self, hash: u64) -> &LinkedList<K, V> {
+        &self.map[hash as usize % self.nbuckets]
+    }
 
+    fn insert(
+        &self,
+        key: K,
+        value: V,
+        overwrite: bool,
+        remove_nodes: &mut Vec<*mut Node<K, V>>,
+    ) -> Change<V> {
+        let hash = Self::hash(&key);
+        let ret = self
+            .bucket(hash)
+            .insert(hash, key, value, overwrite, remove_nodes);
+        self.count(&ret);
         ret
     }
 
     fn get(&self, key: &K, remove_nodes: &mut Vec<*mut Node<K, V>>) -> Option<V> {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash: usize = hasher.finish() as usize;
-        let index = hash % self.nbuckets;
-
-        self.map[index].get(key, remove_nodes)
+        let hash = Self::hash(key);
+        self.bucket(hash).get(hash, key, remove_nodes)
     }
 
-    fn delete(&self, key: &K, remove_nodes: &mut Vec<*mut Node<K, V>>) -> Option<V> {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash: usize = hasher.finish() as usize;
-        let index = hash % self.nbuckets;
+    fn delete(&self, key: &K, remove_nodes: &mut Vec<*mut Node<K, V>>) -> Change<V> {
+        let hash = Self::hash(key);
+        let ret = self.bucket(hash).delete(hash, key, remove_nodes);
+        self.count(&ret);
+        ret
+    }
 
-        let ret = self.map[index].delete(key, remove_nodes);
+    fn compute<F, G>(
+        &self,
+        key: &K,
+        new_key: G,
+        f: F,
+        remove_nodes: &mut Vec<*mut Node<K, V>>,
+    ) -> Change<V>
+    where
+        F: FnMut(Option<&V>) -> Action<V>,
+        G: FnMut() -> K,
+    {
+        let hash = Self::hash(key);
+        let ret = self
+            .bucket(hash)
+            .compute(hash, key, new_key, f, remove_nodes);
+        self.count(&ret);
+        ret
+    }
 
-        if ret.is_some() {
-            self.nitems.fetch_sub(1, OSC);
+    /// Keeps `nitems` up to date with a change made to the table.
+    fn count(&self, change: &Change<V>) {
+        match (&change.old, &change.new) {
+            (None, Some(_)) => {
+                self.nitems.fetch_add(1, OSC);
+            }
+            (Some(_), None) => {
+                self.nitems.fetch_sub(1, OSC);
+            }
+            _ => {}
         }
+    }
+}
 
-        ret
+/// Marks a handle as being in the middle of an operation for as long as it is alive.
+///
+/// A handle's epoch counter is odd while it may be holding pointers into the map, and `cleanup`
+/// waits for every odd counter to move on. Leaving the critical section on drop means the counter
+/// is made even again even if a `Hash` or `Eq` implementation panics partway through, instead of
+/// leaving every other handle spinning forever.
+pub(crate) struct CriticalSection<'a>(&'a AtomicUsize);
+
+impl<'a> CriticalSection<'a> {
+    pub(crate) fn enter(epoch_counter: &'a AtomicUsize) -> Self {
+        epoch_counter.fetch_add(1, OSC);
+        CriticalSection(epoch_counter)
+    }
+}
+
+impl<'a> Drop for CriticalSection<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, OSC);
+    }
+}
+
+/// Waits until every handle whose epoch counter is in `handles` has left the critical section it
+/// was in, if any, when this was called.
+///
+/// Memory that was unlinked before the call can then no longer be reached by any handle, and is
+/// safe to free.
+pub(crate) fn quiesce(handles: &[Arc<AtomicUsize>]) {
+    //epoch set up, load all of the values
+    let started: Vec<_> = handles.iter().map(|h| h.load(OSC)).collect();
+    for (i, h) in handles.iter().enumerate() {
+        if started[i] % 2 == 0 {
+            continue;
+        }
+        let mut check = h.load(OSC);
+        let mut iter = 0;
+        while (check <= started[i]) && (check % 2 == 1) {
+            if iter % 4 == 0 {
+                // we may be waiting for a thread that isn't currently running
+                thread::yield_now();
+            }
+            check = h.load(OSC);
+            iter += 1;
+            //do nothing, epoch spinning
+        }
     }
 }
 
@@ -105,12 +278,13 @@ where
 ///
 /// Any operation performed on this handle affects the map seen by all other related `MapHandle`
 /// instances. To get another handle to the `Map`, simply clone any of its handles.
-pub struct MapHandle<K, V> {
+pub struct MapHandle<K, V: 'static> {
     map: Arc<Map<K, V>>,
     epoch_counter: Arc<AtomicUsize>,
     remove_nodes: Vec<*mut Node<K, V>>,
     remove_val: Vec<*mut V>,
     refresh: usize,
+    reclamation: Reclamation,
 }
 
 unsafe impl<K, V> Send for MapHandle<K, V>
@@ -121,57 +295,147 @@ where
 }
 
 impl<K, V> MapHandle<K, V> {
-    fn cleanup(&mut self) {
-        //epoch set up, load all of the values
-        let mut started = Vec::new();
-        let handles_map = self.map.handles.read().unwrap();
-        for h in handles_map.iter() {
-            started.push(h.load(OSC));
-        }
-        for (i, h) in handles_map.iter().enumerate() {
-            if started[i] % 2 == 0 {
-                continue;
-            }
-            let mut check = h.load(OSC);
-            let mut iter = 0;
-            while (check <= started[i]) && (check % 2 == 1) {
-                if iter % 4 == 0 {
-                    // we may be waiting for a thread that isn't currently running
-                    thread::yield_now();
-                }
-                check = h.load(OSC);
-                iter += 1;
-                //do nothing, epoch spinning
+    /// Creates a new handle to `map` and adds it to the map's epoch system.
+    fn register(map: Arc<Map<K, V>>) -> Self {
+        let ret = MapHandle {
+            epoch_counter: Arc::new(AtomicUsize::new(0)),
+            remove_nodes: Vec::new(),
+            remove_val: Vec::new(),
+            refresh: 0,
+            reclamation: map.reclamation,
+            map,
+        };
+
+        let mut handles_vec = ret.map.handles.write().unwrap(); //handles vector
+        handles_vec.push(Arc::clone(&ret.epoch_counter));
+        drop(handles_vec);
+
+        ret
+    }
+
+    /// Returns a handle to the same map that can be shared between threads.
+    ///
+    /// See [`SyncMapHandle`] for details.
+    pub fn sync_handle(&self) -> SyncMapHandle<K, V> {
+        SyncMapHandle::new(Arc::clone(&self.map))
+    }
+
+    /// Returns the [`Reclamation`] policy this handle currently follows.
+    pub fn reclamation(&self) -> Reclamation {
+        self.reclamation
+    }
+
+    /// Changes when this handle reclaims the memory it retires.
+    ///
+    /// This only affects this handle; other handles of the same map, including ones later cloned
+    /// from this one, use the policy the map was created with.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::{Map, Reclamation};
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// map.set_reclamation(Reclamation::manual());
+    /// map.insert(1, "a");
+    /// map.remove(&1);
+    /// // nothing is freed until we ask for it
+    /// map.flush();
+    /// ```
+    pub fn set_reclamation(&mut self, reclamation: Reclamation) {
+        self.reclamation = reclamation;
+    }
+
+    /// Waits until no other handle can still observe memory this handle has retired, and then
+    /// frees all of it.
+    ///
+    /// This is done automatically according to the handle's [`Reclamation`] policy, but can be
+    /// called explicitly to reclaim at a convenient time, such as between the phases of a batch
+    /// job. Note that this blocks while other handles are in the middle of an operation.
+    pub fn flush(&mut self) {
+        self.refresh = 0;
+        self.cleanup();
+    }
+
+    fn tick(&mut self, counted: bool) {
+        if !counted {
+            return;
+        }
+
+        self.refresh += 1;
+        if let Some(interval) = self.reclamation.interval {
+            if self.refresh >= interval {
+                self.flush();
             }
         }
+    }
+
+    fn cleanup(&mut self) {
+        quiesce(&self.map.handles.read().unwrap());
 
         //physical deletion, epoch has rolled over so we are safe to proceed with physical deletion
         //epoch rolled over, so we know we have exclusive access to the node
 
-        // println!("{:?}", &self.remove_nodes.len());
-        for to_drop in &self.remove_nodes {
-            let n = unsafe { (&**to_drop).val.load(OSC) };
-            self.remove_val.push(n);
+        // take the lists before freeing anything: if a key's destructor panics, the rest of the
+        // list is leaked rather than freed a second time by the next cleanup
+        let remove_nodes = mem::take(&mut self.remove_nodes);
+        let mut remove_val = mem::take(&mut self.remove_val);
+
+        for to_drop in &remove_nodes {
             //[drop the value inside of the node, or add to remove_val]
-            drop(unsafe { Box::from_raw(*to_drop) });
+            let v = unsafe { (**to_drop).val.load(OSC) };
+            if !v.is_null() {
+                remove_val.push(v);
+            }
         }
 
-        // println!("{:?}", &self.remove_val.len());
-        for to_drop in &self.remove_val {
-            drop(unsafe { Box::from_raw(*to_drop) });
+        for to_drop in remove_val {
+            unsafe { free_slot(to_drop) };
         }
 
-        //reset
-        self.remove_nodes = Vec::new();
-        self.remove_val = Vec::new();
+        for to_drop in remove_nodes {
+            drop(unsafe { Box::from_raw(to_drop) });
+        }
     }
 }
 
 impl<K, V> MapHandle<K, V>
 where
-    K: Hash + Ord,
+    K: Hash + Eq,
     V: Copy,
 {
+    /// Retires the value a change replaced or removed, if any, and counts the operation towards
+    /// the next reclamation if `counted` is set.
+    fn finish(&mut self, change: Change<V>, counted: bool) -> (Option<V>, Option<V>) {
+        if let Some(v) = change.retired {
+            if is_boxed_slot(v) {
+                self.remove_val.push(v);
+            }
+        }
+        self.tick(counted);
+        (change.old, change.new)
+    }
+
+    fn compute<F, G>(&mut self, key: &K, new_key: G, f: F) -> (Option<V>, Option<V>)
+    where
+        F: FnMut(Option<&V>) -> Action<V>,
+        G: FnMut() -> K,
+    {
+        let change = {
+            let _critical = CriticalSection::enter(&self.epoch_counter);
+            self.map
+                .table
+                .compute(key, new_key, f, &mut self.remove_nodes)
+        };
+
+        let counted = if change.new.is_some() {
+            self.reclamation.count_inserts
+        } else {
+            self.reclamation.count_removes
+        };
+        self.finish(change, counted)
+    }
+
     /// Inserts a key-value pair into the map.
     ///
     /// If the map did not have this key present, `None` is returned.
@@ -194,25 +458,73 @@ where
     /// assert_eq!(map.get(&37), Some("c"));
     /// ```
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        self.refresh = self.refresh + 1;
-
-        self.epoch_counter.fetch_add(1, OSC);
-        let val = self.map.table.insert(key, value, &mut self.remove_nodes);
-        self.epoch_counter.fetch_add(1, OSC);
+        let change = {
+            let _critical = CriticalSection::enter(&self.epoch_counter);
+            self.map
+                .table
+                .insert(key, value, true, &mut self.remove_nodes)
+        };
 
-        let mut ret = None;
+        let counted = self.reclamation.count_inserts;
+        self.finish(change, counted).0
+    }
 
-        if let Some(v) = val {
-            ret = Some(unsafe { *v });
-            self.remove_val.push(v);
-        }
+    /// Inserts a key-value pair into the map, unless the key is already present.
+    ///
+    /// If the map did not have this key present, the pair is inserted and `None` is returned.
+    ///
+    /// If the map did have this key present, the map is left unchanged, and the current value is
+    /// returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// assert_eq!(map.insert_if_absent(37, "a"), None);
+    /// assert_eq!(map.insert_if_absent(37, "b"), Some("a"));
+    /// assert_eq!(map.get(&37), Some("a"));
+    /// ```
+    pub fn insert_if_absent(&mut self, key: K, value: V) -> Option<V> {
+        let change = {
+            let _critical = CriticalSection::enter(&self.epoch_counter);
+            self.map
+                .table
+                .insert(key, value, false, &mut self.remove_nodes)
+        };
 
-        // if self.refresh == REFRESH_RATE {
-        //     self.refresh = 0;
-        //     self.cleanup();
-        // }
+        let counted = self.reclamation.count_inserts;
+        self.finish(change, counted).0
+    }
 
-        ret
+    /// Replaces the value for a key that is in the map, returning the previous value.
+    ///
+    /// If the map did not have this key present, nothing is inserted and `None` is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// assert_eq!(map.replace(&37, "a"), None);
+    /// assert_eq!(map.get(&37), None);
+    ///
+    /// map.insert(37, "a");
+    /// assert_eq!(map.replace(&37, "b"), Some("a"));
+    /// assert_eq!(map.get(&37), Some("b"));
+    /// ```
+    pub fn replace(&mut self, key: &K, value: V) -> Option<V> {
+        self.compute(
+            key,
+            || unreachable!("replace never inserts"),
+            |v| match v {
+                Some(_) => Action::Set(value),
+                None => Action::Keep,
+            },
+        )
+        .0
     }
 
     /// Returns a reference to the value corresponding to the key.
@@ -228,16 +540,13 @@ where
     /// assert_eq!(map.get(&2), None);
     /// ```
     pub fn get(&mut self, key: &K) -> Option<V> {
-        self.refresh = self.refresh + 1;
-
-        self.epoch_counter.fetch_add(1, OSC);
-        let ret = self.map.table.get(key, &mut self.remove_nodes);
-        self.epoch_counter.fetch_add(1, OSC);
+        let ret = {
+            let _critical = CriticalSection::enter(&self.epoch_counter);
+            self.map.table.get(key, &mut self.remove_nodes)
+        };
 
-        if self.refresh == REFRESH_RATE {
-            self.refresh = 0;
-            self.cleanup();
-        }
+        let counted = self.reclamation.count_gets;
+        self.tick(counted);
 
         ret
     }
@@ -256,18 +565,70 @@ where
     /// assert_eq!(map.remove(&1), None);
     /// ```
     pub fn remove(&mut self, key: &K) -> Option<V> {
-        self.refresh = self.refresh + 1;
+        let change = {
+            let _critical = CriticalSection::enter(&self.epoch_counter);
+            self.map.table.delete(key, &mut self.remove_nodes)
+        };
 
-        self.epoch_counter.fetch_add(1, OSC);
-        let ret = self.map.table.delete(key, &mut self.remove_nodes);
-        self.epoch_counter.fetch_add(1, OSC);
+        let counted = self.reclamation.count_removes;
+        self.finish(change, counted).0
+    }
 
-        if self.refresh == REFRESH_RATE {
-            self.refresh = 0;
-            self.cleanup();
+    /// Removes a key from the map if `f` returns true for its current value, returning the
+    /// removed value.
+    ///
+    /// The check and the removal happen atomically: the key is only removed if its value has not
+    /// changed since `f` was called on it. If it has, `f` is called again with the new value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// map.insert(1, 10);
+    /// assert_eq!(map.remove_if(&1, |&v| v > 10), None);
+    /// assert_eq!(map.remove_if(&1, |&v| v == 10), Some(10));
+    /// assert_eq!(map.get(&1), None);
+    /// ```
+    pub fn remove_if<F>(&mut self, key: &K, mut f: F) -> Option<V>
+    where
+        F: FnMut(&V) -> bool,
+    {
+        let (old, new) = self.compute(
+            key,
+            || unreachable!("remove_if never inserts"),
+            |v| match v {
+                Some(v) if f(v) => Action::Remove,
+                _ => Action::Keep,
+            },
+        );
+        if new.is_none() {
+            old
+        } else {
+            None
         }
+    }
 
-        ret
+    /// Removes a key from the map if its value is equal to `expected`, returning true if it was
+    /// removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// map.insert("lease", 7);
+    /// assert!(!map.remove_if_eq(&"lease", &8));
+    /// assert!(map.remove_if_eq(&"lease", &7));
+    /// assert_eq!(map.get(&"lease"), None);
+    /// ```
+    pub fn remove_if_eq(&mut self, key: &K, expected: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        self.remove_if(key, |v| v == expected).is_some()
     }
 
     /// Returns the number of elements in the map.
@@ -303,29 +664,267 @@ where
     }
 }
 
+impl<K, V> MapHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    /// Atomically updates the value for `key` with the result of `f`, and returns the new value.
+    ///
+    /// `f` is given the current value, or `None` if the key is not in the map. If it returns
+    /// `Some`, that value is stored for the key, inserting it if necessary. If it returns `None`,
+    /// the key is removed. No other write to the key can happen between `f` observing the current
+    /// value and its result being stored; if one races with it, `f` is called again with the new
+    /// current value, so it may be called more than once.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// assert_eq!(map.update(&"hits", |v| Some(v.map(|v| v + 1).unwrap_or(1))), Some(1));
+    /// assert_eq!(map.update(&"hits", |v| Some(v.map(|v| v + 1).unwrap_or(1))), Some(2));
+    /// assert_eq!(map.update(&"hits", |_| None), None);
+    /// assert_eq!(map.get(&"hits"), None);
+    /// ```
+    pub fn update<F>(&mut self, key: &K, mut f: F) -> Option<V>
+    where
+        F: FnMut(Option<&V>) -> Option<V>,
+    {
+        self.compute(key, || key.clone(), |v| f(v).into()).1
+    }
+
+    /// Atomically updates the value for `key` with the result of `f`, and returns the previous
+    /// value.
+    ///
+    /// See [`MapHandle::update`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// map.insert(1, 10);
+    /// assert_eq!(map.fetch_update(&1, |v| v.map(|v| v * 2)), Some(10));
+    /// assert_eq!(map.get(&1), Some(20));
+    /// ```
+    pub fn fetch_update<F>(&mut self, key: &K, mut f: F) -> Option<V>
+    where
+        F: FnMut(Option<&V>) -> Option<V>,
+    {
+        self.compute(key, || key.clone(), |v| f(v).into()).0
+    }
+
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not in
+    /// the map.
+    ///
+    /// If several threads miss on the same key at once, only one of them calls its `f`, and the
+    /// others wait for it and return the value it inserted. If that `f` panics, the threads
+    /// waiting for it panic as well, and the key is left out of the map, so the next call tries
+    /// again. `f` must not look up `key` in this map with `get_or_insert_with` itself, since it
+    /// would end up waiting for itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// assert_eq!(map.get_or_insert_with(1, || 10), 10);
+    /// assert_eq!(map.get_or_insert_with(1, || unreachable!()), 10);
+    /// ```
+    pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        match self.try_get_or_insert_with(key, || Ok::<V, Infallible>(f())) {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not in
+    /// the map and `f` succeeds.
+    ///
+    /// This is [`MapHandle::get_or_insert_with`] for fallible `f`. If `f` fails, nothing is
+    /// inserted, and its error is returned both by this call and by the calls that were waiting
+    /// for it. A waiting call that expects a different type of error instead goes on to call its
+    /// own `f`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// assert_eq!(map.try_get_or_insert_with(1, || "1x".parse::<u32>()).is_err(), true);
+    /// assert_eq!(map.get(&1), None);
+    /// assert_eq!(map.try_get_or_insert_with(1, || "10".parse::<u32>()), Ok(10));
+    /// ```
+    pub fn try_get_or_insert_with<F, E>(&mut self, key: K, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+        E: Clone + Send + Sync + 'static,
+    {
+        let map = Arc::clone(&self.map);
+        flight::get_or_load(
+            &map.flights,
+            self,
+            key,
+            |h, k| h.get(k),
+            |h, k, v| h.insert_if_absent(k, v),
+            f,
+        )
+    }
+
+    /// Removes every key from the map.
+    ///
+    /// The map is walked one bucket at a time, while other handles keep using it, so keys that
+    /// are inserted during the walk may be left in the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// map.clear();
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn clear(&mut self) {
+        for bucket in 0..self.map.table.nbuckets {
+            let mut keys = Vec::new();
+            {
+                let _critical = CriticalSection::enter(&self.epoch_counter);
+                self.map.table.map[bucket].for_each(|k, _| keys.push(k.clone()));
+            }
+            for k in keys {
+                self.remove(&k);
+            }
+        }
+    }
+}
+
+impl<K, V> MapHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Integer,
+{
+    /// Atomically adds `val` to the value for `key`, and returns the previous value.
+    ///
+    /// A key that is not in the map is treated as if it held zero, and is inserted. Like
+    /// `AtomicUsize::fetch_add`, the addition wraps around on overflow. Integers that fit in a
+    /// word are updated in place, without allocating; see [`Integer`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// assert_eq!(map.fetch_add(&"hits", 1u32), None);
+    /// assert_eq!(map.fetch_add(&"hits", 2), Some(1));
+    /// assert_eq!(map.get(&"hits"), Some(3));
+    /// ```
+    pub fn fetch_add(&mut self, key: &K, val: V) -> Option<V> {
+        self.fetch_update(key, |v| {
+            Some(v.cloned().unwrap_or(V::ZERO).wrapping_add(val))
+        })
+    }
+
+    /// Atomically subtracts `val` from the value for `key`, and returns the previous value.
+    ///
+    /// See [`MapHandle::fetch_add`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// map.insert("stock", 10i32);
+    /// assert_eq!(map.fetch_sub(&"stock", 3), Some(10));
+    /// assert_eq!(map.fetch_sub(&"missing", 3), None);
+    /// assert_eq!(map.get(&"missing"), Some(-3));
+    /// ```
+    pub fn fetch_sub(&mut self, key: &K, val: V) -> Option<V> {
+        self.fetch_update(key, |v| {
+            Some(v.cloned().unwrap_or(V::ZERO).wrapping_sub(val))
+        })
+    }
+
+    /// Atomically sets the value for `key` to the maximum of its current value and `val`, and
+    /// returns the previous value.
+    ///
+    /// A key that is not in the map is inserted with `val`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// assert_eq!(map.fetch_max(&"peak", 5u8), None);
+    /// assert_eq!(map.fetch_max(&"peak", 3), Some(5));
+    /// assert_eq!(map.get(&"peak"), Some(5));
+    /// ```
+    pub fn fetch_max(&mut self, key: &K, val: V) -> Option<V> {
+        self.fetch_update(key, |v| Some(v.map(|&v| v.max(val)).unwrap_or(val)))
+    }
+
+    /// Atomically sets the value for `key` to the minimum of its current value and `val`, and
+    /// returns the previous value.
+    ///
+    /// A key that is not in the map is inserted with `val`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    ///
+    /// let mut map = Map::with_capacity(16);
+    /// assert_eq!(map.fetch_min(&"low", 5u8), None);
+    /// assert_eq!(map.fetch_min(&"low", 3), Some(5));
+    /// assert_eq!(map.get(&"low"), Some(3));
+    /// ```
+    pub fn fetch_min(&mut self, key: &K, val: V) -> Option<V> {
+        self.fetch_update(key, |v| Some(v.map(|&v| v.min(val)).unwrap_or(val)))
+    }
+}
+
 impl<K, V> Clone for MapHandle<K, V> {
     fn clone(&self) -> Self {
-        let ret = Self {
-            map: Arc::clone(&self.map),
-            epoch_counter: Arc::new(AtomicUsize::new(0)),
-            remove_nodes: Vec::new(),
-            remove_val: Vec::new(),
-            refresh: 0,
-        };
-
-        let mut handles_vec = self.map.handles.write().unwrap(); //handles vector
-        handles_vec.push(Arc::clone(&ret.epoch_counter));
+        MapHandle::register(Arc::clone(&self.map))
+    }
+}
 
-        ret
+impl<K, V> Drop for MapHandle<K, V> {
+    fn drop(&mut self) {
+        // free what we still hold, and stop other handles from waiting on us
+        self.cleanup();
+        let mut handles_vec = self.map.handles.write().unwrap();
+        handles_vec.retain(|h| !Arc::ptr_eq(h, &self.epoch_counter));
     }
 }
 
 /// A shared, concurrent hash map.
 ///
 /// See [`MapHandle`] for how to interact with this map.
-pub struct Map<K, V> {
+pub struct Map<K, V: 'static> {
+    id: usize,
+    /// The number of `SyncMapHandle`s to this map. The last one to go drops `participants`.
+    sync_handles: AtomicUsize,
+    /// Every thread's participant in this map, owned by the map rather than by the threads so
+    /// that they do not outlive its `SyncMapHandle`s.
+    participants: Mutex<Vec<Arc<Participant<K, V>>>>,
     table: Table<K, V>,
     handles: RwLock<Vec<Arc<AtomicUsize>>>, //(started, finished)
+    reclamation: Reclamation,
+    flights: Flights<K, V>,
 }
 
 impl<K, V> Map<K, V> {
@@ -335,35 +934,51 @@ impl<K, V> Map<K, V> {
     /// more keys than buckets, performance will suffer, as all the keys in a key's bucket must be
     /// searched to read or update that key.
     pub fn with_capacity(nbuckets: usize) -> MapHandle<K, V> {
+        Self::with_reclamation(nbuckets, Reclamation::default())
+    }
+
+    /// Create a new, shared map whose handles reclaim memory according to `reclamation`.
+    ///
+    /// See [`Map::with_capacity`] for the meaning of `nbuckets`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::{Map, Reclamation};
+    ///
+    /// let mut map = Map::with_reclamation(16, Reclamation::every(64));
+    /// map.insert(1, "a");
+    /// assert_eq!(map.reclamation(), Reclamation::every(64));
+    /// assert_eq!(map.clone().reclamation(), Reclamation::every(64));
+    /// ```
+    pub fn with_reclamation(nbuckets: usize, reclamation: Reclamation) -> MapHandle<K, V> {
         let new_hashmap = Map {
+            id: NEXT_MAP_ID.fetch_add(1, OSC),
+            sync_handles: AtomicUsize::new(0),
+            participants: Mutex::new(Vec::new()),
             table: Table::new(nbuckets),
             handles: RwLock::new(Vec::new()),
-        };
-        let ret = MapHandle {
-            map: Arc::new(new_hashmap),
-            epoch_counter: Arc::new(AtomicUsize::new(0)),
-            remove_nodes: Vec::new(),
-            remove_val: Vec::new(),
-            refresh: 0,
+            reclamation,
+            flights: Flights::default(),
         };
 
         //push the first maphandle into the epoch system
-        let hashmap = Arc::clone(&ret.map);
-        let mut handles_vec = hashmap.handles.write().unwrap();
-        handles_vec.push(Arc::clone(&ret.epoch_counter));
-        ret
+        MapHandle::register(Arc::new(new_hashmap))
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_unwrap)]
 mod tests {
     use super::*;
     use rand::{thread_rng, Rng};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Barrier;
     use std::thread;
 
     /*
-    the data produced is a bit strange because of the way I take mod to test only even values 
-    are inserted so the end number of values should be n/2 (computer style) and the capacity 
+    the data produced is a bit strange because of the way I take mod to test only even values
+    are inserted so the end number of values should be n/2 (computer style) and the capacity
     of the map should be equal to the greatest power of 2 less than n/2.
     */
     #[test]
@@ -464,4 +1079,312 @@ mod tests {
         assert_eq!(new_hashmap.get(&0).unwrap(), 0);
         assert!(new_hashmap.get(&3).unwrap() != 2); // test that it changed
     }
+
+    #[test]
+    fn hashmap_reclamation() {
+        // values too big to be stored inline, so that they are retired
+        let mut handle: MapHandle<usize, u128> = Map::with_reclamation(8, Reclamation::manual());
+        for i in 0..2000 {
+            handle.insert(i, i as u128);
+        }
+        for i in 0..2000 {
+            handle.insert(i, i as u128 + 1);
+        }
+        for i in 0..2000 {
+            assert_eq!(handle.remove(&i), Some(i as u128 + 1));
+        }
+
+        // nothing is reclaimed inline in manual mode
+        assert_eq!(handle.remove_nodes.len(), 2000);
+        assert_eq!(handle.remove_val.len(), 4000);
+
+        handle.flush();
+        assert!(handle.remove_nodes.is_empty());
+        assert!(handle.remove_val.is_empty());
+
+        handle.set_reclamation(Reclamation {
+            interval: Some(10),
+            count_gets: false,
+            count_inserts: false,
+            count_removes: true,
+        });
+        for i in 0..10 {
+            handle.insert(i, i as u128);
+        }
+        for i in 0..10 {
+            handle.get(&i);
+        }
+        for i in 0..9 {
+            handle.remove(&i);
+        }
+        assert_eq!(handle.remove_nodes.len(), 9);
+        handle.remove(&9);
+        assert!(handle.remove_nodes.is_empty());
+    }
+
+    #[test]
+    fn hashmap_update() {
+        let handle = Map::with_capacity(8);
+        let mut threads = vec![];
+        let nthreads = 5;
+        let num_iterations = 10000;
+        for _ in 0..nthreads {
+            let mut new_handle = handle.clone();
+            threads.push(thread::spawn(move || {
+                for i in 0..num_iterations {
+                    new_handle.update(&(i % 4), |v| Some(v.map(|v| v + 1).unwrap_or(1)));
+                    // keep a key that comes and goes, to race removals against updates
+                    new_handle.update(&4, |v| match v {
+                        Some(_) => None,
+                        None => Some(0),
+                    });
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let mut handle = handle;
+        for k in 0..4 {
+            assert_eq!(handle.get(&k), Some(nthreads * num_iterations / 4));
+        }
+        assert_eq!(handle.len(), 4 + handle.get(&4).map(|_| 1).unwrap_or(0));
+
+        assert_eq!(handle.fetch_update(&0, |v| v.map(|v| v * 2)), Some(12500));
+        assert_eq!(handle.get(&0), Some(25000));
+        assert_eq!(handle.fetch_update(&0, |_| None), Some(25000));
+        assert_eq!(handle.get(&0), None);
+        assert_eq!(handle.update(&0, |_| None), None);
+        assert_eq!(handle.fetch_update(&5, |_| Some(1)), None);
+        assert_eq!(handle.get(&5), Some(1));
+    }
+
+    #[test]
+    fn hashmap_counters() {
+        let small = Map::with_capacity(8);
+        let large = Map::with_capacity(8);
+        let mut threads = vec![];
+        let nthreads = 5;
+        let num_iterations = 10000;
+        for t in 0..nthreads {
+            let mut small = small.clone();
+            let mut large = large.clone();
+            threads.push(thread::spawn(move || {
+                for i in 0..num_iterations {
+                    small.fetch_add(&(i % 4), 1u16);
+                    large.fetch_add(&(i % 4), 1u64);
+                    large.fetch_max(&4, (t * num_iterations + i) as u64);
+                    large.fetch_min(&5, (t * num_iterations + i) as u64);
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let mut small = small;
+        let mut large = large;
+        for k in 0..4 {
+            assert_eq!(small.get(&k), Some((nthreads * num_iterations / 4) as u16));
+            assert_eq!(large.get(&k), Some((nthreads * num_iterations / 4) as u64));
+        }
+        assert_eq!(large.get(&4), Some((nthreads * num_iterations - 1) as u64));
+        assert_eq!(large.get(&5), Some(0));
+
+        // integers that fit in a word are updated in place, so there is nothing to retire
+        assert!(small.remove_val.is_empty());
+        if cfg!(target_pointer_width = "64") {
+            assert!(large.remove_val.is_empty());
+
+            // one that needs the top bit is boxed, and retired when it is replaced
+            large.insert(6, u64::MAX >> 1);
+            assert_eq!(large.fetch_add(&6, 1), Some(u64::MAX >> 1));
+            assert!(large.remove_val.is_empty());
+            assert_eq!(large.fetch_add(&6, 1), Some((u64::MAX >> 1) + 1));
+            assert_eq!(large.remove_val.len(), 1);
+            assert_eq!(large.fetch_sub(&6, 2), Some((u64::MAX >> 1) + 2));
+            assert_eq!(large.remove_val.len(), 2);
+            assert_eq!(large.get(&6), Some(u64::MAX >> 1));
+        }
+
+        assert_eq!(small.fetch_sub(&0, 12500), Some(12500));
+        assert_eq!(small.get(&0), Some(0));
+        assert_eq!(small.fetch_sub(&0, 1), Some(0));
+        assert_eq!(small.get(&0), Some(u16::MAX));
+        assert_eq!(small.fetch_add(&0, 1), Some(u16::MAX));
+        assert_eq!(small.get(&0), Some(0));
+    }
+
+    #[test]
+    fn hashmap_conditional() {
+        let handle = Map::with_capacity(8);
+        let mut threads = vec![];
+        let nthreads = 5;
+        let nkeys = 1000;
+        let barrier = Arc::new(Barrier::new(nthreads));
+        for t in 0..nthreads {
+            let barrier = Arc::clone(&barrier);
+            let mut new_handle = handle.clone();
+            threads.push(thread::spawn(move || {
+                // exactly one thread gets to insert each key, and then to remove it
+                let mut inserted = 0;
+                let mut removed = 0;
+                for k in 0..nkeys {
+                    if new_handle.insert_if_absent(k, t).is_none() {
+                        inserted += 1;
+                    }
+                }
+                barrier.wait();
+                for k in 0..nkeys {
+                    if let Some(v) = new_handle.get(&k) {
+                        if new_handle.remove_if_eq(&k, &v) {
+                            removed += 1;
+                        }
+                    }
+                }
+                (inserted, removed)
+            }));
+        }
+        let (mut inserted, mut removed) = (0, 0);
+        for t in threads {
+            let (i, r) = t.join().unwrap();
+            inserted += i;
+            removed += r;
+        }
+        assert_eq!(inserted, nkeys);
+        assert_eq!(removed, nkeys);
+
+        let mut handle = handle;
+        assert_eq!(handle.len(), 0);
+        assert_eq!(handle.replace(&1, 1), None);
+        assert_eq!(handle.get(&1), None);
+        assert_eq!(handle.insert_if_absent(1, 1), None);
+        assert_eq!(handle.insert_if_absent(1, 2), Some(1));
+        assert_eq!(handle.replace(&1, 3), Some(1));
+        assert_eq!(handle.remove_if(&1, |&v| v == 1), None);
+        assert_eq!(handle.remove_if(&1, |&v| v == 3), Some(3));
+        assert_eq!(handle.len(), 0);
+    }
+
+    // which trait implementation of `Bomb` should panic; only used by `hashmap_panic_safety`
+    static EXPLODE: AtomicUsize = AtomicUsize::new(0);
+    const EXPLODE_HASH: usize = 1;
+    const EXPLODE_EQ: usize = 2;
+
+    #[derive(Debug)]
+    struct Bomb(u32);
+
+    impl Bomb {
+        fn fuse(trigger: usize) {
+            if EXPLODE.load(OSC) == trigger {
+                panic!("boom");
+            }
+        }
+    }
+
+    impl Hash for Bomb {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            Bomb::fuse(EXPLODE_HASH);
+            // give every key the same hash so that keys have to be compared
+            0.hash(state);
+        }
+    }
+
+    impl PartialEq for Bomb {
+        fn eq(&self, other: &Self) -> bool {
+            Bomb::fuse(EXPLODE_EQ);
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Bomb {}
+
+    #[test]
+    fn hashmap_panic_safety() {
+        let mut handle = Map::with_capacity(4);
+        let mut other = handle.clone();
+        for i in 0..16 {
+            handle.insert(Bomb(i), i);
+        }
+
+        for &trigger in &[EXPLODE_HASH, EXPLODE_EQ] {
+            for op in 0..3 {
+                EXPLODE.store(trigger, OSC);
+                let res = panic::catch_unwind(AssertUnwindSafe(|| match op {
+                    0 => {
+                        handle.insert(Bomb(8), 0);
+                    }
+                    1 => {
+                        handle.get(&Bomb(8));
+                    }
+                    _ => {
+                        handle.remove(&Bomb(8));
+                    }
+                }));
+                EXPLODE.store(0, OSC);
+                assert!(res.is_err());
+
+                // the panicking handle must not be left inside its critical section
+                assert_eq!(handle.epoch_counter.load(OSC) % 2, 0);
+
+                // so other handles can still reclaim memory without waiting forever
+                other.remove(&Bomb(15));
+                other.flush();
+                other.insert(Bomb(15), 15);
+            }
+        }
+
+        // and the map is still fully usable from both handles
+        assert_eq!(handle.get(&Bomb(8)), Some(8));
+        assert_eq!(handle.insert(Bomb(8), 80), Some(8));
+        assert_eq!(other.get(&Bomb(8)), Some(80));
+        assert_eq!(other.remove(&Bomb(3)), Some(3));
+        assert_eq!(handle.get(&Bomb(3)), None);
+        assert_eq!(handle.len(), 15);
+        handle.flush();
+        other.flush();
+    }
+
+    #[test]
+    fn hashmap_get_or_insert() {
+        let mut handle = Map::with_capacity(8);
+        let loads = Arc::new(AtomicUsize::new(0));
+        let nthreads = 8;
+        let barrier = Arc::new(Barrier::new(nthreads));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                let loads = Arc::clone(&loads);
+                let mut new_handle = handle.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    new_handle.get_or_insert_with(1, || {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(::std::time::Duration::from_millis(50));
+                        42
+                    })
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), 42);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        // failed and panicked loads leave nothing behind
+        assert_eq!(
+            handle.try_get_or_insert_with(2, || Err("nope")),
+            Err("nope")
+        );
+        assert_eq!(handle.get(&2), None);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle.get_or_insert_with(2, || panic!("loader"))
+        }));
+        assert!(res.is_err());
+        assert_eq!(handle.get(&2), None);
+        assert_eq!(handle.try_get_or_insert_with(2, || Ok::<_, ()>(7)), Ok(7));
+        assert_eq!(handle.get_or_insert_with(2, || 8), 7);
+        assert_eq!(handle.len(), 2);
+    }
 }
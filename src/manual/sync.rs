@@ -0,0 +1,546 @@
+use super::{Map, MapHandle, OSC};
+use flight;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::mem;
+use std::ptr;
+use std::sync::{Arc, Mutex, TryLockError, Weak};
+use Integer;
+
+/// A thread's participant in a map it uses through a `SyncMapHandle`.
+///
+/// The map owns its participants, so that the last `SyncMapHandle` can drop them all, including
+/// those of threads that no longer use the map.
+pub(super) struct Participant<K, V: 'static> {
+    /// The participant's `MapHandle`, which is locked for the length of an operation. Only the
+    /// participant's own thread ever locks it.
+    handle: Mutex<MapHandle<K, V>>,
+}
+
+/// A thread's reference to its participant in a map, which drops the participant when the
+/// thread exits.
+struct Local<K, V: 'static> {
+    participant: Weak<Participant<K, V>>,
+    map: Weak<Map<K, V>>,
+}
+
+/// A `Local` of any map.
+trait AnyLocal {
+    fn as_any(&self) -> &dyn Any;
+
+    /// Returns true if the participant has already been dropped along with the map's last
+    /// `SyncMapHandle`.
+    fn is_dropped(&self) -> bool;
+}
+
+impl<K: 'static, V: 'static> AnyLocal for Local<K, V> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn is_dropped(&self) -> bool {
+        self.participant.upgrade().is_none()
+    }
+}
+
+impl<K, V> Drop for Local<K, V> {
+    fn drop(&mut self) {
+        let map = match self.map.upgrade() {
+            Some(map) => map,
+            None => return,
+        };
+        let mut participants = map.participants.lock().unwrap();
+        let participant = participants
+            .iter()
+            .position(|p| ptr::eq(&**p, self.participant.as_ptr()))
+            .map(|i| participants.swap_remove(i));
+        drop(participants);
+        // dropping a participant waits for the other handles of its map, so do it without holding
+        // on to the map's participants
+        drop(participant);
+    }
+}
+
+thread_local! {
+    /// This thread's participant in every map it uses through a `SyncMapHandle`, by map id.
+    static PARTICIPANTS: RefCell<HashMap<usize, Box<dyn AnyLocal>>> = RefCell::new(HashMap::new());
+}
+
+/// A handle to a shared [`Map`] that can itself be shared between threads.
+///
+/// A [`MapHandle`] keeps track of the memory it has retired and of when it is in the middle of an
+/// operation, which is why its methods take `&mut self` and every thread needs its own clone. A
+/// `SyncMapHandle` instead keeps that state in a thread-local participant, created the first time
+/// a thread uses the map and registered with the map just like a cloned `MapHandle` would be. Its
+/// methods therefore only need `&self`, so it can be put in an `Arc` or a `static` and used from
+/// any thread.
+///
+/// Each participant follows the map's [`Reclamation`](super::Reclamation) policy, and is dropped,
+/// freeing what it has retired, when its thread exits or once the map has no `SyncMapHandle`s
+/// left, whichever comes first. The map owns the participants, so dropping the last
+/// `SyncMapHandle` drops those of every thread, including threads that no longer use the map.
+///
+/// The closures given to [`update`](Self::update), [`fetch_update`](Self::fetch_update) and
+/// [`remove_if`](Self::remove_if), and the map's `Hash` and `Eq` implementations, run in the
+/// middle of an operation on the map. They must not use this same map through a
+/// `SyncMapHandle`, since the thread's participant is busy; doing so panics. They may use other
+/// maps, but only if no thread uses this map from inside such a closure on those: an operation
+/// can wait for every thread to finish its current operation on the same map, so two threads
+/// doing this in opposite order can deadlock. The loader given to
+/// [`get_or_insert_with`](Self::get_or_insert_with) runs outside of any operation, and may use any
+/// map, this one included.
+///
+/// # Examples
+///
+/// ```
+/// use concache::manual::Map;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let map = Arc::new(Map::with_capacity(16).sync_handle());
+/// let threads: Vec<_> = (0..4)
+///     .map(|i| {
+///         let map = Arc::clone(&map);
+///         thread::spawn(move || {
+///             map.insert(i, i * 10);
+///         })
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+///
+/// assert_eq!(map.len(), 4);
+/// assert_eq!(map.get(&2), Some(20));
+/// ```
+pub struct SyncMapHandle<K, V: 'static> {
+    map: Arc<Map<K, V>>,
+}
+
+unsafe impl<K, V> Send for SyncMapHandle<K, V>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+{
+}
+
+unsafe impl<K, V> Sync for SyncMapHandle<K, V>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+{
+}
+
+impl<K, V> SyncMapHandle<K, V> {
+    pub(super) fn new(map: Arc<Map<K, V>>) -> Self {
+        map.sync_handles.fetch_add(1, OSC);
+        SyncMapHandle { map }
+    }
+
+    /// Returns a regular [`MapHandle`] to the same map.
+    pub fn handle(&self) -> MapHandle<K, V> {
+        MapHandle::register(Arc::clone(&self.map))
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.map.table.nitems.load(OSC)
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.map.table.nitems.load(OSC) == 0
+    }
+}
+
+impl<K, V> SyncMapHandle<K, V>
+where
+    K: 'static,
+    V: 'static,
+{
+    /// Runs `f` with the calling thread's participant in this map, creating it if necessary.
+    fn with_participant<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut MapHandle<K, V>) -> R,
+    {
+        let (participant, pruned) = PARTICIPANTS.with(|participants| {
+            let mut participants = participants.borrow_mut();
+            let existing = participants.get(&self.map.id).and_then(|l| {
+                let local = l
+                    .as_any()
+                    .downcast_ref::<Local<K, V>>()
+                    .expect("map ids are unique");
+                local.participant.upgrade()
+            });
+            if let Some(participant) = existing {
+                return (participant, Vec::new());
+            }
+
+            // while we are here, forget the participants that their maps have dropped, which
+            // includes any earlier one in this map
+            let dropped: Vec<_> = participants
+                .iter()
+                .filter(|&(_, l)| l.is_dropped())
+                .map(|(&id, _)| id)
+                .collect();
+            let pruned: Vec<_> = dropped
+                .into_iter()
+                .filter_map(|id| participants.remove(&id))
+                .collect();
+
+            let participant = Arc::new(Participant {
+                handle: Mutex::new(self.handle()),
+            });
+            self.map
+                .participants
+                .lock()
+                .unwrap()
+                .push(Arc::clone(&participant));
+            let local: Box<dyn AnyLocal> = Box::new(Local {
+                participant: Arc::downgrade(&participant),
+                map: Arc::downgrade(&self.map),
+            });
+            participants.insert(self.map.id, local);
+            (participant, pruned)
+        });
+        drop(pruned);
+
+        let mut handle = match participant.handle.try_lock() {
+            Ok(handle) => handle,
+            // like a `MapHandle`, a participant stays usable if an operation panics
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => panic!(
+                "a SyncMapHandle was used from inside one of its own operations on the same map"
+            ),
+        };
+        f(&mut handle)
+    }
+
+    /// Frees the memory retired by the calling thread's participant in this map.
+    ///
+    /// See [`MapHandle::flush`].
+    pub fn flush(&self) {
+        self.with_participant(|h| h.flush())
+    }
+}
+
+impl<K, V> SyncMapHandle<K, V>
+where
+    K: Hash + Eq + 'static,
+    V: Copy + 'static,
+{
+    /// Inserts a key-value pair into the map.
+    ///
+    /// See [`MapHandle::insert`].
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.with_participant(|h| h.insert(key, value))
+    }
+
+    /// Inserts a key-value pair into the map, unless the key is already present.
+    ///
+    /// See [`MapHandle::insert_if_absent`].
+    pub fn insert_if_absent(&self, key: K, value: V) -> Option<V> {
+        self.with_participant(|h| h.insert_if_absent(key, value))
+    }
+
+    /// Replaces the value for a key that is in the map, returning the previous value.
+    ///
+    /// See [`MapHandle::replace`].
+    pub fn replace(&self, key: &K, value: V) -> Option<V> {
+        self.with_participant(|h| h.replace(key, value))
+    }
+
+    /// Returns the value corresponding to the key.
+    ///
+    /// See [`MapHandle::get`].
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.with_participant(|h| h.get(key))
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key was previously in the
+    /// map.
+    ///
+    /// See [`MapHandle::remove`].
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.with_participant(|h| h.remove(key))
+    }
+
+    /// Removes a key from the map if `f` returns true for its current value, returning the
+    /// removed value.
+    ///
+    /// `f` runs in the middle of the operation; see [`SyncMapHandle`] for what it may do.
+    ///
+    /// See [`MapHandle::remove_if`].
+    pub fn remove_if<F>(&self, key: &K, f: F) -> Option<V>
+    where
+        F: FnMut(&V) -> bool,
+    {
+        self.with_participant(|h| h.remove_if(key, f))
+    }
+
+    /// Removes a key from the map if its value is equal to `expected`, returning true if it was
+    /// removed.
+    ///
+    /// See [`MapHandle::remove_if_eq`].
+    pub fn remove_if_eq(&self, key: &K, expected: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        self.with_participant(|h| h.remove_if_eq(key, expected))
+    }
+}
+
+impl<K, V> SyncMapHandle<K, V>
+where
+    K: Hash + Eq + Clone + 'static,
+    V: Copy + 'static,
+{
+    /// Atomically updates the value for `key` with the result of `f`, and returns the new value.
+    ///
+    /// `f` runs in the middle of the operation; see [`SyncMapHandle`] for what it may do.
+    ///
+    /// See [`MapHandle::update`].
+    pub fn update<F>(&self, key: &K, f: F) -> Option<V>
+    where
+        F: FnMut(Option<&V>) -> Option<V>,
+    {
+        self.with_participant(|h| h.update(key, f))
+    }
+
+    /// Atomically updates the value for `key` with the result of `f`, and returns the previous
+    /// value.
+    ///
+    /// `f` runs in the middle of the operation; see [`SyncMapHandle`] for what it may do.
+    ///
+    /// See [`MapHandle::fetch_update`].
+    pub fn fetch_update<F>(&self, key: &K, f: F) -> Option<V>
+    where
+        F: FnMut(Option<&V>) -> Option<V>,
+    {
+        self.with_participant(|h| h.fetch_update(key, f))
+    }
+
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not in
+    /// the map.
+    ///
+    /// See [`MapHandle::get_or_insert_with`].
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        match self.try_get_or_insert_with(key, || Ok::<V, Infallible>(f())) {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not in
+    /// the map and `f` succeeds.
+    ///
+    /// See [`MapHandle::try_get_or_insert_with`].
+    pub fn try_get_or_insert_with<F, E>(&self, key: K, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+        E: Clone + Send + Sync + 'static,
+    {
+        // `f` runs outside of the participant, so that it can use the map itself
+        flight::get_or_load(
+            &self.map.flights,
+            self,
+            key,
+            |h, k| h.get(k),
+            |h, k, v| h.insert_if_absent(k, v),
+            f,
+        )
+    }
+}
+
+impl<K, V> SyncMapHandle<K, V>
+where
+    K: Hash + Eq + Clone + 'static,
+    V: Integer + 'static,
+{
+    /// Atomically adds `val` to the value for `key`, and returns the previous value.
+    ///
+    /// See [`MapHandle::fetch_add`].
+    pub fn fetch_add(&self, key: &K, val: V) -> Option<V> {
+        self.with_participant(|h| h.fetch_add(key, val))
+    }
+
+    /// Atomically subtracts `val` from the value for `key`, and returns the previous value.
+    ///
+    /// See [`MapHandle::fetch_sub`].
+    pub fn fetch_sub(&self, key: &K, val: V) -> Option<V> {
+        self.with_participant(|h| h.fetch_sub(key, val))
+    }
+
+    /// Atomically sets the value for `key` to the maximum of its current value and `val`, and
+    /// returns the previous value.
+    ///
+    /// See [`MapHandle::fetch_max`].
+    pub fn fetch_max(&self, key: &K, val: V) -> Option<V> {
+        self.with_participant(|h| h.fetch_max(key, val))
+    }
+
+    /// Atomically sets the value for `key` to the minimum of its current value and `val`, and
+    /// returns the previous value.
+    ///
+    /// See [`MapHandle::fetch_min`].
+    pub fn fetch_min(&self, key: &K, val: V) -> Option<V> {
+        self.with_participant(|h| h.fetch_min(key, val))
+    }
+}
+
+impl<K, V> Clone for SyncMapHandle<K, V> {
+    fn clone(&self) -> Self {
+        SyncMapHandle::new(Arc::clone(&self.map))
+    }
+}
+
+impl<K, V> Drop for SyncMapHandle<K, V> {
+    fn drop(&mut self) {
+        if self.map.sync_handles.fetch_sub(1, OSC) != 1 {
+            return;
+        }
+
+        // this was the last one, so every thread's participant can go; a thread that is still in
+        // the middle of an operation drops its own once that finishes
+        let participants = mem::take(&mut *self.map.participants.lock().unwrap());
+        drop(participants);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn sync_handle_concurr() {
+        let handle = Arc::new(Map::with_capacity(8).sync_handle());
+        let mut threads = vec![];
+        for _ in 0..5 {
+            let handle = Arc::clone(&handle);
+            threads.push(thread::spawn(move || {
+                let mut rng = thread_rng();
+                for _ in 0..100000 {
+                    let val = rng.gen_range(0, 128);
+                    match rng.gen_range(0, 3) {
+                        0 => {
+                            handle.insert(val, val);
+                        }
+                        1 => {
+                            if let Some(v) = handle.get(&val) {
+                                assert_eq!(v, val);
+                            }
+                        }
+                        _ => {
+                            handle.remove(&val);
+                        }
+                    }
+                }
+                handle.flush();
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // every thread's participant was dropped, and with it its registration
+        assert_eq!(handle.map.handles.read().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn sync_handle_basics() {
+        let mut handle = Map::with_capacity(8);
+        let shared = handle.sync_handle();
+        let other = Map::with_capacity(8).sync_handle();
+
+        assert_eq!(shared.insert(1, 10), None);
+        assert_eq!(other.insert(1, 20), None);
+        assert_eq!(handle.get(&1), Some(10));
+        assert_eq!(shared.clone().get(&1), Some(10));
+        assert_eq!(other.get(&1), Some(20));
+
+        handle.insert(2, 5);
+        assert_eq!(shared.remove(&2), Some(5));
+        assert_eq!(shared.len(), 1);
+        assert!(!shared.is_empty());
+        shared.flush();
+
+        assert_eq!(shared.fetch_add(&3, 4), None);
+        assert_eq!(shared.fetch_sub(&3, 1), Some(4));
+        assert_eq!(shared.fetch_max(&3, 7), Some(3));
+        assert_eq!(shared.fetch_min(&3, 2), Some(7));
+        assert_eq!(handle.get(&3), Some(2));
+
+        // the loader can use the map itself
+        assert_eq!(
+            shared.get_or_insert_with(4, || shared.get(&3).unwrap() + 1),
+            3
+        );
+        assert_eq!(shared.try_get_or_insert_with(4, || Err(())), Ok(3));
+    }
+
+    #[test]
+    fn sync_handle_nested() {
+        let a = Map::with_capacity(8).sync_handle();
+        let b = Map::with_capacity(8).sync_handle();
+        b.insert(1, 10);
+        assert_eq!(a.update(&1, |_| b.get(&1)), Some(10));
+        assert_eq!(b.fetch_update(&1, |_| a.remove(&1)), Some(10));
+        assert_eq!(b.get(&1), Some(10));
+        assert!(a.is_empty());
+    }
+
+    #[test]
+    fn sync_handle_pruned() {
+        let first = Map::with_capacity(8).sync_handle();
+        let map = Arc::downgrade(&first.map);
+        first.insert(1, 1);
+
+        // another thread uses the map once, and then stays around without using it again
+        let shared = first.clone();
+        let (used_tx, used_rx) = mpsc::channel();
+        let (exit_tx, exit_rx) = mpsc::channel();
+        let idle = thread::spawn(move || {
+            shared.insert(2, 2);
+            drop(shared);
+            used_tx.send(()).unwrap();
+            exit_rx.recv().unwrap();
+        });
+        used_rx.recv().unwrap();
+        {
+            let map = map.upgrade().unwrap();
+            assert_eq!(map.handles.read().unwrap().len(), 2);
+            assert_eq!(map.table.nitems.load(OSC), 2);
+        }
+
+        // dropping the last handle drops that thread's participant too, and with it the map
+        drop(first);
+        assert!(map.upgrade().is_none());
+        exit_tx.send(()).unwrap();
+        idle.join().unwrap();
+    }
+
+    #[test]
+    fn sync_handle_again() {
+        let handle = Map::with_capacity(8);
+        let shared = handle.sync_handle();
+        shared.insert(1, 1);
+        assert_eq!(handle.map.handles.read().unwrap().len(), 2);
+        drop(shared);
+        assert_eq!(handle.map.handles.read().unwrap().len(), 1);
+
+        // a later handle gets a new participant
+        let shared = handle.sync_handle();
+        assert_eq!(shared.get(&1), Some(1));
+        assert_eq!(handle.map.handles.read().unwrap().len(), 2);
+        assert_eq!(handle.map.participants.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,400 @@
+//! Concurrent memoization of pure functions.
+//!
+//! A [`Memo`] keeps the results of a function in one of the crate's maps, which it calls its
+//! store. Any type that implements [`MemoStore`] can be the store: both [`crossbeam::Map`] and
+//! [`manual::Map`] do, as does [`Cache`], which evicts results with its own policy.
+
+use cache::Cache;
+use crossbeam;
+use manual;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+/// A map that a [`Memo`] can keep its results in.
+///
+/// `get_or_insert_with` must only call `f` from one of the threads that miss on a key at the same
+/// time, as the maps of this crate do, or the memoized function may run more than once for the
+/// same key.
+pub trait MemoStore<K, V> {
+    /// Returns the value for `key`, first inserting the value returned by `f` if the key is not in
+    /// the store.
+    fn get_or_insert_with<F>(&mut self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V;
+
+    /// Removes a key from the store.
+    fn remove(&mut self, key: &K);
+
+    /// Removes every key from the store.
+    fn clear(&mut self);
+
+    /// Returns the number of keys in the store.
+    fn len(&self) -> usize;
+
+    /// Returns true if the store contains no keys.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V> MemoStore<K, V> for crossbeam::MapHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    fn get_or_insert_with<F>(&mut self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        crossbeam::MapHandle::get_or_insert_with(self, key, f)
+    }
+
+    fn remove(&mut self, key: &K) {
+        crossbeam::MapHandle::remove(self, key);
+    }
+
+    fn clear(&mut self) {
+        crossbeam::MapHandle::clear(self);
+    }
+
+    fn len(&self) -> usize {
+        crossbeam::MapHandle::len(self)
+    }
+}
+
+impl<K, V> MemoStore<K, V> for manual::MapHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    fn get_or_insert_with<F>(&mut self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        manual::MapHandle::get_or_insert_with(self, key, f)
+    }
+
+    fn remove(&mut self, key: &K) {
+        manual::MapHandle::remove(self, key);
+    }
+
+    fn clear(&mut self) {
+        manual::MapHandle::clear(self);
+    }
+
+    fn len(&self) -> usize {
+        manual::MapHandle::len(self)
+    }
+}
+
+impl<K, V> MemoStore<K, V> for Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Copy,
+{
+    fn get_or_insert_with<F>(&mut self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        Cache::get_or_insert_with(self, key, f)
+    }
+
+    fn remove(&mut self, key: &K) {
+        Cache::remove(self, key);
+    }
+
+    fn clear(&mut self) {
+        Cache::clear(self);
+    }
+
+    fn len(&self) -> usize {
+        Cache::len(self)
+    }
+}
+
+/// The keys of a bounded [`Memo`], in the order their results were computed.
+struct Order<K> {
+    max_entries: usize,
+    keys: Mutex<VecDeque<K>>,
+}
+
+/// A handle to a memoized function, whose results are shared by all of the memo's handles.
+///
+/// Calling [`get`](Memo::get) with a key returns the function's result for that key, which is
+/// only computed the first time. When several threads ask for the same key at once, only one of
+/// them calls the function, and the others wait for its result. The function should be pure:
+/// a memo assumes that calling it again with the same key would give the same result.
+///
+/// Like a [`manual::MapHandle`], each thread needs its own handle, which it gets by cloning any
+/// handle of the memo; this is why `get` takes `&mut self`. Cloning a handle clones the store's
+/// handle, and shares the function.
+///
+/// An unbounded memo, made with [`Memo::new`], keeps every result until it is
+/// [cleared](Memo::clear). A bounded one, made with [`Memo::bounded`], keeps at most a given
+/// number, and forgets the oldest result when a new one takes it over that number. Results that
+/// are computed at the same time may briefly take it over by one each. To have results evicted by
+/// how they are used instead, use a [`Cache`] as the store.
+///
+/// # Examples
+///
+/// ```
+/// use concache::crossbeam::Map;
+/// use concache::Memo;
+/// use std::thread;
+///
+/// let squares = Memo::new(Map::with_capacity(16), |&n: &u64| n * n);
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let mut squares = squares.clone();
+///         thread::spawn(move || (0..10).map(|n| squares.get(n)).sum::<u64>())
+///     })
+///     .collect();
+/// for t in threads {
+///     assert_eq!(t.join().unwrap(), 285);
+/// }
+/// assert_eq!(squares.len(), 10);
+/// ```
+pub struct Memo<K, V, F, S> {
+    store: S,
+    f: Arc<F>,
+    order: Option<Arc<Order<K>>>,
+    _marker: PhantomData<fn(&K) -> V>,
+}
+
+impl<K, V, F, S> Memo<K, V, F, S>
+where
+    F: Fn(&K) -> V,
+    S: MemoStore<K, V>,
+{
+    /// Memoizes `f`, keeping all of its results in `store`.
+    pub fn new(store: S, f: F) -> Self {
+        Memo {
+            store,
+            f: Arc::new(f),
+            order: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Memoizes `f`, keeping at most `max_entries` of its results in `store`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_entries` is 0.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Map;
+    /// use concache::Memo;
+    ///
+    /// let mut lengths = Memo::bounded(Map::with_capacity(16), 2, |s: &&str| s.len());
+    /// assert_eq!(lengths.get("a"), 1);
+    /// assert_eq!(lengths.get("bb"), 2);
+    /// assert_eq!(lengths.get("ccc"), 3);
+    /// assert_eq!(lengths.len(), 2);
+    /// ```
+    pub fn bounded(store: S, max_entries: usize, f: F) -> Self {
+        assert!(max_entries > 0, "a memo must hold at least one result");
+        Memo {
+            store,
+            f: Arc::new(f),
+            order: Some(Arc::new(Order {
+                max_entries,
+                keys: Mutex::new(VecDeque::with_capacity(max_entries + 1)),
+            })),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of results the memo holds.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    /// Returns true if the memo holds no results.
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    /// Forgets every result, so that each key's result is computed again the next time it is
+    /// asked for.
+    pub fn clear(&mut self) {
+        if let Some(ref order) = self.order {
+            order.keys.lock().unwrap().clear();
+        }
+        self.store.clear();
+    }
+}
+
+impl<K, V, F, S> Memo<K, V, F, S>
+where
+    K: Clone,
+    F: Fn(&K) -> V,
+    S: MemoStore<K, V>,
+{
+    /// Returns the result of the memoized function for `key`, calling the function only if no
+    /// result for `key` is held.
+    ///
+    /// The function must not call `get` on this memo with the same key, since it would end up
+    /// waiting for itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::crossbeam::Map;
+    /// use concache::Memo;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let calls = AtomicUsize::new(0);
+    /// let mut double = Memo::new(Map::with_capacity(16), |&n: &u32| {
+    ///     calls.fetch_add(1, Ordering::SeqCst);
+    ///     n * 2
+    /// });
+    /// assert_eq!(double.get(21), 42);
+    /// assert_eq!(double.get(21), 42);
+    /// assert_eq!(calls.load(Ordering::SeqCst), 1);
+    /// ```
+    pub fn get(&mut self, key: K) -> V {
+        let f = &self.f;
+        let mut computed = false;
+        let v = self.store.get_or_insert_with(key.clone(), || {
+            computed = true;
+            f(&key)
+        });
+
+        if computed {
+            if let Some(ref order) = self.order {
+                let evicted = {
+                    let mut keys = order.keys.lock().unwrap();
+                    keys.push_back(key);
+                    if keys.len() > order.max_entries {
+                        keys.pop_front()
+                    } else {
+                        None
+                    }
+                };
+                if let Some(k) = evicted {
+                    self.store.remove(&k);
+                }
+            }
+        }
+        v
+    }
+}
+
+impl<K, V, F, S> Clone for Memo<K, V, F, S>
+where
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        Memo {
+            store: self.store.clone(),
+            f: Arc::clone(&self.f),
+            order: self.order.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, F, S> fmt::Debug for Memo<K, V, F, S>
+where
+    S: MemoStore<K, V>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Memo")
+            .field("len", &self.store.len())
+            .field("max_entries", &self.order.as_ref().map(|o| o.max_entries))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn memo_basics() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = |calls: &Arc<AtomicUsize>| {
+            let calls = Arc::clone(calls);
+            move |&n: &usize| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                n + 1
+            }
+        };
+
+        let mut memo = Memo::new(crossbeam::Map::with_capacity(4), counted(&calls));
+        assert!(memo.is_empty());
+        for n in 0..20 {
+            assert_eq!(memo.get(n % 10), n % 10 + 1);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 10);
+        assert_eq!(memo.len(), 10);
+        memo.clear();
+        assert!(memo.is_empty());
+        assert_eq!(memo.get(3), 4);
+        assert_eq!(calls.load(Ordering::SeqCst), 11);
+
+        // the oldest result is forgotten first
+        calls.store(0, Ordering::SeqCst);
+        let mut memo = Memo::bounded(manual::Map::with_capacity(4), 3, counted(&calls));
+        for n in 0..5 {
+            memo.get(n);
+        }
+        assert_eq!(memo.len(), 3);
+        memo.get(4);
+        memo.get(2);
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+        memo.get(0);
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+        assert_eq!(memo.len(), 3);
+        assert_eq!(
+            format!("{:?}", memo),
+            "Memo { len: 3, max_entries: Some(3) }"
+        );
+        memo.clear();
+        assert!(memo.is_empty());
+
+        let mut memo = Memo::new(Cache::new(2), counted(&calls));
+        for n in 0..10 {
+            assert_eq!(memo.get(n), n + 1);
+        }
+        assert_eq!(memo.len(), 2);
+    }
+
+    #[test]
+    fn memo_concurrent() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let memo = {
+            let calls = Arc::clone(&calls);
+            Memo::bounded(manual::Map::with_capacity(8), 50, move |&n: &usize| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(1));
+                n * n
+            })
+        };
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mut memo = memo.clone();
+                thread::spawn(move || {
+                    for n in 0..40 {
+                        assert_eq!(memo.get(n), n * n);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        // every key was computed once, however many threads asked for it at the same time
+        assert_eq!(calls.load(Ordering::SeqCst), 40);
+        assert_eq!(memo.len(), 40);
+    }
+}
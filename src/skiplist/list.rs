@@ -0,0 +1,453 @@
+use manual::{
+    free_slot, get_marked_reference, get_unmarked_reference, into_slot, is_marked_reference,
+    read_slot, Change,
+};
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Bound;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+const OSC: Ordering = Ordering::SeqCst;
+
+/// The most levels a node can be linked on, which is plenty for billions of keys.
+const MAX_HEIGHT: usize = 32;
+
+type Path<K, V> = [*mut Node<K, V>; MAX_HEIGHT];
+
+/// A node of the skip list, linked on the bottom level and, with probability 2<sup>-i</sup>, on
+/// each level `i` above it.
+///
+/// As in the manual map's lists, a key is removed the moment its node's value is swapped for
+/// null. The node is then marked, through the low bit of its `next` pointer on every level, from
+/// the top down, and unlinked from each level by whichever search comes across it.
+pub(super) struct Node<K, V: 'static> {
+    key: Option<K>,
+    pub val: AtomicPtr<V>,
+    next: Box<[AtomicPtr<Node<K, V>>]>,
+    /// How many of the thread that inserted the node, which may still be linking it into the
+    /// levels above the bottom one, and the thread that removes it, have yet to be done with it.
+    /// The last one to be done retires the node, which by then is unlinked from every level.
+    owners: AtomicUsize,
+}
+
+impl<K, V> Node<K, V> {
+    fn head() -> Self {
+        Node {
+            key: None,
+            val: AtomicPtr::new(ptr::null_mut()),
+            next: (0..MAX_HEIGHT)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            owners: AtomicUsize::new(0),
+        }
+    }
+
+    fn new(key: K, val: V, height: usize) -> Self {
+        Node {
+            key: Some(key),
+            val: AtomicPtr::new(into_slot(val)),
+            next: (0..height)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            owners: AtomicUsize::new(2),
+        }
+    }
+
+    fn key(&self) -> &K {
+        self.key.as_ref().expect("the head has no key")
+    }
+
+    /// Returns true if the node has been marked as deleted.
+    fn is_marked(&self) -> bool {
+        is_marked_reference(self.next[0].load(OSC))
+    }
+
+    /// Marks the node as deleted on every level, from the top down, which allows it to be
+    /// unlinked. Its value must already be null.
+    fn mark(&self) {
+        for next in self.next.iter().rev() {
+            loop {
+                let n = next.load(OSC);
+                if is_marked_reference(n)
+                    || next
+                        .compare_exchange(n, get_marked_reference(n), OSC, OSC)
+                        .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub(super) struct SkipList<K, V: 'static> {
+    head: AtomicPtr<Node<K, V>>,
+}
+
+impl<K, V> Default for SkipList<K, V> {
+    fn default() -> Self {
+        SkipList {
+            head: AtomicPtr::new(Box::into_raw(Box::new(Node::head()))),
+        }
+    }
+}
+
+impl<K, V> SkipList<K, V> {
+    fn head(&self) -> &Node<K, V> {
+        unsafe { &*self.head.load(OSC) }
+    }
+
+    /// Gives up the caller's ownership of `node`, and retires it if no one else owns it anymore.
+    fn release(node: *mut Node<K, V>, remove_nodes: &mut Vec<*mut Node<K, V>>) {
+        if unsafe { &*node }.owners.fetch_sub(1, OSC) == 1 {
+            remove_nodes.push(node);
+        }
+    }
+}
+
+impl<K, V> SkipList<K, V>
+where
+    K: Ord,
+    V: Copy,
+{
+    /// Finds, on every level, the last unmarked node whose key is less than `key`, and the node
+    /// after it, unlinking any marked nodes found between the two. Returns true if the node after
+    /// it on the bottom level holds `key`.
+    ///
+    /// Marked nodes are only unlinked here, never retired; that is left to their owners.
+    fn find(&self, key: &K, preds: &mut Path<K, V>, succs: &mut Path<K, V>) -> bool {
+        'search_again: loop {
+            let mut pred = self.head.load(OSC);
+            for level in (0..MAX_HEIGHT).rev() {
+                let mut curr = get_unmarked_reference(unsafe { &*pred }.next[level].load(OSC));
+                while !curr.is_null() {
+                    let c = unsafe { &*curr };
+                    let succ = c.next[level].load(OSC);
+                    if is_marked_reference(succ) {
+                        // curr is being deleted; unlink it from this level
+                        let succ = get_unmarked_reference(succ);
+                        if unsafe { &*pred }.next[level]
+                            .compare_exchange(curr, succ, OSC, OSC)
+                            .is_err()
+                        {
+                            continue 'search_again;
+                        }
+                        curr = succ;
+                        continue;
+                    }
+                    if c.key() >= key {
+                        break;
+                    }
+                    pred = curr;
+                    curr = succ;
+                }
+                preds[level] = pred;
+                succs[level] = curr;
+            }
+            return !succs[0].is_null() && unsafe { &*succs[0] }.key() == key;
+        }
+    }
+
+    /// Inserts `key` with `val`, replacing the current value if there is one and `overwrite` is
+    /// set.
+    pub(super) fn insert(
+        &self,
+        key: K,
+        val: V,
+        overwrite: bool,
+        remove_nodes: &mut Vec<*mut Node<K, V>>,
+    ) -> Change<V> {
+        let mut preds = [ptr::null_mut(); MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        let mut new_node = Box::new(Node::new(key, val, random_height()));
+
+        loop {
+            if self.find(new_node.key(), &mut preds, &mut succs) {
+                let n = unsafe { &*succs[0] };
+                let old = n.val.load(OSC);
+                if old.is_null() {
+                    // the node is being deleted; finish that and try again
+                    n.mark();
+                    continue;
+                }
+
+                let current = unsafe { read_slot(old) };
+                if !overwrite {
+                    unsafe { free_slot(new_node.val.load(OSC)) };
+                    return Change {
+                        old: Some(current),
+                        new: Some(current),
+                        retired: None,
+                    };
+                }
+
+                // hand the value we already allocated over to the existing node
+                let v = new_node.val.load(OSC);
+                if n.val.compare_exchange(old, v, OSC, OSC).is_ok() {
+                    return Change {
+                        old: Some(current),
+                        new: Some(val),
+                        retired: Some(old),
+                    };
+                }
+                continue;
+            }
+
+            for (next, &succ) in new_node.next.iter().zip(succs.iter()) {
+                next.store(succ, OSC);
+            }
+
+            let new_node_ptr = Box::into_raw(new_node);
+            if unsafe { &*preds[0] }.next[0]
+                .compare_exchange(succs[0], new_node_ptr, OSC, OSC)
+                .is_ok()
+            {
+                // the key is in the map from here on; the levels above only speed up searches
+                self.link_upper(new_node_ptr, &mut preds, &mut succs);
+                Self::release(new_node_ptr, remove_nodes);
+                return Change {
+                    old: None,
+                    new: Some(val),
+                    retired: None,
+                };
+            }
+            new_node = unsafe { Box::from_raw(new_node_ptr) };
+        }
+    }
+
+    /// Links `node`, which is linked on the bottom level, on the levels above it, given where
+    /// the last search for its key ended.
+    fn link_upper(&self, node: *mut Node<K, V>, preds: &mut Path<K, V>, succs: &mut Path<K, V>) {
+        let n = unsafe { &*node };
+        'levels: for level in 1..n.next.len() {
+            loop {
+                let next = n.next[level].load(OSC);
+                if is_marked_reference(next) {
+                    // the node is being deleted, so there is no point linking it any further
+                    break 'levels;
+                }
+                if next != succs[level]
+                    && n.next[level]
+                        .compare_exchange(next, succs[level], OSC, OSC)
+                        .is_err()
+                {
+                    continue;
+                }
+                if unsafe { &*preds[level] }.next[level]
+                    .compare_exchange(succs[level], node, OSC, OSC)
+                    .is_ok()
+                {
+                    continue 'levels;
+                }
+                // the neighbourhood changed; look again, unless the node is gone already
+                if !self.find(n.key(), preds, succs) || succs[0] != node {
+                    break 'levels;
+                }
+            }
+        }
+
+        if n.is_marked() {
+            // the node was deleted while we were linking it, and the deleting thread may have
+            // unlinked it before we linked it on some level
+            self.find(n.key(), preds, succs);
+        }
+    }
+
+    pub(super) fn get(&self, key: &K) -> Option<V> {
+        let mut preds = [ptr::null_mut(); MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        if !self.find(key, &mut preds, &mut succs) {
+            return None;
+        }
+        let v = unsafe { &*succs[0] }.val.load(OSC);
+        if v.is_null() {
+            None
+        } else {
+            Some(unsafe { read_slot(v) })
+        }
+    }
+
+    pub(super) fn delete(&self, key: &K, remove_nodes: &mut Vec<*mut Node<K, V>>) -> Change<V> {
+        let mut preds = [ptr::null_mut(); MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+
+        loop {
+            if !self.find(key, &mut preds, &mut succs) {
+                return Change {
+                    old: None,
+                    new: None,
+                    retired: None,
+                };
+            }
+
+            let node = succs[0];
+            let n = unsafe { &*node };
+            let old = n.val.load(OSC);
+            if old.is_null() {
+                n.mark();
+                continue;
+            }
+
+            let current = unsafe { read_slot(old) };
+            if n.val
+                .compare_exchange(old, ptr::null_mut(), OSC, OSC)
+                .is_ok()
+            {
+                n.mark();
+                // unlink the node from every level before giving it up
+                self.find(key, &mut preds, &mut succs);
+                Self::release(node, remove_nodes);
+                return Change {
+                    old: Some(current),
+                    new: None,
+                    retired: Some(old),
+                };
+            }
+        }
+    }
+
+    /// Calls `f` with the keys and values on the bottom level, in order, starting from the first
+    /// key within `start`, for as long as `f` returns true. Keys that are inserted or removed
+    /// during the walk may or may not be seen. The caller must be in a critical section.
+    pub(super) fn walk<F>(&self, start: Bound<&K>, mut f: F)
+    where
+        F: FnMut(&K, V) -> bool,
+    {
+        let mut t = match start {
+            Bound::Unbounded => self.head().next[0].load(OSC),
+            Bound::Included(key) | Bound::Excluded(key) => {
+                let mut preds = [ptr::null_mut(); MAX_HEIGHT];
+                let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+                self.find(key, &mut preds, &mut succs);
+                succs[0]
+            }
+        };
+
+        while !t.is_null() {
+            let n = unsafe { &*t };
+            let next = n.next[0].load(OSC);
+            let v = n.val.load(OSC);
+            let skip = match start {
+                Bound::Excluded(key) => n.key() <= key,
+                _ => false,
+            };
+            if !skip
+                && !is_marked_reference(next)
+                && !v.is_null()
+                && !f(n.key(), unsafe { read_slot(v) })
+            {
+                return;
+            }
+            t = get_unmarked_reference(next);
+        }
+    }
+
+    /// Returns the greatest key in the list that is less than `before`, if given, along with its
+    /// value. The caller must be in a critical section.
+    pub(super) fn last<'a>(&'a self, mut before: Option<&'a K>) -> Option<(&'a K, V)> {
+        loop {
+            // go as far right as possible on every level, like a search for a key past the end
+            let mut pred = self.head();
+            for level in (0..MAX_HEIGHT).rev() {
+                loop {
+                    let next = get_unmarked_reference(pred.next[level].load(OSC));
+                    if next.is_null() {
+                        break;
+                    }
+                    let n = unsafe { &*next };
+                    if before.is_some_and(|b| n.key() >= b) {
+                        break;
+                    }
+                    pred = n;
+                }
+            }
+
+            let key = pred.key.as_ref()?;
+            let v = pred.val.load(OSC);
+            if !v.is_null() && !pred.is_marked() {
+                return Some((key, unsafe { read_slot(v) }));
+            }
+            // the last node is being deleted; look for the one before it
+            before = Some(key);
+        }
+    }
+}
+
+impl<K, V> Drop for SkipList<K, V> {
+    fn drop(&mut self) {
+        // no handle is left, so every node that is still linked is ours to free
+        let mut t = self.head.load(OSC);
+        while !t.is_null() {
+            let n = unsafe { Box::from_raw(t) };
+            let v = n.val.load(OSC);
+            if !v.is_null() {
+                unsafe { free_slot(v) };
+            }
+            t = get_unmarked_reference(n.next[0].load(OSC));
+        }
+    }
+}
+
+thread_local! {
+    /// The state of this thread's xorshift generator, which must never be 0.
+    static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+/// Returns the height of a new node: 1 with probability 1/2, 2 with probability 1/4, and so on.
+fn random_height() -> usize {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        (x.trailing_zeros() as usize + 1).min(MAX_HEIGHT)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skiplist_basics() {
+        let mut remove_nodes = Vec::new();
+        let list = SkipList::default();
+
+        for &k in &[5, 1, 9, 3, 7] {
+            assert_eq!(list.insert(k, k * 10, true, &mut remove_nodes).old, None);
+        }
+        assert_eq!(list.insert(3, 31, false, &mut remove_nodes).old, Some(30));
+        assert_eq!(list.insert(3, 32, true, &mut remove_nodes).old, Some(30));
+        assert_eq!(list.get(&3), Some(32));
+        assert_eq!(list.get(&4), None);
+
+        let mut keys = Vec::new();
+        list.walk(Bound::Unbounded, |&k, _| {
+            keys.push(k);
+            true
+        });
+        assert_eq!(keys, [1, 3, 5, 7, 9]);
+
+        assert_eq!(list.delete(&5, &mut remove_nodes).old, Some(50));
+        assert_eq!(list.delete(&5, &mut remove_nodes).old, None);
+        // the node was unlinked, and retired by the thread that removed it
+        assert_eq!(remove_nodes.len(), 1);
+
+        keys.clear();
+        list.walk(Bound::Excluded(&3), |&k, _| {
+            keys.push(k);
+            k < 7
+        });
+        assert_eq!(keys, [7]);
+        assert_eq!(list.last(None), Some((&9, 90)));
+        assert_eq!(list.last(Some(&9)), Some((&7, 70)));
+        assert_eq!(list.last(Some(&1)), None);
+
+        for n in remove_nodes {
+            drop(unsafe { Box::from_raw(n) });
+        }
+    }
+}
@@ -0,0 +1,591 @@
+//! A concurrent, ordered map implemented as a lock-free skip list.
+//!
+//! The hash maps in [`crossbeam`](::crossbeam) and [`manual`](::manual) spread their keys over
+//! buckets by hash, which makes lookups fast but loses the keys' order. The [`SkipMap`] in this
+//! module keeps its keys sorted instead, so it can also answer [`range`](SkipMapHandle::range)
+//! queries, find the [`first`](SkipMapHandle::first) and [`last`](SkipMapHandle::last) keys, and
+//! iterate over the keys in order, at the cost of lookups that take logarithmic rather than
+//! constant time. Keys must be `Ord`.
+//!
+//! The skip list's levels are lock-free linked lists that use the same mark bits on their `next`
+//! pointers as the lists of the [`manual`](::manual) map, and its memory is reclaimed with the
+//! same _Quiescent-State-Based Reclamation_, following the same [`Reclamation`] policies. As with
+//! that map, creating a [`SkipMap`] gives you a [`SkipMapHandle`], which each thread clones to get
+//! its own handle to the map.
+//!
+//! Values must be `Copy` and `'static`, and values smaller than a pointer are stored directly in
+//! the nodes.
+
+mod list;
+
+use self::list::{Node, SkipList};
+use manual::{free_slot, is_boxed_slot, quiesce, Change, CriticalSection, Reclamation};
+use std::fmt;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::vec;
+
+const OSC: Ordering = Ordering::SeqCst;
+
+/// How many entries an iterator copies out of the map each time it enters a critical section.
+const BATCH: usize = 64;
+
+/// A shared, concurrent, ordered map.
+///
+/// See [`SkipMapHandle`] for how to interact with this map.
+pub struct SkipMap<K, V: 'static> {
+    list: SkipList<K, V>,
+    nitems: AtomicUsize,
+    handles: RwLock<Vec<Arc<AtomicUsize>>>,
+    reclamation: Reclamation,
+}
+
+impl<K, V> SkipMap<K, V> {
+    /// Create a new, shared, ordered map and return a handle to it.
+    // like `manual::Map::with_capacity`, this hands out the first handle rather than the map
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> SkipMapHandle<K, V> {
+        Self::with_reclamation(Reclamation::default())
+    }
+
+    /// Create a new, shared, ordered map whose handles reclaim memory according to
+    /// `reclamation`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::manual::Reclamation;
+    /// use concache::skiplist::SkipMap;
+    ///
+    /// let mut map = SkipMap::with_reclamation(Reclamation::every(64));
+    /// map.insert(1, "a");
+    /// assert_eq!(map.reclamation(), Reclamation::every(64));
+    /// ```
+    pub fn with_reclamation(reclamation: Reclamation) -> SkipMapHandle<K, V> {
+        let map = SkipMap {
+            list: SkipList::default(),
+            nitems: AtomicUsize::new(0),
+            handles: RwLock::new(Vec::new()),
+            reclamation,
+        };
+
+        SkipMapHandle::register(Arc::new(map))
+    }
+}
+
+/// A handle to a shared [`SkipMap`].
+///
+/// Any operation performed on this handle affects the map seen by all other related
+/// `SkipMapHandle` instances. To get another handle to the `SkipMap`, simply clone any of its
+/// handles.
+///
+/// Like a [`MapHandle`](::manual::MapHandle), a handle holds on to the memory it unlinks from the
+/// map until it can prove that no other handle is still looking at it. Only inserts and removals
+/// retire memory, so only they take `&mut self` and count towards the handle's [`Reclamation`]
+/// policy; reads take `&self`, and are never counted.
+///
+/// # Examples
+///
+/// ```
+/// use concache::skiplist::SkipMap;
+///
+/// let mut readings = SkipMap::new();
+/// for &(t, v) in &[(30, 1.5), (10, 0.5), (20, 1.0), (40, 2.0)] {
+///     readings.insert(t, v);
+/// }
+///
+/// let window: Vec<_> = readings.range(15..40).collect();
+/// assert_eq!(window, [(20, 1.0), (30, 1.5)]);
+/// assert_eq!(readings.first(), Some((10, 0.5)));
+/// assert_eq!(readings.last(), Some((40, 2.0)));
+/// ```
+pub struct SkipMapHandle<K, V: 'static> {
+    map: Arc<SkipMap<K, V>>,
+    epoch_counter: Arc<AtomicUsize>,
+    remove_nodes: Vec<*mut Node<K, V>>,
+    remove_val: Vec<*mut V>,
+    refresh: usize,
+    reclamation: Reclamation,
+}
+
+unsafe impl<K, V> Send for SkipMapHandle<K, V>
+where
+    K: Send + Sync,
+    V: Send,
+{
+}
+
+impl<K, V> SkipMapHandle<K, V> {
+    /// Creates a new handle to `map` and adds it to the map's epoch system.
+    fn register(map: Arc<SkipMap<K, V>>) -> Self {
+        let ret = SkipMapHandle {
+            epoch_counter: Arc::new(AtomicUsize::new(0)),
+            remove_nodes: Vec::new(),
+            remove_val: Vec::new(),
+            refresh: 0,
+            reclamation: map.reclamation,
+            map,
+        };
+
+        ret.map
+            .handles
+            .write()
+            .unwrap()
+            .push(Arc::clone(&ret.epoch_counter));
+        ret
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.map.nitems.load(OSC)
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.map.nitems.load(OSC) == 0
+    }
+
+    /// Returns the [`Reclamation`] policy this handle currently follows.
+    pub fn reclamation(&self) -> Reclamation {
+        self.reclamation
+    }
+
+    /// Changes when this handle reclaims the memory it retires.
+    ///
+    /// See [`MapHandle::set_reclamation`](::manual::MapHandle::set_reclamation).
+    pub fn set_reclamation(&mut self, reclamation: Reclamation) {
+        self.reclamation = reclamation;
+    }
+
+    /// Waits until no other handle can still observe memory this handle has retired, and then
+    /// frees all of it.
+    ///
+    /// See [`MapHandle::flush`](::manual::MapHandle::flush).
+    pub fn flush(&mut self) {
+        self.refresh = 0;
+        self.cleanup();
+    }
+
+    fn tick(&mut self, counted: bool) {
+        if !counted {
+            return;
+        }
+
+        self.refresh += 1;
+        if let Some(interval) = self.reclamation.interval {
+            if self.refresh >= interval {
+                self.flush();
+            }
+        }
+    }
+
+    fn cleanup(&mut self) {
+        quiesce(&self.map.handles.read().unwrap());
+
+        // take the lists before freeing anything: if a key's destructor panics, the rest of the
+        // list is leaked rather than freed a second time by the next cleanup
+        let remove_nodes = mem::take(&mut self.remove_nodes);
+        let remove_val = mem::take(&mut self.remove_val);
+
+        for to_drop in remove_val {
+            unsafe { free_slot(to_drop) };
+        }
+
+        for to_drop in remove_nodes {
+            // removed nodes have had their value taken, and retired, already
+            drop(unsafe { Box::from_raw(to_drop) });
+        }
+    }
+
+    /// Keeps the map's length up to date with `change`, retires the value it replaced or
+    /// removed, if any, and counts the operation towards the next reclamation if `counted` is set.
+    fn finish(&mut self, change: Change<V>, counted: bool) -> Option<V> {
+        match (&change.old, &change.new) {
+            (None, Some(_)) => {
+                self.map.nitems.fetch_add(1, OSC);
+            }
+            (Some(_), None) => {
+                self.map.nitems.fetch_sub(1, OSC);
+            }
+            _ => {}
+        }
+        if let Some(v) = change.retired {
+            if is_boxed_slot(v) {
+                self.remove_val.push(v);
+            }
+        }
+        self.tick(counted);
+        change.old
+    }
+}
+
+impl<K, V> SkipMapHandle<K, V>
+where
+    K: Ord,
+    V: Copy,
+{
+    /// Inserts a key-value pair into the map, returning the previous value for the key, if any.
+    ///
+    /// The key is not updated if it was already present; this matters for types that can be `==`
+    /// without being identical.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::skiplist::SkipMap;
+    ///
+    /// let mut map = SkipMap::new();
+    /// assert_eq!(map.insert(37, "a"), None);
+    /// assert_eq!(map.insert(37, "b"), Some("a"));
+    /// assert_eq!(map.get(&37), Some("b"));
+    /// ```
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let change = {
+            let _critical = CriticalSection::enter(&self.epoch_counter);
+            self.map
+                .list
+                .insert(key, value, true, &mut self.remove_nodes)
+        };
+
+        let counted = self.reclamation.count_inserts;
+        self.finish(change, counted)
+    }
+
+    /// Inserts a key-value pair into the map, unless the key is already present, in which case
+    /// the map is left unchanged and the current value is returned.
+    pub fn insert_if_absent(&mut self, key: K, value: V) -> Option<V> {
+        let change = {
+            let _critical = CriticalSection::enter(&self.epoch_counter);
+            self.map
+                .list
+                .insert(key, value, false, &mut self.remove_nodes)
+        };
+
+        let counted = self.reclamation.count_inserts;
+        self.finish(change, counted)
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key was previously in the
+    /// map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::skiplist::SkipMap;
+    ///
+    /// let mut map = SkipMap::new();
+    /// map.insert(1, "a");
+    /// assert_eq!(map.remove(&1), Some("a"));
+    /// assert_eq!(map.remove(&1), None);
+    /// ```
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let change = {
+            let _critical = CriticalSection::enter(&self.epoch_counter);
+            self.map.list.delete(key, &mut self.remove_nodes)
+        };
+
+        let counted = self.reclamation.count_removes;
+        self.finish(change, counted)
+    }
+
+    /// Returns the value corresponding to the key.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let _critical = CriticalSection::enter(&self.epoch_counter);
+        self.map.list.get(key)
+    }
+
+    /// Returns true if the map contains a value for the key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+impl<K, V> SkipMapHandle<K, V>
+where
+    K: Ord + Clone,
+    V: Copy,
+{
+    /// Returns the smallest key in the map, along with its value.
+    pub fn first(&self) -> Option<(K, V)> {
+        let _critical = CriticalSection::enter(&self.epoch_counter);
+        let mut first = None;
+        self.map.list.walk(Bound::Unbounded, |k, v| {
+            first = Some((k.clone(), v));
+            false
+        });
+        first
+    }
+
+    /// Returns the greatest key in the map, along with its value.
+    pub fn last(&self) -> Option<(K, V)> {
+        let _critical = CriticalSection::enter(&self.epoch_counter);
+        self.map.list.last(None).map(|(k, v)| (k.clone(), v))
+    }
+
+    /// Returns an iterator over the entries of the map whose keys are within `range`, in order.
+    ///
+    /// The iterator copies a few entries at a time out of the map, while other handles keep using
+    /// it, so entries that are inserted or removed during the walk may or may not be seen. Every
+    /// entry that is in the map throughout the walk is seen exactly once, and the keys seen are
+    /// always increasing.
+    ///
+    /// # Panics
+    ///
+    /// Like `BTreeMap::range`, panics if the range starts after it ends.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use concache::skiplist::SkipMap;
+    ///
+    /// let mut map = SkipMap::new();
+    /// for i in 0..10 {
+    ///     map.insert(i, i * i);
+    /// }
+    /// let keys: Vec<_> = map.range(3..=5).map(|(k, _)| k).collect();
+    /// assert_eq!(keys, [3, 4, 5]);
+    /// assert_eq!(map.range(8..).count(), 2);
+    /// ```
+    pub fn range<R>(&self, range: R) -> Range<'_, K, V>
+    where
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        if let (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) =
+            (&start, &end)
+        {
+            assert!(s <= e, "range start is greater than range end");
+        }
+
+        Range {
+            handle: self,
+            next: Some(start),
+            end,
+            batch: Vec::new().into_iter(),
+        }
+    }
+
+    /// Returns an iterator over all the entries of the map, in order.
+    ///
+    /// See [`SkipMapHandle::range`] for how it behaves while the map changes.
+    pub fn iter(&self) -> Range<'_, K, V> {
+        self.range(..)
+    }
+}
+
+impl<K, V> Clone for SkipMapHandle<K, V> {
+    fn clone(&self) -> Self {
+        SkipMapHandle::register(Arc::clone(&self.map))
+    }
+}
+
+impl<K, V> Drop for SkipMapHandle<K, V> {
+    fn drop(&mut self) {
+        // free what we still hold, and stop other handles from waiting on us
+        self.cleanup();
+        let mut handles_vec = self.map.handles.write().unwrap();
+        handles_vec.retain(|h| !Arc::ptr_eq(h, &self.epoch_counter));
+    }
+}
+
+impl<K, V> fmt::Debug for SkipMapHandle<K, V>
+where
+    K: Ord + Clone + fmt::Debug,
+    V: Copy + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// An iterator over a range of the entries of a [`SkipMap`], in order.
+///
+/// See [`SkipMapHandle::range`].
+pub struct Range<'a, K: 'a, V: 'static> {
+    handle: &'a SkipMapHandle<K, V>,
+    /// Where the next batch starts, or `None` once the end of the range has been reached.
+    next: Option<Bound<K>>,
+    end: Bound<K>,
+    batch: vec::IntoIter<(K, V)>,
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V>
+where
+    K: Ord + Clone,
+    V: Copy,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            if let Some(kv) = self.batch.next() {
+                return Some(kv);
+            }
+            let start = self.next.take()?;
+
+            // only stay in the critical section for one batch, so that a caller that holds on to
+            // the iterator does not hold up reclamation
+            let mut batch = Vec::with_capacity(BATCH);
+            let mut more = false;
+            {
+                let end = &self.end;
+                let _critical = CriticalSection::enter(&self.handle.epoch_counter);
+                self.handle.map.list.walk(start.as_ref(), |k, v| {
+                    let within = match *end {
+                        Bound::Included(ref e) => k <= e,
+                        Bound::Excluded(ref e) => k < e,
+                        Bound::Unbounded => true,
+                    };
+                    if !within {
+                        return false;
+                    }
+                    if batch.len() == BATCH {
+                        more = true;
+                        return false;
+                    }
+                    batch.push((k.clone(), v));
+                    true
+                });
+            }
+
+            if more {
+                let last = &batch[batch.len() - 1].0;
+                self.next = Some(Bound::Excluded(last.clone()));
+            }
+            self.batch = batch.into_iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn skipmap_basics() {
+        let mut map = SkipMap::new();
+        assert!(map.is_empty());
+        assert_eq!(map.first(), None);
+        assert_eq!(map.last(), None);
+
+        // enough keys for several batches
+        for i in (0..500).rev() {
+            assert_eq!(map.insert(i * 2, i), None);
+        }
+        assert_eq!(map.len(), 500);
+        assert_eq!(map.insert_if_absent(10, 0), Some(5));
+        assert_eq!(map.get(&10), Some(5));
+        assert_eq!(map.get(&11), None);
+        assert!(map.contains_key(&998));
+
+        assert_eq!(map.iter().count(), 500);
+        assert!(map.iter().zip(0..).all(|((k, v), i)| k == i * 2 && v == i));
+        let keys: Vec<_> = map.range(95..=104).map(|(k, _)| k).collect();
+        assert_eq!(keys, [96, 98, 100, 102, 104]);
+        assert_eq!(
+            map.range((Bound::Excluded(96), Bound::Excluded(100)))
+                .count(),
+            1
+        );
+        assert_eq!(map.range(..0).count(), 0);
+        assert_eq!(map.range(900..).count(), 50);
+
+        assert_eq!(map.remove(&0), Some(0));
+        assert_eq!(map.remove(&998), Some(499));
+        assert_eq!(map.remove(&998), None);
+        assert_eq!(map.first(), Some((2, 1)));
+        assert_eq!(map.last(), Some((996, 498)));
+        assert_eq!(map.len(), 498);
+        map.flush();
+
+        let small: SkipMapHandle<_, _> = {
+            let mut m = SkipMap::new();
+            m.insert("b", 2);
+            m.insert("a", 1);
+            m
+        };
+        assert_eq!(format!("{:?}", small), r#"{"a": 1, "b": 2}"#);
+    }
+
+    #[test]
+    #[should_panic(expected = "range start is greater than range end")]
+    fn skipmap_bad_range() {
+        let map = SkipMap::<u8, u8>::new();
+        map.range((Bound::Included(2), Bound::Included(1)));
+    }
+
+    #[test]
+    fn skipmap_boxed_values() {
+        // values too big to be stored inline, so that retiring them is exercised
+        let mut map = SkipMap::with_reclamation(Reclamation::every(8));
+        for i in 0..100u64 {
+            map.insert(i, (i, i));
+            map.insert(i, (i, i + 1));
+        }
+        for i in 0..50 {
+            assert_eq!(map.remove(&i), Some((i, i + 1)));
+        }
+        assert_eq!(map.first(), Some((50, (50, 51))));
+    }
+
+    #[test]
+    fn skipmap_contended() {
+        // every thread inserts and removes the same few keys, so that nodes are often deleted
+        // while they are still being linked
+        let map = SkipMap::with_reclamation(Reclamation::every(4));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mut map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..5000u64 {
+                        map.insert(i % 8, (i, i));
+                        map.remove(&((i + 3) % 8));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let keys: Vec<_> = map.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys.len(), map.len());
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn skipmap_concurrent() {
+        let map = SkipMap::with_reclamation(Reclamation::every(16));
+        let threads: Vec<_> = (0..4u64)
+            .map(|t| {
+                let mut map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..2000 {
+                        let k = i * 4 + t;
+                        map.insert(k, k);
+                        if i % 2 == 1 {
+                            assert_eq!(map.remove(&k), Some(k));
+                        }
+                        if i % 100 == 0 {
+                            // whatever else is going on, an iteration sees increasing keys
+                            let keys: Vec<_> = map.iter().map(|(k, _)| k).collect();
+                            assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(map.len(), 4000);
+        let keys: Vec<_> = map.iter().map(|(k, _)| k).collect();
+        let expected: Vec<_> = (0..8000).filter(|k| (k / 4) % 2 == 0).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(map.last(), Some((7995, 7995)));
+    }
+}